@@ -154,6 +154,49 @@ impl FrameType {
     }
 }
 
+/// Decodes a string in one of the ID3 text encodings: 0 is Latin-1, 1 is UTF-16 with a byte order
+/// mark, 2 is UTF-16 big-endian, and 3 is UTF-8.  Trailing nulls are dropped.
+pub fn decode_text(encoding: u8, data: &[u8]) -> Option<String> {
+    let utf16 = |data: &[u8], be: bool| {
+        let units = data.chunks_exact(2).map(|c| {
+            if be {
+                u16::from_be_bytes([c[0], c[1]])
+            } else {
+                u16::from_le_bytes([c[0], c[1]])
+            }
+        });
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect::<String>()
+    };
+
+    let text = match (encoding, data) {
+        (0, _) => data.iter().map(|&b| b as char).collect(),
+        (1, [0xFF, 0xFE, rest @ ..]) => utf16(rest, false),
+        // no byte order mark means big-endian
+        (1, [0xFE, 0xFF, rest @ ..] | rest) | (2, rest) => utf16(rest, true),
+        (3, _) => String::from_utf8_lossy(data).into_owned(),
+        _ => return None,
+    };
+
+    Some(text.trim_end_matches('\0').to_owned())
+}
+
+/// Splits a string off the front of `data` at its null terminator, returning it and the rest.
+fn split_text(encoding: u8, data: &[u8]) -> Option<(String, &[u8])> {
+    let end = if matches!(encoding, 1 | 2) {
+        // UTF-16 ends with two null bytes, on a 2-byte boundary
+        data.chunks_exact(2)
+            .position(|c| c == [0, 0])
+            .map(|i| (i * 2, i * 2 + 2))
+    } else {
+        data.iter().position(|&b| b == 0).map(|i| (i, i + 1))
+    };
+
+    let (text, rest) = end.unwrap_or((data.len(), data.len()));
+    Some((decode_text(encoding, &data[..text])?, &data[rest..]))
+}
+
 impl From<([u8; 4], Vec<u8>)> for FrameType {
     fn from((tag, data): ([u8; 4], Vec<u8>)) -> Self {
         match &tag {
@@ -163,7 +206,21 @@ impl From<([u8; 4], Vec<u8>)> for FrameType {
     }
 }
 
+impl From<FrameType> for Frame {
+    fn from(frame_type: FrameType) -> Self {
+        Self {
+            frame_type,
+            flags: [0; 2],
+        }
+    }
+}
+
 impl Frame {
+    #[inline]
+    pub fn frame_type(&self) -> &FrameType {
+        &self.frame_type
+    }
+
     pub fn byte_len(&self) -> u32 {
         // header is 10 bytes - frame id (4) + flags (2) + size (u32 ⇒ 4)
         self.frame_type.data_len() + 10
@@ -172,6 +229,8 @@ impl Frame {
     pub fn read(mut r: impl Read) -> io::Result<Option<Self>> {
         let mut frame_id = [0u8; 4];
         match r.read(&mut frame_id) {
+            // padding
+            Ok(4) if frame_id[0] == 0 => return Ok(None),
             Ok(4) => (),
             Ok(_) => return Ok(None),
             Err(e) => return Err(e),
@@ -203,6 +262,44 @@ impl Frame {
     }
 }
 
+/// An attached picture (`APIC`), like an album cover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Picture<'a> {
+    pub mime_type: &'a str,
+    /// What the picture is of: 3 is the front cover
+    pub kind: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Picture<'a> {
+    pub const FRONT_COVER: u8 = 3;
+
+    fn read(data: &'a [u8]) -> Option<Self> {
+        let (&encoding, data) = data.split_first()?;
+
+        let end = data.iter().position(|&b| b == 0)?;
+        let mime_type = std::str::from_utf8(&data[..end]).ok()?;
+        let (&kind, data) = data[end + 1..].split_first()?;
+
+        let description_end = if matches!(encoding, 1 | 2) {
+            data.chunks_exact(2).position(|c| c == [0, 0])? * 2 + 2
+        } else {
+            data.iter().position(|&b| b == 0)? + 1
+        };
+
+        Some(Self {
+            // ID3v2.2 style image formats
+            mime_type: match mime_type {
+                "" | "JPG" | "jpg" => "image/jpeg",
+                "PNG" | "png" => "image/png",
+                mime_type => mime_type,
+            },
+            kind,
+            data: &data[description_end..],
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Id3 {
     major_version: u8,
@@ -212,6 +309,18 @@ pub struct Id3 {
 }
 
 impl Id3 {
+    /// An ID3v2.3 tag, with no flags set
+    pub fn new(frames: Vec<Frame>) -> Self {
+        Self {
+            major_version: 3,
+            revision: 0,
+            flags: 0,
+            frames,
+        }
+    }
+
+    /// Reads the tag at the start of `source`, leaving the source at the end of it.  `None` if
+    /// there's no tag, with the source rewound, or if it's a version other than ID3v2.3 or 2.4.
     pub fn read(mut source: impl Read + Seek) -> io::Result<Option<Self>> {
         let mut header_size = 10;
 
@@ -226,7 +335,6 @@ impl Id3 {
 
         // minor (revision) version is version[4]
         assert_eq!(&version[0..3], b"ID3", "Invalid ID3 tag/version");
-        assert!((3..=4).contains(&version[3]));
 
         let flags = read_be!(u8, source)?;
 
@@ -235,6 +343,13 @@ impl Id3 {
         source.read_exact(&mut size)?;
         let size = u28::from(size).0;
 
+        if !(3..=4).contains(&version[3]) {
+            // e.g. ID3v2.2, which has shorter frame headers.  The tag is skipped, so the source is
+            // still left at the end of it.
+            source.seek(io::SeekFrom::Current(size as i64))?;
+            return Ok(None);
+        }
+
         if flags & (HeaderFlags::ExtendedHeader as u8) != 0 {
             let ext_header_size = read_be!(u32, source)?;
             // TODO: handle extended header; now we just skip it
@@ -245,7 +360,14 @@ impl Id3 {
             header_size += ext_header_size;
         }
 
-        let mut src = (&mut source).take((size - header_size) as u64);
+        // the size covers everything after the header, including the extended header
+        let frames_size = (size + 10).checked_sub(header_size).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ID3 extended header is larger than the tag",
+            )
+        })?;
+        let mut src = (&mut source).take(frames_size as u64);
 
         let mut frames = Vec::new();
 
@@ -253,6 +375,9 @@ impl Id3 {
             frames.push(frame)
         }
 
+        // skip any padding, so the source is left at the end of the tag
+        io::copy(&mut src, &mut io::sink())?;
+
         Ok(Some(Self {
             major_version: version[3],
             revision: version[4],
@@ -261,6 +386,73 @@ impl Id3 {
         }))
     }
 
+    #[inline]
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Finds the value of a user-defined text frame (`TXXX`) by its description, which is compared
+    /// case-insensitively.  ReplayGain values are stored this way.
+    pub fn user_text(&self, description: &str) -> Option<String> {
+        self.frames
+            .iter()
+            .find_map(|frame| match &frame.frame_type {
+                FrameType::Other {
+                    tag: [b'T', b'X', b'X', b'X'],
+                    data,
+                } => {
+                    let (&encoding, data) = data.split_first()?;
+                    let (desc, value) = split_text(encoding, data)?;
+
+                    desc.eq_ignore_ascii_case(description)
+                        .then(|| decode_text(encoding, value))
+                        .flatten()
+                }
+                _ => None,
+            })
+    }
+
+    /// Finds the value of a text frame (`T...`, other than the title), like `TALB` for the album.
+    /// Multiple values, which ID3v2.4 separates with nulls, are joined with `/`.
+    pub fn text(&self, tag: &[u8; 4]) -> Option<String> {
+        self.frames
+            .iter()
+            .find_map(|frame| match &frame.frame_type {
+                FrameType::Other { tag: t, data } if t == tag && tag[0] == b'T' => {
+                    let (&encoding, data) = data.split_first()?;
+                    let text = decode_text(encoding, data)?;
+
+                    Some(text.split('\0').collect::<Vec<_>>().join("/"))
+                        .filter(|text| !text.is_empty())
+                }
+                _ => None,
+            })
+    }
+
+    /// The front cover, or else the first attached picture
+    pub fn picture(&self) -> Option<Picture<'_>> {
+        let mut pictures = self
+            .frames
+            .iter()
+            .filter_map(|frame| match &frame.frame_type {
+                FrameType::Other {
+                    tag: [b'A', b'P', b'I', b'C'],
+                    data,
+                } => Picture::read(data),
+                _ => None,
+            });
+
+        let first = pictures.next()?;
+        if first.kind == Picture::FRONT_COVER {
+            return Some(first);
+        }
+        Some(
+            pictures
+                .find(|picture| picture.kind == Picture::FRONT_COVER)
+                .unwrap_or(first),
+        )
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut vec = vec![
             b'I',
@@ -281,3 +473,124 @@ impl Id3 {
         vec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(frames: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, data) in frames {
+            body.extend(*id);
+            body.extend((data.len() as u32).to_be_bytes());
+            body.extend([0, 0]);
+            body.extend(data);
+        }
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend(u28(body.len() as u32).into());
+        tag.extend(body);
+        tag
+    }
+
+    #[test]
+    fn user_text() {
+        // each string has its own byte order mark
+        let utf16: Vec<u8> = "\u{FEFF}replaygain_track_gain\0\u{FEFF}-1.50 dB"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+
+        let data = tag(&[
+            (b"TXXX", b"\x00REPLAYGAIN_TRACK_PEAK\x000.98\x00".to_vec()),
+            (b"TXXX", [&[1][..], &utf16].concat()),
+        ]);
+
+        let id3 = Id3::read(io::Cursor::new(data)).unwrap().expect("no tag");
+        assert_eq!(
+            id3.user_text("REPLAYGAIN_TRACK_PEAK").as_deref(),
+            Some("0.98")
+        );
+        assert_eq!(
+            id3.user_text("REPLAYGAIN_TRACK_GAIN").as_deref(),
+            Some("-1.50 dB")
+        );
+        assert_eq!(id3.user_text("R128_TRACK_GAIN"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let id3 = Id3::new(vec![Frame::from(FrameType::Other {
+            tag: *b"PRIV",
+            data: b"owner\0data".to_vec(),
+        })]);
+        let mut bytes = id3.as_bytes();
+        bytes.extend(b"after");
+
+        let mut source = io::Cursor::new(bytes);
+        let read = Id3::read(&mut source).unwrap().expect("no tag");
+        assert_eq!(source.position(), 10 + 10 + 10);
+        assert!(matches!(
+            read.frames(),
+            [frame] if matches!(frame.frame_type(), FrameType::Other { tag, data } if tag == b"PRIV" && data == b"owner\0data")
+        ));
+    }
+
+    #[test]
+    fn text_and_pictures() {
+        let data = tag(&[
+            (b"TALB", b"\x03Album\x00".to_vec()),
+            (b"TCON", b"\x00Rock\x00Pop".to_vec()),
+            (b"APIC", b"\x00image/png\x00\x00icon\x00small".to_vec()),
+            (b"APIC", b"\x00\x00\x03\x00cover".to_vec()),
+        ]);
+
+        let id3 = Id3::read(io::Cursor::new(data)).unwrap().expect("no tag");
+        assert_eq!(id3.text(b"TALB").as_deref(), Some("Album"));
+        assert_eq!(id3.text(b"TCON").as_deref(), Some("Rock/Pop"));
+        assert_eq!(id3.text(b"TYER"), None);
+        assert_eq!(
+            id3.picture(),
+            Some(Picture {
+                mime_type: "image/jpeg",
+                kind: Picture::FRONT_COVER,
+                data: b"cover",
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_version() -> io::Result<()> {
+        // an ID3v2.2 tag with one 3-letter frame, then the audio
+        let mut data = b"ID3\x02\x00\x00".to_vec();
+        data.extend(u28(11).into());
+        data.extend(b"TT2\x00\x00\x05\x00abcd");
+        data.extend(b"fLaC");
+
+        let mut source = io::Cursor::new(data);
+        assert!(Id3::read(&mut source)?.is_none());
+        assert_eq!(source.position(), 21);
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_extended_header() {
+        let mut data = b"ID3\x04\x00".to_vec();
+        data.push(HeaderFlags::ExtendedHeader as u8);
+        data.extend(u28(10).into());
+        data.extend(100u32.to_be_bytes());
+        data.extend([0; 200]);
+
+        let e = Id3::read(io::Cursor::new(data)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(decode_text(0, b"caf\xE9\0").as_deref(), Some("café"));
+        assert_eq!(decode_text(3, "café".as_bytes()).as_deref(), Some("café"));
+        assert_eq!(decode_text(2, &[0, b'h', 0, b'i']).as_deref(), Some("hi"));
+        assert_eq!(decode_text(7, b"?"), None);
+    }
+}
@@ -279,7 +279,8 @@ impl<T> Receiver<T> {
             Ok(Ok(msg_ref)) => Ok(msg_ref),
             Ok(Err(tail)) => {
                 let node = RecvFut { tail }.await?;
-                self.curr = node.next.read().map_err(|_| RecvError)?.clone();
+                // not `node.next`: more messages may have been sent since this one
+                self.curr = Link::Next(Arc::clone(&node));
                 Ok(MsgRef { inner: node })
             }
             Err(e) => Err(e),
@@ -417,6 +418,26 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn woken_after_several() -> Result<(), Err> {
+        let mut sx = Sender::new();
+        let mut rx = sx.subscribe();
+
+        let waiting = tokio::spawn(async move {
+            let first = *rx.recv().await?;
+            let second = *rx.recv().await?;
+            Ok::<_, RecvError>((first, second))
+        });
+        tokio::task::yield_now().await;
+
+        // both sent before the receiver wakes up
+        sx.send(1)?;
+        sx.send(2)?;
+
+        assert_eq!(waiting.await??, (1, 2));
+        Ok(())
+    }
+
     #[test]
     fn multiple_rx() -> Result<(), Err> {
         let mut sx = Sender::new();
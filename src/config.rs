@@ -0,0 +1,1324 @@
+//! Station configuration, loaded from a TOML file and overridden from the command line.
+//!
+//! ```toml
+//! [[output]]
+//! kind = "http"
+//! bind = "0.0.0.0:6912"
+//!
+//! [[getter]]
+//! kind = "fs"
+//! dir = "./media"
+//!
+//! [[getter]]
+//! kind = "youtube-dl"
+//! executable = "/usr/bin/yt-dlp"
+//! ffmpeg = "/usr/bin/ffmpeg"
+//! cache = "./media"
+//!
+//! [[playlist]]
+//! kind = "lastfm"
+//! sid_env = "SID"
+//! ```
+//!
+//! Playlist sources are played one after another, unless `mix` is set to `"interleave"` (one song
+//! from each source in turn) or `"weighted"` (interleaved according to each source's `weight`).
+//! With `refresh = <seconds>`, new songs from the sources are added to the queue periodically.
+//!
+//! `format` (default `"mp3"`; also `"ogg"`, `"aac"` or `"flac"`) is what the station streams.
+//! Songs in any other format are skipped, unless there is a `[transcode]` section, in which case
+//! every song is converted with ffmpeg to the format, bitrate, sample rate and channels given
+//! there:
+//!
+//! ```toml
+//! [transcode]
+//! ffmpeg = "/usr/bin/ffmpeg"
+//! bitrate = 128 # kb/s
+//! sample_rate = 44100
+//! channels = 2
+//! ```
+//!
+//! With a `[loudness]` section, songs are normalized to the `target` loudness (default -18 LUFS,
+//! the ReplayGain reference).  The gain for each song comes from its ReplayGain or R128 tags, or,
+//! if it has neither and `ffmpeg` is set, from measuring it.  The gain is applied while
+//! transcoding; without `[transcode]`, it's left to the players, which get it with the song's
+//! title and artist.
+//!
+//! ```toml
+//! [loudness]
+//! target = -18.0
+//! ffmpeg = "/usr/bin/ffmpeg"
+//! ```
+//!
+//! With a `[silence]` section, each song is checked for silence with ffmpeg when it's fetched, and
+//! played from where its sound starts to where it ends, so downloads with quiet intros and outros
+//! don't leave dead air.  Silence is anything quieter than `threshold` dB for at least
+//! `min_duration` seconds.  With `max_gap`, silences longer than that many seconds in the middle of
+//! songs are skipped too, on AAC and FLAC stations, whose frames stand alone.
+//!
+//! ```toml
+//! [silence]
+//! ffmpeg = "/usr/bin/ffmpeg"
+//! threshold = -50.0
+//! min_duration = 0.5 # seconds
+//! max_gap = 5.0 # seconds
+//! ```
+//!
+//! Cue points can also be set by hand for each track through the API (see `src/output/api.rs`),
+//! over the ones found from silence: where to start it, where to stop it, and where to start fading
+//! into the next song.  With a `[cues]` section, they're saved to `path` and kept across restarts
+//! (the station won't start if that file can't be read); otherwise they only last until the
+//! station stops.
+//!
+//! ```toml
+//! [cues]
+//! path = "./cues.json"
+//! ```
+//!
+//! An `icecast` output serves the stream the way Icecast and SHOUTcast servers do, so that
+//! ordinary players (VLC, mpv, hardware players) can play it with the current song's title:
+//!
+//! ```toml
+//! [[output]]
+//! kind = "icecast"
+//! bind = "0.0.0.0:8000"
+//! name = "sandy" # shown by players; printable ASCII only
+//! metaint = 16000 # bytes of audio between song titles
+//! ```
+//!
+//! With an `[hls]` section, `http` outputs also serve the stream with HTTP Live Streaming, at
+//! `/hls/live.m3u8`, for mobile browsers and CDNs.  Only MP3 and AAC stations can be segmented,
+//! and there has to be an `http` output.
+//!
+//! ```toml
+//! [hls]
+//! segment = 6 # seconds
+//! window = 6 # segments in the playlist
+//! ```
+//!
+//! With a `[crossfade]` section, each song fades into the next over `duration` seconds instead of
+//! cutting straight to it.  The overlap is mixed and encoded again with the `[transcode]` settings,
+//! so crossfading needs transcoding, and an MP3 or AAC station.  That starts 10 seconds before the
+//! fade, while the song plays on, so the next song has to have loaded by then.  `curve` is how the
+//! volumes change: `"equal-power"` (the default) keeps the loudness even through the fade;
+//! `"linear"`, `"s-curve"`, `"logarithmic"` and `"exponential"` are the others.  With a `[silence]`
+//! section too, songs fade from where their sound ends into where the next one's starts, rather
+//! than into and out of nothing.
+//!
+//! ```toml
+//! [crossfade]
+//! duration = 4.0 # seconds
+//! curve = "equal-power"
+//! ```
+//!
+//! An `icecast-source` output relays the stream to an existing Icecast server instead, as its
+//! source client, reconnecting whenever the connection drops:
+//!
+//! ```toml
+//! [[output]]
+//! kind = "icecast-source"
+//! host = "icecast.example.com:8000"
+//! mount = "/sandy"
+//! user = "source"
+//! password_env = "ICECAST_PASSWORD" # or `password = "..."`
+//! ```
+//!
+//! With a `[state]` section, the queue, the current song and how far into it the station is, and
+//! the recent history are saved to `path` every `interval` seconds, and restored from it on
+//! startup.  Songs that were already fetched to a file that's kept (by an `fs` getter, or to a
+//! `youtube-dl` getter's `cache`) are loaded from it again rather than fetched:
+//!
+//! ```toml
+//! [state]
+//! path = "./sandy.json"
+//! interval = 30 # seconds
+//! ```
+//!
+//! With a `[history]` section, every song played is also appended to `log`, one JSON object per
+//! line, for reporting.  `artist_separation` keeps songs by an artist that played or is queued in
+//! the last that many songs from being queued next to each other.  With a `[scheduler]`, it's set
+//! there instead.
+//!
+//! ```toml
+//! [history]
+//! log = "./history.jsonl"
+//! artist_separation = 3
+//! ```
+//!
+//! With a `[scheduler]` section, songs are picked from a pool of everything the playlist sources
+//! list instead of being queued in order and looped.  No artist comes up again within
+//! `artist_separation` songs, and no song within `title_separation`, unless nothing else is left.
+//! Each song's chance is its source's `weight`, times how many songs ago it last played with
+//! `weighting = "recency"`, or times its `rating` from 1 to 5 with `weighting = "rating"`.  Ratings
+//! are set by hand through the API along with cues (so they're saved with `[cues]`), and songs
+//! without one count as a 3.  Dayparts limit which sources (numbered from 1, in order) play during
+//! some hours of the day, in UTC plus `utc_offset`.
+//!
+//! ```toml
+//! [scheduler]
+//! artist_separation = 3
+//! title_separation = 20
+//! weighting = "recency" # or "even", or "rating"
+//! utc_offset = -5
+//!
+//! [[scheduler.daypart]]
+//! from = 6 # hour, inclusive
+//! to = 10 # exclusive; a daypart ending before it starts runs past midnight
+//! playlists = [2]
+//! ```
+//!
+//! With a `[shuffle]` section, the queue is shuffled on startup.  `mode = "random"` (the default)
+//! shuffles it evenly; `"smart"` also spreads each artist's songs out across it.  Every shuffle
+//! logs its seed, and with `seed` set, the same one is used every time, so an order can be
+//! reproduced.  With `reshuffle = true`, the queue (past the `lookahead` songs already being
+//! fetched) is shuffled again each time it has played through, instead of looping in the same
+//! order.  Without a `[shuffle]` section, `fs` sources list their songs in a new random order each
+//! time instead.
+//!
+//! ```toml
+//! [shuffle]
+//! mode = "smart"
+//! seed = 1234
+//! reshuffle = true
+//! ```
+//!
+//! Songs are only fetched shortly before they play: `lookahead` (default 3) sets how many songs
+//! after the current one are fetched ahead of time.
+
+use std::{
+    env, fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::song::Format;
+
+const DEFAULT_PATH: &str = "sandy.toml";
+
+const USAGE: &str = "\
+usage: sandy [options]
+
+options:
+    -c, --config <file>   read configuration from <file> (default: ./sandy.toml, if it exists)
+        --http <addr>     bind the http output(s) to <addr>
+        --tcp <addr>      bind the tcp output(s) to <addr>
+        --media <dir>     use <dir> for every filesystem getter, playlist and cache
+    -h, --help            print this message";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// What the station streams
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub mix: Mix,
+    /// Seconds between refreshes of the playlist sources
+    pub refresh: Option<u64>,
+    /// Number of songs after the current one to fetch ahead of time
+    #[serde(default = "default_lookahead")]
+    pub lookahead: usize,
+    #[serde(default, rename = "output")]
+    pub outputs: Vec<Output>,
+    /// Getters are tried in order until one of them returns a source
+    #[serde(default, rename = "getter")]
+    pub getters: Vec<Getter>,
+    #[serde(default, rename = "playlist")]
+    pub playlists: Vec<Playlist>,
+    pub transcode: Option<Transcode>,
+    pub loudness: Option<Loudness>,
+    pub silence: Option<Silence>,
+    pub cues: Option<Cues>,
+    pub hls: Option<Hls>,
+    pub crossfade: Option<Crossfade>,
+    pub state: Option<State>,
+    pub history: Option<History>,
+    pub scheduler: Option<Scheduler>,
+    pub shuffle: Option<Shuffle>,
+}
+
+/// How the queue is shuffled
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shuffle {
+    #[serde(default)]
+    pub mode: ShuffleMode,
+    /// Used for every shuffle if set, instead of a random one
+    pub seed: Option<u64>,
+    /// Whether to shuffle again each time the queue has played through
+    #[serde(default)]
+    pub reshuffle: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShuffleMode {
+    #[default]
+    Random,
+    /// Spreads each artist's songs out
+    Smart,
+}
+
+/// Picking songs from a pool by rules, instead of playing them in order
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scheduler {
+    #[serde(default)]
+    pub artist_separation: usize,
+    #[serde(default)]
+    pub title_separation: usize,
+    #[serde(default)]
+    pub weighting: Weighting,
+    /// Hours from UTC of the time of day dayparts go by
+    #[serde(default)]
+    pub utc_offset: i32,
+    #[serde(default, rename = "daypart")]
+    pub dayparts: Vec<Daypart>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weighting {
+    /// Just by the weight of each song's source
+    #[default]
+    Even,
+    /// Also favors songs that haven't played in a while
+    Recency,
+    /// Also favors songs rated higher, through the cues API
+    Rating,
+}
+
+/// Hours of the day when only some playlist sources are played
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Daypart {
+    pub from: u32,
+    pub to: u32,
+    /// Numbered from 1
+    pub playlists: Vec<usize>,
+}
+
+impl Daypart {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&hour)
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+/// Where the station saves what it's playing, to pick up from after a restart
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    pub path: PathBuf,
+    /// Seconds between saves
+    #[serde(default = "default_save_interval")]
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct History {
+    /// Every song played is appended here
+    pub log: Option<PathBuf>,
+    /// Number of songs before an artist can be queued again
+    #[serde(default)]
+    pub artist_separation: usize,
+}
+
+/// Output parameters that every song is converted to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transcode {
+    pub ffmpeg: PathBuf,
+    /// In kb/s.  Ignored for FLAC, which is lossless.
+    #[serde(default = "default_bitrate")]
+    pub bitrate: u32,
+    /// In Hz
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u8,
+}
+
+/// Loudness normalization
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Loudness {
+    /// In LUFS
+    #[serde(default = "default_target")]
+    pub target: f64,
+    /// Measures songs that don't have ReplayGain or R128 tags, if set
+    pub ffmpeg: Option<PathBuf>,
+}
+
+/// Finding silence at the ends of songs, so they start and stop where their sound does
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Silence {
+    pub ffmpeg: PathBuf,
+    /// In dB below full scale
+    #[serde(default = "default_silence_threshold")]
+    pub threshold: f64,
+    /// Seconds of quiet that count as silence
+    #[serde(default = "default_min_silence")]
+    pub min_duration: f64,
+    /// Silences longer than this many seconds in the middle of songs are skipped too, if set
+    pub max_gap: Option<f64>,
+}
+
+/// Where cue points set by hand are kept
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cues {
+    pub path: PathBuf,
+}
+
+/// HTTP Live Streaming
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hls {
+    /// Length of each segment, in seconds
+    #[serde(default = "default_segment")]
+    pub segment: u64,
+    /// Number of segments in the playlist
+    #[serde(default = "default_window")]
+    pub window: usize,
+}
+
+/// Fading each song into the next
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Crossfade {
+    /// In seconds
+    #[serde(default = "default_crossfade")]
+    pub duration: f64,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl Crossfade {
+    /// The longest a fade can be, in seconds, whether set here or from a track's fade point
+    pub const MAX: f64 = 30.;
+}
+
+/// How volumes change through a crossfade
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Curve {
+    Linear,
+    #[default]
+    EqualPower,
+    /// Slow at the ends and quick in the middle
+    #[serde(rename = "s-curve")]
+    HalfSine,
+    Logarithmic,
+    Exponential,
+}
+
+/// How to combine multiple playlist sources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mix {
+    #[default]
+    Chain,
+    Interleave,
+    Weighted,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Output {
+    Http {
+        bind: SocketAddr,
+    },
+    Tcp {
+        bind: SocketAddr,
+    },
+    Icecast {
+        bind: SocketAddr,
+        #[serde(default = "default_name")]
+        name: String,
+        #[serde(default = "default_metaint")]
+        metaint: usize,
+    },
+    IcecastSource {
+        /// `host:port` of the Icecast server
+        host: String,
+        #[serde(default = "default_mount")]
+        mount: String,
+        #[serde(default = "default_user")]
+        user: String,
+        /// Takes precedence over `password_env`
+        password: Option<String>,
+        /// Environment variable to read the password from, if `password` is not set
+        #[serde(default = "default_password_env")]
+        password_env: String,
+        #[serde(default = "default_name")]
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Getter {
+    Fs {
+        dir: PathBuf,
+    },
+    YoutubeDl {
+        executable: PathBuf,
+        ffmpeg: PathBuf,
+        /// Directory to save transcoded downloads to, laid out like an `fs` getter
+        cache: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Playlist {
+    Lastfm {
+        /// last.fm session ID.  Takes precedence over `sid_env`.
+        sid: Option<String>,
+        /// Environment variable to read the session ID from, if `sid` is not set
+        #[serde(default = "default_sid_env")]
+        sid_env: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+    Fs {
+        dir: PathBuf,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+impl Playlist {
+    pub fn weight(&self) -> u32 {
+        match self {
+            Playlist::Lastfm { weight, .. } | Playlist::Fs { weight, .. } => *weight,
+        }
+    }
+}
+
+impl Output {
+    /// The address this output listens on, if it accepts connections
+    pub fn bind(&self) -> Option<SocketAddr> {
+        match self {
+            Output::Http { bind } | Output::Tcp { bind } | Output::Icecast { bind, .. } => {
+                Some(*bind)
+            }
+            Output::IcecastSource { .. } => None,
+        }
+    }
+
+    fn bind_mut(&mut self) -> Option<&mut SocketAddr> {
+        match self {
+            Output::Http { bind } | Output::Tcp { bind } | Output::Icecast { bind, .. } => {
+                Some(bind)
+            }
+            Output::IcecastSource { .. } => None,
+        }
+    }
+}
+
+fn default_name() -> String {
+    "sandy".into()
+}
+
+fn default_metaint() -> usize {
+    16000
+}
+
+fn default_mount() -> String {
+    "/stream".into()
+}
+
+fn default_user() -> String {
+    "source".into()
+}
+
+fn default_password_env() -> String {
+    "ICECAST_PASSWORD".into()
+}
+
+fn default_sid_env() -> String {
+    "SID".into()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_lookahead() -> usize {
+    3
+}
+
+fn default_bitrate() -> u32 {
+    128
+}
+
+fn default_sample_rate() -> u32 {
+    44100
+}
+
+fn default_channels() -> u8 {
+    2
+}
+
+fn default_target() -> f64 {
+    -18.
+}
+
+fn default_segment() -> u64 {
+    6
+}
+
+fn default_window() -> usize {
+    6
+}
+
+fn default_crossfade() -> f64 {
+    4.
+}
+
+fn default_silence_threshold() -> f64 {
+    -50.
+}
+
+fn default_min_silence() -> f64 {
+    0.5
+}
+
+fn default_save_interval() -> u64 {
+    30
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Args(String),
+    /// `--help` was passed
+    Help,
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "Error reading {}: {e}", path.display()),
+            Error::Parse(path, e) => write!(f, "Error parsing {}: {e}", path.display()),
+            Error::Args(msg) => write!(f, "{msg}\n\n{USAGE}"),
+            Error::Help => f.write_str(USAGE),
+            Error::Invalid(problems) => {
+                f.write_str("Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Default for Config {
+    /// The configuration used when no config file exists: both outputs on their usual ports, songs from
+    /// `./media` or yt-dlp, and last.fm recommendations for the session in `$SID`.
+    fn default() -> Self {
+        Self {
+            outputs: vec![
+                Output::Http {
+                    bind: SocketAddr::from(([0, 0, 0, 0], 6912)),
+                },
+                Output::Tcp {
+                    bind: SocketAddr::from(([0, 0, 0, 0], 3615)),
+                },
+            ],
+            getters: vec![
+                Getter::Fs {
+                    dir: "./media".into(),
+                },
+                Getter::YoutubeDl {
+                    executable: "/usr/bin/yt-dlp".into(),
+                    ffmpeg: "/usr/bin/ffmpeg".into(),
+                    cache: Some("./media".into()),
+                },
+            ],
+            playlists: vec![Playlist::Lastfm {
+                sid: None,
+                sid_env: default_sid_env(),
+                weight: default_weight(),
+            }],
+            format: Format::Mp3,
+            mix: Mix::Chain,
+            refresh: None,
+            lookahead: default_lookahead(),
+            transcode: None,
+            loudness: None,
+            silence: None,
+            cues: None,
+            hls: None,
+            crossfade: None,
+            state: None,
+            history: None,
+            scheduler: None,
+            shuffle: None,
+        }
+    }
+}
+
+/// Command line options that override the config file
+#[derive(Debug, Default)]
+struct Overrides {
+    config: Option<PathBuf>,
+    http: Option<SocketAddr>,
+    tcp: Option<SocketAddr>,
+    media: Option<PathBuf>,
+}
+
+impl Overrides {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut overrides = Self::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| Error::Args(format!("Missing value for {name}")))
+            };
+
+            let addr = |name: &str, value: String| {
+                value
+                    .parse()
+                    .map_err(|e| Error::Args(format!("Invalid address for {name} `{value}`: {e}")))
+            };
+
+            match arg.as_str() {
+                "-c" | "--config" => overrides.config = Some(value(&arg)?.into()),
+                "--http" => overrides.http = Some(addr(&arg, value(&arg)?)?),
+                "--tcp" => overrides.tcp = Some(addr(&arg, value(&arg)?)?),
+                "--media" => overrides.media = Some(value(&arg)?.into()),
+                "-h" | "--help" => return Err(Error::Help),
+                _ => return Err(Error::Args(format!("Unknown argument `{arg}`"))),
+            }
+        }
+
+        Ok(overrides)
+    }
+
+    fn apply(self, config: &mut Config) {
+        if let Some(addr) = self.http {
+            set_bind(
+                config,
+                addr,
+                |o| matches!(o, Output::Http { .. }),
+                |bind| Output::Http { bind },
+            );
+        }
+
+        if let Some(addr) = self.tcp {
+            set_bind(
+                config,
+                addr,
+                |o| matches!(o, Output::Tcp { .. }),
+                |bind| Output::Tcp { bind },
+            );
+        }
+
+        if let Some(media) = self.media {
+            for getter in &mut config.getters {
+                match getter {
+                    Getter::Fs { dir } => *dir = media.clone(),
+                    Getter::YoutubeDl { cache, .. } => {
+                        if cache.is_some() {
+                            *cache = Some(media.clone());
+                        }
+                    }
+                }
+            }
+
+            for playlist in &mut config.playlists {
+                if let Playlist::Fs { dir, .. } = playlist {
+                    *dir = media.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Rebinds every output matching `is_kind`, or adds a new one if there are none.
+fn set_bind(
+    config: &mut Config,
+    addr: SocketAddr,
+    is_kind: impl Fn(&Output) -> bool,
+    new: impl FnOnce(SocketAddr) -> Output,
+) {
+    let mut found = false;
+    for bind in config
+        .outputs
+        .iter_mut()
+        .filter(|o| is_kind(o))
+        .filter_map(Output::bind_mut)
+    {
+        *bind = addr;
+        found = true;
+    }
+
+    if !found {
+        config.outputs.push(new(addr));
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file and overrides given on the command line.
+    pub fn from_args() -> Result<Self, Error> {
+        Self::load(env::args().skip(1))
+    }
+
+    fn load(args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut overrides = Overrides::parse(args)?;
+
+        let mut config = match overrides.config.take() {
+            Some(path) => Self::read(&path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::read(DEFAULT_PATH.as_ref())?,
+            None => Self::default(),
+        };
+
+        overrides.apply(&mut config);
+        config.resolve_env();
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Fills in values that come from environment variables.
+    fn resolve_env(&mut self) {
+        for playlist in &mut self.playlists {
+            if let Playlist::Lastfm {
+                sid: sid @ None,
+                sid_env,
+                ..
+            } = playlist
+            {
+                *sid = env::var(sid_env).ok();
+            }
+        }
+
+        for output in &mut self.outputs {
+            if let Output::IcecastSource {
+                password: password @ None,
+                password_env,
+                ..
+            } = output
+            {
+                *password = env::var(password_env).ok();
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Io(path.into(), e))?;
+        toml::from_str(&text).map_err(|e| Error::Parse(path.into(), e))
+    }
+
+    /// Checks everything that would otherwise fail (or panic) once the station is running, and
+    /// reports all of the problems at once.
+    fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+
+        if self.outputs.is_empty() {
+            problems.push("no outputs configured".into());
+        }
+
+        for (i, bind) in self.outputs.iter().map(Output::bind).enumerate() {
+            let Some(bind) = bind else { continue };
+            if let Some(j) = self.outputs[..i]
+                .iter()
+                .position(|other| other.bind() == Some(bind))
+            {
+                problems.push(format!(
+                    "output #{} binds {bind}, same as output #{}",
+                    i + 1,
+                    j + 1
+                ));
+            }
+        }
+
+        for (i, output) in self.outputs.iter().enumerate() {
+            match output {
+                Output::Icecast { metaint: 0, .. } => {
+                    problems.push(format!("output #{}: metaint must be at least 1", i + 1))
+                }
+                Output::IcecastSource {
+                    password: None,
+                    password_env,
+                    ..
+                } => problems.push(format!(
+                    "output #{}: no `password` given and environment variable {password_env} is not set",
+                    i + 1
+                )),
+                Output::IcecastSource { mount, .. } if !mount.starts_with('/') => {
+                    problems.push(format!("output #{}: mount must start with /", i + 1))
+                }
+                _ => (),
+            }
+        }
+
+        for (i, output) in self.outputs.iter().enumerate() {
+            // these go into request and response headers as they are
+            match output {
+                Output::Icecast { name, .. } => check_header(&mut problems, i, "name", name, true),
+                Output::IcecastSource { name, mount, .. } => {
+                    check_header(&mut problems, i, "name", name, true);
+                    check_header(&mut problems, i, "mount", mount, false);
+                }
+                Output::Http { .. } | Output::Tcp { .. } => (),
+            }
+        }
+
+        if self.getters.is_empty() {
+            problems.push("no getters configured".into());
+        }
+
+        for (i, getter) in self.getters.iter().enumerate() {
+            match getter {
+                Getter::Fs { dir } => check_dir(&mut problems, "getter", i, dir),
+                Getter::YoutubeDl {
+                    executable,
+                    ffmpeg,
+                    cache,
+                } => {
+                    check_file(&mut problems, "getter", i, executable);
+                    check_file(&mut problems, "getter", i, ffmpeg);
+                    if let Some(dir) = cache.as_ref().filter(|dir| dir.exists()) {
+                        check_dir(&mut problems, "getter", i, dir);
+                    }
+                }
+            }
+        }
+
+        if self.playlists.is_empty() {
+            problems.push("no playlist sources configured".into());
+        }
+
+        if self.mix == Mix::Weighted && self.playlists.iter().all(|p| p.weight() == 0) {
+            problems.push("every playlist source has weight 0".into());
+        }
+
+        if self.refresh == Some(0) {
+            problems.push("refresh interval must be at least 1 second".into());
+        }
+
+        for (i, playlist) in self.playlists.iter().enumerate() {
+            match playlist {
+                Playlist::Lastfm {
+                    sid: None, sid_env, ..
+                } => problems.push(format!(
+                    "playlist #{}: no `sid` given and environment variable {sid_env} is not set",
+                    i + 1
+                )),
+                Playlist::Lastfm { sid: Some(_), .. } => (),
+                Playlist::Fs { dir, .. } => check_dir(&mut problems, "playlist", i, dir),
+            }
+        }
+
+        if let Some(transcode) = &self.transcode {
+            if !transcode.ffmpeg.is_file() {
+                problems.push(format!(
+                    "transcode: {} does not exist",
+                    transcode.ffmpeg.display()
+                ));
+            }
+
+            if transcode.bitrate == 0 {
+                problems.push("transcode: bitrate must be at least 1 kb/s".into());
+            }
+
+            // the sample rates MPEG audio can encode
+            const MP3_RATES: [u32; 9] =
+                [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+            if transcode.sample_rate == 0
+                || (self.format == Format::Mp3 && !MP3_RATES.contains(&transcode.sample_rate))
+            {
+                problems.push(format!(
+                    "transcode: {} can't be encoded at {} Hz",
+                    self.format, transcode.sample_rate
+                ));
+            }
+
+            if !(1..=2).contains(&transcode.channels) {
+                problems.push("transcode: channels must be 1 or 2".into());
+            }
+        }
+
+        if let Some(loudness) = &self.loudness {
+            if !(-70.0..=0.0).contains(&loudness.target) {
+                problems.push("loudness: target must be between -70 and 0 LUFS".into());
+            }
+
+            if let Some(ffmpeg) = loudness.ffmpeg.as_ref().filter(|path| !path.is_file()) {
+                problems.push(format!("loudness: {} does not exist", ffmpeg.display()));
+            }
+        }
+
+        if let Some(silence) = &self.silence {
+            if !silence.ffmpeg.is_file() {
+                problems.push(format!(
+                    "silence: {} does not exist",
+                    silence.ffmpeg.display()
+                ));
+            }
+
+            if !(-100.0..=0.0).contains(&silence.threshold) {
+                problems.push("silence: threshold must be between -100 and 0 dB".into());
+            }
+
+            if silence.min_duration <= 0. {
+                problems.push("silence: min_duration must be more than 0 seconds".into());
+            }
+
+            if silence
+                .max_gap
+                .is_some_and(|gap| gap < silence.min_duration)
+            {
+                problems.push("silence: max_gap must be at least min_duration".into());
+            }
+
+            // MP3 frames can lean on the ones before them, and Ogg pages count up from the ones
+            // before them, so neither can have frames taken out of the middle
+            if silence.max_gap.is_some() && !matches!(self.format, Format::Aac | Format::Flac) {
+                problems.push(format!(
+                    "silence: max_gap can't cut gaps out of {}",
+                    self.format
+                ));
+            }
+        }
+
+        if let Some(hls) = &self.hls {
+            if !matches!(self.format, Format::Mp3 | Format::Aac) {
+                problems.push(format!("hls: {} can't be segmented", self.format));
+            }
+
+            if !self
+                .outputs
+                .iter()
+                .any(|o| matches!(o, Output::Http { .. }))
+            {
+                problems.push("hls: needs an http output to serve the playlist from".into());
+            }
+
+            if hls.segment == 0 {
+                problems.push("hls: segments must be at least 1 second".into());
+            }
+
+            if hls.window == 0 {
+                problems.push("hls: window must be at least 1 segment".into());
+            }
+        }
+
+        if let Some(crossfade) = &self.crossfade {
+            if self.transcode.is_none() {
+                problems.push("crossfade: needs [transcode] to encode the overlap".into());
+            }
+
+            if !matches!(self.format, Format::Mp3 | Format::Aac) {
+                problems.push(format!("crossfade: {} can't be spliced", self.format));
+            }
+
+            if !(0.1..=Crossfade::MAX).contains(&crossfade.duration) {
+                problems.push(format!(
+                    "crossfade: duration must be between 0.1 and {} seconds",
+                    Crossfade::MAX
+                ));
+            }
+        }
+
+        if let Some(state) = &self.state {
+            if state.interval == 0 {
+                problems.push("state: interval must be at least 1 second".into());
+            }
+            check_parent(&mut problems, "state", &state.path);
+        }
+
+        if let Some(cues) = &self.cues {
+            check_parent(&mut problems, "cues", &cues.path);
+        }
+
+        if let Some(log) = self
+            .history
+            .as_ref()
+            .and_then(|history| history.log.as_ref())
+        {
+            check_parent(&mut problems, "history", log);
+        }
+
+        if let Some(scheduler) = &self.scheduler {
+            // the scheduler keeps artists apart by its own rule
+            if self
+                .history
+                .as_ref()
+                .is_some_and(|history| history.artist_separation > 0)
+            {
+                problems.push(
+                    "history: artist_separation doesn't apply with [scheduler]; set it there instead"
+                        .into(),
+                );
+            }
+
+            if !(-14..=14).contains(&scheduler.utc_offset) {
+                problems.push("scheduler: utc_offset must be between -14 and 14 hours".into());
+            }
+
+            for (i, daypart) in scheduler.dayparts.iter().enumerate() {
+                if daypart.from > 23 || daypart.to > 24 {
+                    problems.push(format!(
+                        "scheduler: daypart #{} has hours outside 0 to 24",
+                        i + 1
+                    ));
+                }
+
+                if let Some(n) = daypart
+                    .playlists
+                    .iter()
+                    .find(|&&n| n == 0 || n > self.playlists.len())
+                {
+                    problems.push(format!(
+                        "scheduler: daypart #{} plays playlist source #{n}, which doesn't exist",
+                        i + 1
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(problems))
+        }
+    }
+}
+
+fn check_dir(problems: &mut Vec<String>, section: &str, i: usize, dir: &Path) {
+    if !dir.is_dir() {
+        problems.push(format!(
+            "{section} #{}: {} is not a directory",
+            i + 1,
+            dir.display()
+        ));
+    }
+}
+
+/// Checks that an output's `field` can go in an HTTP header: ASCII, without line breaks or other
+/// control characters, and without spaces unless `spaces` is set
+fn check_header(problems: &mut Vec<String>, i: usize, field: &str, value: &str, spaces: bool) {
+    if !value
+        .bytes()
+        .all(|b| b.is_ascii_graphic() || (spaces && b == b' '))
+    {
+        problems.push(format!(
+            "output #{}: {field} must be printable ASCII{}",
+            i + 1,
+            if spaces { "" } else { " without spaces" }
+        ));
+    }
+}
+
+/// Checks that `file` can be created, if it doesn't exist yet
+fn check_parent(problems: &mut Vec<String>, section: &str, file: &Path) {
+    let parent = match file.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return,
+    };
+
+    if !parent.is_dir() {
+        problems.push(format!(
+            "{section}: {} is not a directory",
+            parent.display()
+        ));
+    }
+}
+
+fn check_file(problems: &mut Vec<String>, section: &str, i: usize, file: &Path) {
+    if !file.is_file() {
+        problems.push(format!(
+            "{section} #{}: {} does not exist",
+            i + 1,
+            file.display()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|&s| s.to_owned())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn parse() {
+        let config: Config = toml::from_str(
+            r#"
+            format = "flac"
+
+            [[output]]
+            kind = "http"
+            bind = "127.0.0.1:8000"
+
+            [[getter]]
+            kind = "youtube-dl"
+            executable = "yt-dlp"
+            ffmpeg = "ffmpeg"
+
+            [[playlist]]
+            kind = "lastfm"
+            sid = "abc"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.format, Format::Flac);
+        assert!(config.transcode.is_none());
+        assert!(
+            matches!(config.outputs[..], [Output::Http { bind }] if bind == SocketAddr::from(([127, 0, 0, 1], 8000)))
+        );
+        assert!(matches!(
+            &config.getters[..],
+            [Getter::YoutubeDl { cache: None, .. }]
+        ));
+        assert!(matches!(
+            &config.playlists[..],
+            [Playlist::Lastfm { sid: Some(sid), sid_env, weight: 1 }] if sid == "abc" && sid_env == "SID"
+        ));
+
+        assert!(toml::from_str::<Config>("[[output]]\nkind = \"http\"\nport = 1").is_err());
+        assert!(toml::from_str::<Config>("[[output]]\nkind = \"carrier-pigeon\"").is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let mut config = Config::default();
+        Overrides::parse(args(&["--tcp", "127.0.0.1:1234", "--media", "/srv/music"]))
+            .unwrap()
+            .apply(&mut config);
+
+        assert_eq!(
+            config.outputs[1].bind(),
+            Some(SocketAddr::from(([127, 0, 0, 1], 1234)))
+        );
+        assert!(matches!(&config.getters[0], Getter::Fs { dir } if dir == Path::new("/srv/music")));
+
+        assert!(matches!(
+            Overrides::parse(args(&["--http"])),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(
+            Overrides::parse(args(&["--http", "localhost"])),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(Overrides::parse(args(&["-h"])), Err(Error::Help)));
+    }
+
+    #[test]
+    fn validate() {
+        let config = Config {
+            outputs: vec![
+                Output::Http {
+                    bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+                },
+                Output::Tcp {
+                    bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+                },
+                Output::Icecast {
+                    bind: SocketAddr::from(([0, 0, 0, 0], 8001)),
+                    name: "sandy\r\nSet-Cookie: a=b".into(),
+                    metaint: default_metaint(),
+                },
+            ],
+            getters: vec![Getter::Fs {
+                dir: "/does/not/exist".into(),
+            }],
+            playlists: vec![],
+            format: Format::Mp3,
+            mix: Mix::Chain,
+            refresh: None,
+            lookahead: default_lookahead(),
+            transcode: Some(Transcode {
+                ffmpeg: "/does/not/exist".into(),
+                bitrate: 128,
+                sample_rate: 44000,
+                channels: 2,
+            }),
+            loudness: Some(Loudness {
+                target: 6.,
+                ffmpeg: None,
+            }),
+            silence: Some(Silence {
+                ffmpeg: "/does/not/exist".into(),
+                threshold: -50.,
+                min_duration: 0.5,
+                max_gap: Some(5.),
+            }),
+            cues: Some(Cues {
+                path: "/does/not/exist/cues.json".into(),
+            }),
+            hls: Some(Hls {
+                segment: 0,
+                window: 6,
+            }),
+            crossfade: Some(Crossfade {
+                duration: 0.,
+                curve: Curve::Linear,
+            }),
+            state: Some(State {
+                path: "/does/not/exist/state.json".into(),
+                interval: 30,
+            }),
+            history: Some(History {
+                log: None,
+                artist_separation: 2,
+            }),
+            scheduler: Some(Scheduler {
+                dayparts: vec![Daypart {
+                    from: 22,
+                    to: 6,
+                    playlists: vec![1],
+                }],
+                ..Default::default()
+            }),
+            shuffle: None,
+        };
+
+        let Err(Error::Invalid(problems)) = config.validate() else {
+            panic!("config should be invalid");
+        };
+        assert_eq!(problems.len(), 15, "{problems:?}");
+        assert!(
+            problems.contains(&"output #3: name must be printable ASCII".into()),
+            "{problems:?}"
+        );
+
+        let config = Config {
+            outputs: vec![Output::Tcp {
+                bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            }],
+            ..config
+        };
+        let Err(Error::Invalid(problems)) = config.validate() else {
+            panic!("config should be invalid");
+        };
+        assert!(
+            problems.contains(&"hls: needs an http output to serve the playlist from".into()),
+            "{problems:?}"
+        );
+    }
+}
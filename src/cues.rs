@@ -0,0 +1,213 @@
+//! Cue points set by hand for particular tracks, like cutting a radio edit out of a long upload.
+//! They're kept apart from the playlist, by track id, so they stick to the track however it gets
+//! queued, and override the cues found from silence.  A rating from 1 to 5 can be set along with
+//! them, for the scheduler to weight the track by.
+
+use std::{collections::HashMap, io, path::PathBuf, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{playlist::TrackId, song::Cues, state};
+
+/// Points in a track, in seconds from the start of it, and its rating
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manual {
+    /// Where to start playing it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue_in: Option<f64>,
+    /// Where to stop playing it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue_out: Option<f64>,
+    /// Where to start fading into the next song, when crossfading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fade: Option<f64>,
+    /// From 1 to 5, for the scheduler to weight it by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+}
+
+impl Manual {
+    /// Whether the points are all in the track, and in order, and the rating is from 1 to 5
+    pub fn valid(&self) -> bool {
+        if self.rating.is_some_and(|rating| !(1..=5).contains(&rating)) {
+            return false;
+        }
+
+        let points = [self.cue_in, self.fade, self.cue_out];
+        let mut points = points.iter().flatten();
+
+        let mut last = 0.;
+        points.all(|&point| {
+            let ordered = point.is_finite() && point >= last;
+            last = point;
+            ordered
+        }) && self
+            .cue_in
+            .zip(self.cue_out)
+            .is_none_or(|(from, to)| from < to)
+    }
+
+    /// Puts the points that are set in place of the ones in `cues`
+    pub fn apply(&self, cues: &mut Cues) {
+        if let Some(cue_in) = self.cue_in {
+            cues.start = cue_in;
+        }
+        if let Some(cue_out) = self.cue_out {
+            cues.end = Some(cue_out);
+        }
+        if let Some(fade) = self.fade {
+            cues.fade = Some(fade);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Store {
+    /// Where the cues are saved, if anywhere
+    path: Option<PathBuf>,
+    cues: RwLock<HashMap<TrackId, Manual>>,
+    /// Held while saving, so saves don't write over each other
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Store {
+    /// Loads the cues saved at `path`, which will be saved to again as they change
+    pub async fn load(path: PathBuf) -> io::Result<Self> {
+        let cues = match tokio::fs::read(&path).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            cues: RwLock::new(cues),
+            saving: Default::default(),
+        })
+    }
+
+    pub fn get(&self, track: TrackId) -> Option<Manual> {
+        self.cues
+            .read()
+            .expect("Error locking cues")
+            .get(&track)
+            .copied()
+    }
+
+    pub fn all(&self) -> HashMap<TrackId, Manual> {
+        self.cues.read().expect("Error locking cues").clone()
+    }
+
+    /// Sets the track's cues, or with `None`, clears them.  Returns the ones it had.
+    pub async fn set(&self, track: TrackId, manual: Option<Manual>) -> io::Result<Option<Manual>> {
+        let _saving = self.saving.lock().await;
+
+        let (old, json) = {
+            let mut cues = self.cues.write().expect("Error locking cues");
+            let old = match manual {
+                Some(manual) => cues.insert(track, manual),
+                None => cues.remove(&track),
+            };
+            (
+                old,
+                serde_json::to_vec(&*cues).expect("Error serializing cues"),
+            )
+        };
+
+        if let Some(path) = &self.path {
+            state::replace(path, json).await?;
+        }
+
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points() {
+        let manual = Manual {
+            cue_in: Some(12.),
+            cue_out: Some(200.),
+            fade: Some(195.),
+            rating: Some(4),
+        };
+        assert!(manual.valid());
+        assert!(Manual::default().valid());
+        assert!(Manual {
+            fade: Some(10.),
+            ..Default::default()
+        }
+        .valid());
+
+        for invalid in [
+            Manual {
+                cue_in: Some(12.),
+                cue_out: Some(12.),
+                ..Default::default()
+            },
+            Manual {
+                fade: Some(5.),
+                ..manual
+            },
+            Manual {
+                cue_in: Some(-1.),
+                ..Default::default()
+            },
+            Manual {
+                cue_out: Some(f64::NAN),
+                ..Default::default()
+            },
+            Manual {
+                rating: Some(0),
+                ..Default::default()
+            },
+            Manual {
+                rating: Some(6),
+                ..manual
+            },
+        ] {
+            assert!(!invalid.valid(), "{invalid:?}");
+        }
+
+        let mut cues = Cues {
+            start: 1.,
+            end: Some(250.),
+            gaps: vec![(100., 110.)],
+            fade: None,
+        };
+        Manual {
+            cue_out: Some(200.),
+            ..Default::default()
+        }
+        .apply(&mut cues);
+        assert_eq!(cues.start, 1.);
+        assert_eq!(cues.end, Some(200.));
+        assert_eq!(cues.gaps.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn saved() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("sandy-cues-{}.json", std::process::id()));
+        let track = TrackId(0xabc);
+        let manual = Manual {
+            cue_in: Some(3.5),
+            ..Default::default()
+        };
+
+        let store = Store::load(path.clone()).await?;
+        assert_eq!(store.get(track), None);
+        store.set(track, Some(manual)).await?;
+
+        let store = Store::load(path.clone()).await?;
+        assert_eq!(store.get(track), Some(manual));
+        assert_eq!(store.set(track, None).await?, Some(manual));
+        assert!(Store::load(path.clone()).await?.all().is_empty());
+
+        std::fs::remove_file(path)
+    }
+}
@@ -3,22 +3,42 @@ use std::{
     fs,
     future::{ready, Ready},
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use crate::playlist::SongMetadata;
 
-use super::Source;
+use super::{Fetched, Source};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Ext {
     Mp3,
+    Ogg,
+    Opus,
+    Aac,
+    Flac,
+}
+
+impl Ext {
+    /// Every extension of a format that can be played
+    pub const ALL: [Self; 5] = [Self::Mp3, Self::Ogg, Self::Opus, Self::Aac, Self::Flac];
+
+    pub fn of(path: &Path) -> Option<Self> {
+        let ext = path.extension()?;
+        Self::ALL
+            .into_iter()
+            .find(|&e| ext.eq_ignore_ascii_case(AsRef::<OsStr>::as_ref(&e)))
+    }
 }
 
 impl AsRef<str> for Ext {
     fn as_ref(&self) -> &'static str {
         match self {
             Self::Mp3 => "mp3",
+            Self::Ogg => "ogg",
+            Self::Opus => "opus",
+            Self::Aac => "aac",
+            Self::Flac => "flac",
         }
     }
 }
@@ -30,9 +50,19 @@ impl AsRef<OsStr> for Ext {
     }
 }
 
+/// A title or artist as one file name, so that songs queued through the API can't point outside
+/// the directory
+fn component(name: &str) -> String {
+    match name {
+        "." | ".." => "_".into(),
+        name => name.replace(['/', '\\', '\0'], "_"),
+    }
+}
+
 #[derive(Debug)]
 pub struct Fs {
     dir: PathBuf,
+    /// Extension of new files.  Existing songs are found in any format.
     ext: Ext,
 }
 
@@ -44,30 +74,78 @@ impl Fs {
         }
     }
 
+    /// Where the song goes, with this getter's extension
     pub fn path(&self, song: &SongMetadata) -> PathBuf {
-        let mut dir = self.dir.join(&song.artist);
+        self.path_with(song, self.ext)
+    }
+
+    fn path_with(&self, song: &SongMetadata, ext: Ext) -> PathBuf {
+        let mut dir = self.dir.join(component(&song.artist));
 
-        dir.push(&song.title);
-        dir.set_extension(self.ext);
+        dir.push(component(&song.title));
+        dir.set_extension(ext);
 
         dir
     }
+
+    /// The song's file, in whichever format it exists
+    fn find(&self, song: &SongMetadata) -> Option<PathBuf> {
+        std::iter::once(self.ext)
+            .chain(Ext::ALL)
+            .map(|ext| self.path_with(song, ext))
+            .find(|path| path.exists())
+    }
 }
 
 impl super::Getter for Fs {
     type Error = io::Error;
-    type Future = Ready<io::Result<Source>>;
+    type Future = Ready<io::Result<Fetched>>;
 
     fn can_get(&self, song: &SongMetadata) -> Option<bool> {
-        Some(self.path(song).exists())
+        Some(self.find(song).is_some())
     }
 
     fn get(&self, song: &SongMetadata) -> Self::Future {
+        let path = self.find(song).unwrap_or_else(|| self.path(song));
         ready(
             fs::File::options()
                 .read(true)
-                .open(self.path(song))
-                .map(Source::File),
+                .open(&path)
+                .map(|file| Fetched {
+                    path: Some(path),
+                    ..Source::File(file).into()
+                }),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let fs = Fs::new("/music", Ext::Mp3);
+        assert_eq!(
+            fs.path(&song("Title", "Artist", None)),
+            Path::new("/music/Artist/Title.mp3")
+        );
+
+        for (title, artist) in [
+            ("/etc/passwd", ".."),
+            ("../../../etc/passwd", "."),
+            ("..\\..\\x", "/"),
+        ] {
+            let path = fs.path(&song(title, artist, None));
+            assert_eq!(path.parent().unwrap().parent(), Some(Path::new("/music")));
+            assert!(
+                !path
+                    .components()
+                    .any(|c| c == std::path::Component::ParentDir),
+                "{path:?}"
+            );
+        }
+    }
+}
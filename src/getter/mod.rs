@@ -1,8 +1,11 @@
-use std::io;
+use std::{fmt, io, path::PathBuf, sync::Arc};
 
-use futures::Future;
+use futures::{future::BoxFuture, Future, FutureExt, TryFutureExt};
 
-use crate::playlist::SongMetadata;
+use crate::{
+    config, loudness::Normalizer, playlist::SongMetadata, silence::Detector, song,
+    transcode::Transcoder,
+};
 
 pub mod fs;
 pub mod youtube_dl;
@@ -31,9 +34,29 @@ impl io::Seek for Source {
     }
 }
 
+/// A song a getter got
+#[derive(Debug)]
+pub struct Fetched {
+    pub source: Source,
+    /// What else the getter's source knows about the song, like its album
+    pub details: Option<SongMetadata>,
+    /// The file the song is in, if it stays there
+    pub path: Option<PathBuf>,
+}
+
+impl From<Source> for Fetched {
+    fn from(source: Source) -> Self {
+        Self {
+            source,
+            details: None,
+            path: None,
+        }
+    }
+}
+
 pub trait Getter {
     type Error: std::error::Error;
-    type Future: Future<Output = Result<Source, Self::Error>>;
+    type Future: Future<Output = Result<Fetched, Self::Error>>;
 
     fn can_get(&self, _: &SongMetadata) -> Option<bool> {
         None
@@ -42,31 +65,173 @@ pub trait Getter {
     fn get(&self, song: &SongMetadata) -> Self::Future;
 }
 
-macro_rules! multi {
-		($($getter: ident $(,)?)+) => {
-			(
-				|song| async {
-					let mut src = None;
-
-					$(
-						if src.is_none() && $getter.can_get(&song).unwrap_or(true) {
-							match $getter.get(&song).await {
-								Ok(s) => src = Some(s),
-								Err(e) => log::error!("{} error: {:?}", stringify!($getter), e),
-							}
-						}
-					)+
-
-					src.and_then(|source| match Song::load(song, source) {
-						Ok(song) => Some(song),
-						Err(e) => {
-							log::error!("Error reading song: {:?}", e);
-							None
-						}
-					})
-				}
-			)
-		};
+/// A getter chosen at runtime, e.g. from the config file
+#[derive(Debug, Clone)]
+pub enum Any {
+    Fs(Arc<fs::Fs>),
+    YoutubeDl(youtube_dl::YoutubeDl),
+}
+
+impl Getter for Any {
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Fetched, Self::Error>>;
+
+    fn can_get(&self, song: &SongMetadata) -> Option<bool> {
+        match self {
+            Self::Fs(fs) => fs.can_get(song),
+            Self::YoutubeDl(ytdl) => ytdl.can_get(song),
+        }
+    }
+
+    fn get(&self, song: &SongMetadata) -> Self::Future {
+        match self {
+            Self::Fs(fs) => fs.get(song).map_err(Error::Fs).boxed(),
+            Self::YoutubeDl(ytdl) => ytdl.get(song).map_err(Error::YoutubeDl).boxed(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Fs(io::Error),
+    YoutubeDl(youtube_dl::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Fs(e) => write!(f, "fs: {e}"),
+            Error::YoutubeDl(e) => write!(f, "youtube_dl: {e}"),
+        }
+    }
 }
 
-pub(crate) use multi;
+impl std::error::Error for Error {}
+
+impl fmt::Display for Any {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fs(_) => f.write_str("fs"),
+            Self::YoutubeDl(_) => f.write_str("youtube_dl"),
+        }
+    }
+}
+
+impl config::Getter {
+    pub fn getter(&self) -> Any {
+        match self {
+            Self::Fs { dir } => Any::Fs(Arc::new(fs::Fs::new(dir, fs::Ext::Mp3))),
+            Self::YoutubeDl {
+                executable,
+                ffmpeg,
+                cache,
+            } => Any::YoutubeDl(youtube_dl::YoutubeDl {
+                executable: executable.clone(),
+                ffmpeg: ffmpeg.clone(),
+                fs: cache
+                    .as_ref()
+                    .map(|dir| Arc::new(fs::Fs::new(dir, fs::Ext::Mp3))),
+            }),
+        }
+    }
+}
+
+/// Tries each getter in order until one of them returns a source
+#[derive(Debug, Clone, Default)]
+pub struct Chain {
+    pub getters: Vec<Any>,
+    /// Converts every song to the station's format and parameters, if set
+    pub transcoder: Option<Transcoder>,
+    /// Works out each song's gain, if set
+    pub normalizer: Option<Normalizer>,
+    /// Finds where each song's sound starts and ends, if set
+    pub detector: Option<Detector>,
+}
+
+impl Chain {
+    /// Whether any getter might be able to get the song
+    pub fn can_get(&self, song: &SongMetadata) -> bool {
+        self.getters.iter().any(|g| g.can_get(song).unwrap_or(true))
+    }
+
+    pub async fn load(&self, mut song: SongMetadata) -> Option<song::Any> {
+        let mut src = None;
+
+        // fetched before, like before a restart
+        if let Some(path) = song.path.take() {
+            match std::fs::File::open(&path) {
+                Ok(file) => {
+                    src = Some(Source::File(file));
+                    song.path = Some(path);
+                }
+                Err(e) => log::warn!("Error opening {}: {}", path.display(), e),
+            }
+        }
+
+        let getters = if src.is_none() {
+            &self.getters[..]
+        } else {
+            &[]
+        };
+        for getter in getters.iter().filter(|g| g.can_get(&song).unwrap_or(true)) {
+            match getter.get(&song).await {
+                Ok(fetched) => {
+                    song.source = Some(getter.to_string());
+                    song.path = fetched.path;
+                    if let Some(details) = fetched.details {
+                        song.fill(details);
+                    }
+                    src = Some(fetched.source);
+                    break;
+                }
+                Err(e) => log::error!("{} error: {:?}", getter, e),
+            }
+        }
+
+        let mut source = src?;
+
+        // transcoding drops the tags
+        if self.transcoder.is_some() {
+            match song::tags(&mut source) {
+                Ok(tags) => song.fill(tags),
+                Err(e) => log::warn!("Error reading tags: {:?}", e),
+            }
+        }
+
+        let mut gain = match &self.normalizer {
+            Some(normalizer) => normalizer.gain(&song, &mut source).await,
+            None => None,
+        };
+
+        if let Some(transcoder) = &self.transcoder {
+            source = match transcoder.transcode(source, gain.take()).await {
+                Ok(source) => source,
+                Err(e) => {
+                    log::error!("Error transcoding {} - {}: {}", song.title, song.artist, e);
+                    return None;
+                }
+            };
+        }
+
+        // what's played, so after transcoding
+        let silences = match &self.detector {
+            Some(detector) => detector.silences(&song, &mut source).await,
+            None => None,
+        };
+
+        match song::Any::load(song, source) {
+            Ok(mut song) => {
+                song.set_gain(gain);
+                if let (Some(detector), Some(silences)) = (&self.detector, silences) {
+                    let cues = detector.cues(&song, &silences);
+                    song.set_cues(cues);
+                }
+                Some(song)
+            }
+            Err(e) => {
+                log::error!("Error reading song: {:?}", e);
+                None
+            }
+        }
+    }
+}
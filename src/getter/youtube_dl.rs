@@ -1,16 +1,24 @@
 use std::{
     fmt,
     io::{self, Cursor},
-    path::PathBuf,
-    process::ExitStatus,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
     sync::Arc,
+    time::Duration,
 };
 
+use serde::Deserialize;
 use tokio::process::Command;
 
-use crate::playlist::SongMetadata;
+use crate::{
+    playlist::{Cover, SongMetadata},
+    transcode,
+};
+
+use super::{fs::Fs, Fetched, Getter, Source};
 
-use super::{fs::Fs, Getter, Source};
+/// How long youtube-dl gets to download a song before it's given up on
+const TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug, Clone)]
 pub struct YoutubeDl {
@@ -23,6 +31,7 @@ pub struct YoutubeDl {
 pub enum Error {
     Io(io::Error),
     Download,
+    Timeout,
     Transcode,
 }
 
@@ -47,6 +56,7 @@ impl fmt::Display for Error {
         match self {
             Error::Io(e) => write!(f, "IO Error: {e}"),
             Error::Download => write!(f, "Download Error"),
+            Error::Timeout => write!(f, "Download timed out"),
             Error::Transcode => write!(f, "Transcode Error"),
         }
     }
@@ -60,50 +70,148 @@ impl From<io::Error> for Error {
 
 impl std::error::Error for Error {}
 
-impl YoutubeDl {
-    async fn get(self, song: SongMetadata) -> Result<Source, Error> {
-        let mut cmd = Command::new(&self.executable);
-        cmd.arg(song.youtube_url.as_ref().expect("youtube_url is None"))
-            .arg("-f")
-            .arg("bestaudio")
-            .arg("-o");
+/// The parts of youtube-dl's JSON info (from `--print-json`) that go in a song's metadata
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Info {
+    title: Option<String>,
+    artist: Option<String>,
+    /// Who posted it, which stands in for the artist
+    uploader: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    release_year: Option<u32>,
+    /// `YYYYMMDD`
+    upload_date: Option<String>,
+    genre: Option<String>,
+    thumbnail: Option<String>,
+}
 
-        if let Some(fs) = self.fs.as_deref() {
-            let path = fs.path(&song);
+impl From<Info> for SongMetadata {
+    fn from(info: Info) -> Self {
+        Self {
+            title: info.title.unwrap_or_default(),
+            artist: info.artist.or(info.uploader).unwrap_or_default(),
+            album: info.album,
+            album_artist: info.album_artist,
+            track: info.track_number,
+            year: info.release_year.or_else(|| {
+                info.upload_date
+                    .as_ref()
+                    .and_then(|date| date.get(..4)?.parse().ok())
+            }),
+            genre: info.genre,
+            cover: info.thumbnail.map(Cover::Url),
+            ..Default::default()
+        }
+    }
+}
 
-            let parent = path.parent().expect("No parent directory");
-            if !parent.exists() {
-                std::fs::create_dir_all(parent)?
-            }
+/// Removes a download that won't be used, if it got as far as being written.
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            log::warn!("Error removing {}: {:?}", path.display(), e);
+        }
+    }
+}
 
-            let dl = path.with_extension("dl");
-            cmd.arg(&dl);
+impl YoutubeDl {
+    /// Downloads the song, along with the info youtube-dl prints about it.  A song that's already
+    /// in the cache is taken from there without running youtube-dl at all.
+    async fn get(self, song: SongMetadata) -> Result<Fetched, Error> {
+        if let Some(fs) = self.fs.as_deref() {
+            if fs.can_get(&song) == Some(true) {
+                return Ok(fs.get(&song).await?);
+            }
+        }
 
-            Error::try_run(&mut cmd, Error::Download).await?;
+        let path = match self.fs.as_deref() {
+            Some(fs) => {
+                let path = fs.path(&song);
 
-            Error::try_run(
-                Command::new(&self.ffmpeg).arg("-i").arg(&dl).arg(&path),
-                Error::Transcode,
-            )
-            .await?;
+                let parent = path.parent().expect("No parent directory");
+                if !parent.exists() {
+                    std::fs::create_dir_all(parent)?
+                }
 
-            std::fs::remove_file(dl)?;
+                Some(path)
+            }
+            None => None,
+        };
+        let dl = match &path {
+            Some(path) => path.with_extension("dl"),
+            None => transcode::temp_path("dl"),
+        };
 
-            fs.get(&song).await.map_err(Error::from)
-        } else {
-            let out = cmd.arg("-").arg("-f").arg("bestaudio").output().await?;
-            if !out.status.success() {
+        let out = Command::new(&self.executable)
+            .arg(song.youtube_url.as_ref().expect("youtube_url is None"))
+            .args(["-f", "bestaudio", "--no-playlist", "--print-json", "-o"])
+            .arg(&dl)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let out = match tokio::time::timeout(TIMEOUT, out).await {
+            Ok(out) => out,
+            Err(_) => {
+                remove(&dl);
+                return Err(Error::Timeout);
+            }
+        };
+        let out = match out {
+            Ok(out) if out.status.success() => out.stdout,
+            Ok(_) => {
+                remove(&dl);
                 return Err(Error::Download);
             }
+            Err(e) => {
+                remove(&dl);
+                return Err(e.into());
+            }
+        };
 
-            Ok(Source::Buffer(Cursor::new(out.stdout)))
-        }
+        let details = match serde_json::from_slice::<Info>(&out) {
+            Ok(info) => Some(info.into()),
+            Err(e) => {
+                log::warn!("Invalid info for {}: {}", song.title, e);
+                None
+            }
+        };
+
+        let source = match (self.fs.as_deref(), path) {
+            (Some(fs), Some(path)) => {
+                let transcoded = Error::try_run(
+                    Command::new(&self.ffmpeg).arg("-i").arg(&dl).arg(&path),
+                    Error::Transcode,
+                )
+                .await;
+                remove(&dl);
+                transcoded?;
+
+                return Ok(Fetched {
+                    details,
+                    ..fs.get(&song).await?
+                });
+            }
+            _ => {
+                let data = tokio::fs::read(&dl).await;
+                remove(&dl);
+                Source::Buffer(Cursor::new(data?))
+            }
+        };
+
+        Ok(Fetched {
+            source,
+            details,
+            path: None,
+        })
     }
 }
 
 impl Getter for YoutubeDl {
     type Error = Error;
-    type Future = futures::future::BoxFuture<'static, Result<Source, Self::Error>>;
+    type Future = futures::future::BoxFuture<'static, Result<Fetched, Self::Error>>;
 
     fn get(&self, song: &SongMetadata) -> Self::Future {
         Box::pin(self.clone().get(song.clone()))
@@ -113,3 +221,83 @@ impl Getter for YoutubeDl {
         Some(song.youtube_url.is_some())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info() {
+        let info: Info = serde_json::from_str(
+            r#"{"title": "Video", "upload_date": "20200131", "thumbnail": "https://i.ytimg.com/x.jpg", "album": null}"#,
+        )
+        .unwrap();
+        let metadata = SongMetadata::from(info);
+
+        assert_eq!(metadata.title, "Video");
+        assert_eq!(metadata.artist, "");
+        assert_eq!(metadata.year, Some(2020));
+        assert_eq!(metadata.album, None);
+        assert_eq!(
+            metadata.cover,
+            Some(Cover::Url("https://i.ytimg.com/x.jpg".into()))
+        );
+    }
+
+    /// Runs a stand-in for youtube-dl that writes a song to the `-o` path and prints its info.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn download() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = transcode::temp_path("sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\n\
+             while [ \"$1\" != -o ]; do shift; done\n\
+             printf song > \"$2\"\n\
+             echo '{\"album\": \"Album\"}'\n",
+        )?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
+        let song = SongMetadata {
+            title: "title".into(),
+            artist: "artist".into(),
+            youtube_url: Some("https://youtu.be/x".into()),
+            ..Default::default()
+        };
+        let mut ytdl = YoutubeDl {
+            executable: script.clone(),
+            ffmpeg: "/bin/false".into(),
+            fs: None,
+        };
+
+        let Fetched {
+            source: Source::Buffer(data),
+            details,
+            path: None,
+        } = ytdl.clone().get(song.clone()).await?
+        else {
+            panic!("download should be in memory, and not kept");
+        };
+        assert_eq!(data.into_inner(), b"song");
+        assert_eq!(details.and_then(|d| d.album).as_deref(), Some("Album"));
+
+        // cached songs don't run youtube-dl
+        let dir = transcode::temp_path("cache");
+        let fs = Fs::new(&dir, crate::getter::fs::Ext::Mp3);
+        std::fs::create_dir_all(fs.path(&song).parent().unwrap())?;
+        std::fs::write(fs.path(&song), "cached")?;
+        ytdl.executable = "/bin/false".into();
+        let fs = Arc::new(fs);
+        ytdl.fs = Some(Arc::clone(&fs));
+        let fetched = ytdl.get(song.clone()).await?;
+        assert!(matches!(fetched.source, Source::File(_)));
+        assert_eq!(fetched.path, Some(fs.path(&song)));
+        assert!(fetched.details.is_none());
+
+        std::fs::remove_dir_all(dir)?;
+        std::fs::remove_file(script)?;
+        Ok(())
+    }
+}
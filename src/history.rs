@@ -0,0 +1,329 @@
+//! The log of every song played, for reporting, and the rules for what can be queued given what
+//! played recently.
+//!
+//! The log is one JSON object per line, appended to as each song ends, so it survives restarts and
+//! can be read back in order.
+
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use futures::{stream, Stream};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::runner::Played;
+
+#[derive(Debug, Clone)]
+pub struct Log {
+    pub path: PathBuf,
+}
+
+impl Log {
+    pub async fn append(&self, played: &Played) -> io::Result<()> {
+        let mut line = serde_json::to_vec(played).expect("Error serializing history");
+        line.push(b'\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await
+    }
+
+    /// The songs in the log that started from `since` to before `until`, newest first, and at
+    /// most `limit` of them.  The log only grows, so it's read back from the end, no further than
+    /// it has to be.  Lines that can't be read are skipped.
+    pub async fn recent(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<Played>> {
+        let log = self.clone();
+        tokio::task::spawn_blocking(move || log.read_back(since, until, limit))
+            .await
+            .expect("Error reading history")
+    }
+
+    fn read_back(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<Played>> {
+        const BLOCK_SIZE: u64 = 1 << 16;
+
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut found = Vec::new();
+        let mut end = file.metadata()?.len();
+        // the start of the last block read, up to its first newline: the end of a line that
+        // starts further back
+        let mut partial = Vec::new();
+        while end > 0 && found.len() < limit {
+            let start = end.saturating_sub(BLOCK_SIZE);
+            let mut block = vec![0; (end - start) as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut block)?;
+            block.append(&mut partial);
+            end = start;
+
+            let whole = match block.iter().position(|&b| b == b'\n') {
+                _ if start == 0 => 0,
+                Some(newline) => newline + 1,
+                None => {
+                    partial = block;
+                    continue;
+                }
+            };
+            partial = block[..whole].to_vec();
+
+            for line in block[whole..].split(|&b| b == b'\n').rev() {
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+                let played: Played = match serde_json::from_slice(line) {
+                    Ok(played) => played,
+                    Err(e) => {
+                        log::warn!("{}: {}", self.path.display(), e);
+                        continue;
+                    }
+                };
+
+                // everything further back started earlier still
+                if since.is_some_and(|since| played.started < since) {
+                    return Ok(found);
+                }
+                if until.is_none_or(|until| played.started < until) {
+                    found.push(played);
+                    if found.len() == limit {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Every song in the log, oldest first, read as they're needed.  Lines that can't be read
+    /// are skipped.
+    pub async fn songs(&self) -> io::Result<impl Stream<Item = Played>> {
+        let file = match tokio::fs::File::open(&self.path).await {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let lines = file.map(|file| BufReader::new(file).lines());
+        let path = self.path.clone();
+
+        Ok(stream::unfold(lines, move |mut lines| {
+            let path = path.clone();
+            async move {
+                loop {
+                    let line = match lines.as_mut()?.next_line().await {
+                        Ok(line) => line?,
+                        Err(e) => {
+                            log::error!("Error reading {}: {}", path.display(), e);
+                            return None;
+                        }
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(played) => return Some((played, lines)),
+                        Err(e) => log::warn!("{}: {}", path.display(), e),
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// The first row of the CSV export
+pub const CSV_HEADER: &str =
+    "started,id,track,title,artist,album,source,duration,played,skipped\r\n";
+
+/// A song as a row of the CSV export
+pub fn csv_row(played: &Played) -> String {
+    let song = &played.metadata;
+    let mut row = String::new();
+    writeln!(
+        row,
+        "{},{},{},{},{},{},{},{:.3},{:.3},{}\r",
+        played.started,
+        played.id,
+        played.track,
+        csv_field(&song.title),
+        csv_field(&song.artist),
+        csv_field(song.album.as_deref().unwrap_or_default()),
+        csv_field(song.source.as_deref().unwrap_or_default()),
+        played.duration,
+        played.played,
+        played.skipped,
+    )
+    .expect("Error writing to string!");
+    row
+}
+
+/// The songs as CSV, with a header row
+pub fn csv(history: &[Played]) -> String {
+    std::iter::once(CSV_HEADER.to_owned())
+        .chain(history.iter().map(csv_row))
+        .collect()
+}
+
+/// Whether a song by `artist` can follow `recent` (most recent first) without the artist coming
+/// up again within `separation` songs.  Artists are compared ignoring case.
+pub fn separated<'a>(
+    mut recent: impl Iterator<Item = &'a str>,
+    artist: &str,
+    separation: usize,
+) -> bool {
+    !recent
+        .by_ref()
+        .take(separation)
+        .any(|other| other.eq_ignore_ascii_case(artist))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        playlist::{SongMetadata, TrackId},
+        runner::Played,
+    };
+
+    use super::*;
+
+    #[test]
+    fn export() {
+        let played = Played {
+            id: 3,
+            track: TrackId(255),
+            metadata: SongMetadata {
+                title: "Hello, \"World\"".into(),
+                artist: "Artist".into(),
+                ..Default::default()
+            },
+            started: 1_700_000_000,
+            duration: 200.,
+            played: 12.5,
+            skipped: true,
+        };
+
+        let csv = csv(&[played]);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            "1700000000,3,00000000000000ff,\"Hello, \"\"World\"\"\",Artist,,,200.000,12.500,true"
+        );
+    }
+
+    #[tokio::test]
+    async fn log() -> io::Result<()> {
+        use futures::StreamExt;
+
+        let log = Log {
+            path: std::env::temp_dir().join(format!("sandy-history-{}.jsonl", std::process::id())),
+        };
+        let played = |i: u64| Played {
+            id: i,
+            track: TrackId(i),
+            metadata: SongMetadata {
+                title: format!("Song {i}"),
+                artist: "Artist".into(),
+                ..Default::default()
+            },
+            started: 1_700_000_000 + 200 * i,
+            duration: 200.,
+            played: 200.,
+            skipped: false,
+        };
+        assert!(log.recent(None, None, 10).await?.is_empty());
+
+        // several blocks' worth, with a line that can't be read in the middle
+        for i in 0..1000 {
+            log.append(&played(i)).await?;
+            if i == 500 {
+                tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&log.path)
+                    .await?
+                    .write_all(b"{\"not\": \"a song\"}\n")
+                    .await?;
+            }
+        }
+
+        let ids = |songs: Vec<Played>| songs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids(log.recent(None, None, 3).await?), [999, 998, 997]);
+        assert_eq!(
+            ids(log
+                .recent(Some(played(10).started), Some(played(13).started), 100)
+                .await?),
+            [12, 11, 10]
+        );
+        let all = log.recent(None, None, usize::MAX).await?;
+        assert_eq!(ids(all), (0..1000).rev().collect::<Vec<_>>());
+
+        let songs: Vec<_> = log.songs().await?.collect().await;
+        assert_eq!(ids(songs), (0..1000).collect::<Vec<_>>());
+
+        std::fs::remove_file(&log.path)
+    }
+
+    #[test]
+    fn logged() {
+        let played = Played {
+            id: 3,
+            track: TrackId(255),
+            metadata: SongMetadata {
+                title: "Title".into(),
+                artist: "Artist".into(),
+                track: Some(7),
+                ..Default::default()
+            },
+            started: 1_700_000_000,
+            duration: 200.,
+            played: 200.,
+            skipped: false,
+        };
+
+        let line = serde_json::to_string(&played).unwrap();
+        let read: Played = serde_json::from_str(&line).unwrap_or_else(|e| panic!("{line}: {e}"));
+        assert_eq!(read.track, TrackId(255));
+        assert_eq!(read.metadata, played.metadata);
+
+        // how songs were saved before
+        let song: SongMetadata =
+            serde_json::from_str(r#"{"title": "Title", "artist": "Artist", "track": 7}"#).unwrap();
+        assert_eq!(song.track, Some(7));
+    }
+
+    #[test]
+    fn separation() {
+        let recent = ["B", "a", "C"];
+        assert!(!separated(recent.into_iter(), "A", 2));
+        assert!(separated(recent.into_iter(), "A", 1));
+        assert!(separated(recent.into_iter(), "D", 3));
+        assert!(separated(recent.into_iter(), "B", 0));
+    }
+}
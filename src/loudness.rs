@@ -0,0 +1,375 @@
+//! Works out how much to turn each song up or down so that they all play at the same loudness.
+//!
+//! The gain comes from the song's ReplayGain or R128 tags when it has them, in ID3 tags for MP3
+//! and AAC and in Vorbis comments for Ogg and FLAC.  Otherwise, ffmpeg's `ebur128` filter measures
+//! the song's integrated loudness, which takes about as long as decoding it, so like transcoding
+//! it happens when the song is fetched.
+//!
+//! Songs aren't turned up past where their loudest peak would clip, when the peak is known.
+
+use std::{
+    fmt,
+    io::{self, Seek},
+    process::{ExitStatus, Stdio},
+};
+
+use id3::Id3;
+use tokio::{io::AsyncReadExt, process::Command};
+
+use crate::{
+    config,
+    getter::Source,
+    playlist::SongMetadata,
+    song::{self, Format},
+    transcode,
+};
+
+/// The loudness that ReplayGain gains are relative to, in LUFS
+const REPLAYGAIN_REFERENCE: f64 = -18.;
+/// The loudness that R128 gains are relative to, in LUFS
+const R128_REFERENCE: f64 = -23.;
+
+/// The tags the gain can come from
+const TAGS: [&str; 3] = [
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+    "R128_TRACK_GAIN",
+];
+
+/// How much to turn a song up by, and its loudest peak, both in dB
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Gain {
+    gain: f64,
+    /// Relative to full scale, so usually below 0
+    peak: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    pub config: config::Loudness,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ffmpeg(ExitStatus),
+    /// ffmpeg succeeded, but didn't print a loudness
+    Output,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO Error: {e}"),
+            Error::Ffmpeg(status) => write!(f, "ffmpeg failed: {status}"),
+            Error::Output => f.write_str("no loudness in ffmpeg output"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses a ReplayGain gain, like `-6.54 dB`.
+fn parse_replaygain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gain: &f64| gain.is_finite())
+}
+
+/// Parses a ReplayGain peak, which is a sample value where 1 is full scale, into dBFS.
+fn parse_peak(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|peak: &f64| peak.is_finite() && *peak > 0.)
+        .map(|peak| 20. * peak.log10())
+}
+
+/// Parses an R128 gain, which is in 1/256ths of a dB.
+fn parse_r128(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse::<i16>()
+        .ok()
+        .map(|gain| gain as f64 / 256.)
+}
+
+/// Finds the integrated loudness, and the true peak if it was measured, in the summary the
+/// `ebur128` filter prints at the end, e.g.
+///
+/// ```text
+/// [Parsed_ebur128_0 @ 0x...] Summary:
+///
+///   Integrated loudness:
+///     I:         -16.9 LUFS
+///     Threshold: -27.2 LUFS
+///
+///   True peak:
+///     Peak:       -0.4 dBFS
+/// ```
+fn parse_ebur128(output: &str) -> Option<(f64, Option<f64>)> {
+    let summary = &output[output.rfind("Summary:")?..];
+
+    let value = |prefix: &str, unit: &str| {
+        summary.lines().find_map(|line| {
+            let value = line
+                .trim()
+                .strip_prefix(prefix)?
+                .trim()
+                .strip_suffix(unit)?;
+            value.trim().parse().ok().filter(|v: &f64| v.is_finite())
+        })
+    };
+
+    Some((value("I:", "LUFS")?, value("Peak:", "dBFS")))
+}
+
+impl Normalizer {
+    /// The gain from the song's tags, if it has any, adjusted to the target loudness
+    fn tagged_gain(&self, source: &mut Source) -> io::Result<Option<Gain>> {
+        source.rewind()?;
+        let tags = match Format::sniff(&mut *source)? {
+            Format::Mp3 | Format::Aac => {
+                let id3 = Id3::read(&mut *source);
+                source.rewind()?;
+                match id3? {
+                    Some(id3) => TAGS
+                        .iter()
+                        .filter_map(|&key| Some((key.to_owned(), id3.user_text(key)?)))
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+            Format::Ogg | Format::Flac => song::comments(&mut *source)?,
+        };
+        let tag = |key: &str| {
+            tags.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.as_str())
+        };
+
+        let target = self.config.target;
+        let gain = tag("REPLAYGAIN_TRACK_GAIN")
+            .and_then(parse_replaygain)
+            .map(|gain| gain + target - REPLAYGAIN_REFERENCE)
+            .or_else(|| {
+                tag("R128_TRACK_GAIN")
+                    .and_then(parse_r128)
+                    .map(|gain| gain + target - R128_REFERENCE)
+            });
+
+        Ok(gain.map(|gain| Gain {
+            gain,
+            peak: tag("REPLAYGAIN_TRACK_PEAK").and_then(parse_peak),
+        }))
+    }
+
+    /// Measures the song's loudness with ffmpeg, in LUFS, and its true peak, in dBFS.
+    async fn measure(&self, source: &mut Source) -> Result<Option<(f64, Option<f64>)>, Error> {
+        let Some(ffmpeg) = &self.config.ffmpeg else {
+            return Ok(None);
+        };
+
+        let mut child = Command::new(ffmpeg)
+            .args(["-hide_banner", "-nostats", "-i", "pipe:0", "-vn"])
+            .args(["-af", "ebur128=peak=true", "-f", "null", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let mut output = Vec::new();
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        // read stderr as we go, so ffmpeg doesn't block writing to it
+        let taken = std::mem::replace(source, Source::Buffer(Default::default()));
+        let ((taken, fed), read) = tokio::join!(
+            transcode::feed(stdin, taken),
+            stderr.read_to_end(&mut output)
+        );
+        *source = taken;
+        source.rewind()?;
+        fed?;
+        read?;
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(Error::Ffmpeg(status));
+        }
+
+        parse_ebur128(&String::from_utf8_lossy(&output))
+            .map(Some)
+            .ok_or(Error::Output)
+    }
+
+    /// How much to turn the song up by, in dB, to reach the target loudness.  `None` if the song
+    /// has no tags and can't be measured.
+    pub async fn gain(&self, song: &SongMetadata, source: &mut Source) -> Option<f64> {
+        let Gain { gain, peak } = match self.tagged_gain(source) {
+            Ok(Some(gain)) => gain,
+            Ok(None) => self.measured_gain(song, source).await?,
+            Err(e) => {
+                log::warn!(
+                    "Error reading loudness tags of {} - {}: {}",
+                    song.title,
+                    song.artist,
+                    e
+                );
+                self.measured_gain(song, source).await?
+            }
+        };
+
+        // turned up no further than the loudest peak can go without clipping
+        Some(peak.map_or(gain, |peak| gain.min(-peak)))
+    }
+
+    async fn measured_gain(&self, song: &SongMetadata, source: &mut Source) -> Option<Gain> {
+        match self.measure(source).await {
+            Ok(measured) => measured.map(|(loudness, peak)| {
+                log::debug!(
+                    "{} - {} measured at {:.1} LUFS",
+                    song.title,
+                    song.artist,
+                    loudness
+                );
+                Gain {
+                    gain: self.config.target - loudness,
+                    peak,
+                }
+            }),
+            Err(e) => {
+                log::error!(
+                    "Error measuring loudness of {} - {}: {}",
+                    song.title,
+                    song.artist,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        assert_eq!(parse_replaygain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_replaygain(" +1.20dB"), Some(1.2));
+        assert_eq!(parse_replaygain("2"), Some(2.));
+        assert_eq!(parse_replaygain("loud"), None);
+        assert_eq!(parse_r128("-512"), Some(-2.));
+        assert_eq!(parse_r128("1.5"), None);
+        assert_eq!(parse_peak("1"), Some(0.));
+        assert_eq!(parse_peak("0.5").map(|peak| peak.round()), Some(-6.));
+        assert_eq!(parse_peak("0"), None);
+    }
+
+    #[test]
+    fn ebur128() {
+        let output = "\
+[Parsed_ebur128_0 @ 0x5581] t: 2.9     TARGET:-23 LUFS    M: -17.2 S:-120.7     I: -17.0 LUFS
+[Parsed_ebur128_0 @ 0x5581] Summary:
+
+  Integrated loudness:
+    I:         -16.9 LUFS
+    Threshold: -27.2 LUFS
+
+  Loudness range:
+    LRA:         0.0 LU
+";
+        assert_eq!(parse_ebur128(output), Some((-16.9, None)));
+        let peak = format!("{output}\n  True peak:\n    Peak:        -0.4 dBFS\n");
+        assert_eq!(parse_ebur128(&peak), Some((-16.9, Some(-0.4))));
+        assert_eq!(parse_ebur128("I: -16.9 LUFS"), None);
+        assert_eq!(parse_ebur128("Summary:\n    I: -inf LUFS\n"), None);
+    }
+
+    #[tokio::test]
+    async fn tagged() {
+        // ID3v2.4 with REPLAYGAIN_TRACK_GAIN in a TXXX frame
+        let mut frame = vec![3];
+        frame.extend(b"replaygain_track_gain\0-6.00 dB");
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        data.push(10 + frame.len() as u8);
+        data.extend(b"TXXX\0\0\0");
+        data.push(frame.len() as u8);
+        data.extend([0, 0]);
+        data.extend(frame);
+        data.extend([0xFF, 0xFB, 0x90, 0x00]);
+
+        let normalizer = Normalizer {
+            config: config::Loudness {
+                target: -14.,
+                ffmpeg: None,
+            },
+        };
+        let song = SongMetadata {
+            title: "title".into(),
+            artist: "artist".into(),
+            youtube_url: None,
+            ..Default::default()
+        };
+
+        let mut source = Source::Buffer(io::Cursor::new(data));
+        assert_eq!(normalizer.gain(&song, &mut source).await, Some(-2.));
+        assert_eq!(source.stream_position().unwrap(), 0);
+
+        let mut source = Source::Buffer(io::Cursor::new(vec![0xFF, 0xFB, 0x90, 0x00]));
+        assert_eq!(normalizer.gain(&song, &mut source).await, None);
+    }
+
+    #[tokio::test]
+    async fn vorbis_comments() {
+        // a FLAC file with a quiet song tagged to be turned up past its peak
+        let comments: &[&str] = &[
+            "TITLE=quiet",
+            "REPLAYGAIN_TRACK_GAIN=+9.00 dB",
+            "replaygain_track_peak=0.5",
+        ];
+        let mut block = 6u32.to_le_bytes().to_vec();
+        block.extend(b"vendor");
+        block.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        let mut data = b"fLaC".to_vec();
+        data.extend([0, 0, 0, 34]);
+        data.extend([0; 34]);
+        data.extend([0x84, 0, 0, block.len() as u8]);
+        data.extend(block);
+
+        let normalizer = Normalizer {
+            config: config::Loudness {
+                target: -18.,
+                ffmpeg: None,
+            },
+        };
+        let song = SongMetadata::default();
+
+        let mut source = Source::Buffer(io::Cursor::new(data));
+        let gain = normalizer.gain(&song, &mut source).await.unwrap();
+        assert!((gain - 6.02).abs() < 0.01, "{gain}");
+        assert_eq!(source.stream_position().unwrap(), 0);
+    }
+}
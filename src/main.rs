@@ -1,70 +1,266 @@
-use std::{collections::VecDeque, env, sync::Arc};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
-use futures::StreamExt;
-use getter::Getter;
-use playlist::lastfm;
+use config::Config;
+use playlist::shuffle::Shuffler;
 use runner::{Current, Runner};
-use song::Song;
 use tokio::sync::mpsc;
 
+mod config;
+mod cues;
 mod getter;
+mod history;
+mod loudness;
 mod output;
 mod playlist;
 mod runner;
+mod scheduler;
+mod silence;
 mod song;
+mod state;
+mod transcode;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     env_logger::init();
 
-    let mut playlist = VecDeque::new();
-    // playlist::fs::glob(&mut playlist, "./media", |x| {
-    // 	x.extension() == Some("mp3".as_ref())
-    // })
-    // .await?;
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(config::Error::Help) => {
+            println!("{}", config::Error::Help);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let getters = Arc::new(getter::Chain {
+        getters: config.getters.iter().map(config::Getter::getter).collect(),
+        transcoder: config
+            .transcode
+            .clone()
+            .map(|transcode| transcode::Transcoder {
+                config: transcode,
+                format: config.format,
+            }),
+        normalizer: config
+            .loudness
+            .clone()
+            .map(|loudness| loudness::Normalizer { config: loudness }),
+        detector: config
+            .silence
+            .clone()
+            .map(|silence| silence::Detector { config: silence }),
+    });
 
-    let mut lastfm = lastfm::Client::new(env::var("SID").expect("need env var SID"));
+    let sender = lighthouse::Sender::new();
 
-    lastfm.scrape_recommendations(&mut playlist).await?;
+    let (control_sx, control_rx) = mpsc::channel(8);
 
-    let fs = Arc::new(getter::fs::Fs::new("./media", getter::fs::Ext::Mp3));
-    let ytdl = getter::youtube_dl::YoutubeDl {
-        executable: "/usr/bin/yt-dlp".into(),
-        ffmpeg: "/usr/bin/ffmpeg".into(),
-        fs: Some(Arc::clone(&fs)),
+    let playlist = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+
+    let history = config.history.clone().unwrap_or_default();
+    let mut current = Current::new(sender.subscribe());
+    current.log = history.log.map(|path| history::Log { path });
+    if let Some(config::Cues { path }) = &config.cues {
+        match cues::Store::load(path.clone()).await {
+            Ok(cues) => current.cues = cues,
+            // carrying on without them would save over them with the first cue set
+            Err(e) => {
+                eprintln!("Error loading cues from {}: {}", path.display(), e);
+                std::process::exit(2);
+            }
+        }
+    }
+    let current = Arc::new(current);
+
+    let snapshot = match &config.state {
+        Some(state) => match state::Snapshot::load(&state.path).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::error!("Error loading state from {}: {}", state.path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+    let recent = match &snapshot {
+        Some(snapshot) => snapshot
+            .history
+            .iter()
+            .rev()
+            .map(|played| played.metadata.artist.clone())
+            .take(history.artist_separation)
+            .collect(),
+        None => Vec::new(),
     };
 
-    let multi = getter::multi!(fs, ytdl);
+    let scheduler = match config.scheduler.clone() {
+        Some(rules) => {
+            let mut scheduler = scheduler::Scheduler::new(
+                rules,
+                config
+                    .playlists
+                    .iter()
+                    .map(config::Playlist::weight)
+                    .collect(),
+            );
+            // so the separation rules carry on from before the restart
+            for played in snapshot.iter().flat_map(|snapshot| &snapshot.history) {
+                scheduler.started(&played.metadata);
+            }
+            let scheduler = Arc::new(std::sync::Mutex::new(scheduler));
 
-    let sender = lighthouse::Sender::new();
+            let mut sources: Vec<_> = config
+                .playlists
+                .iter()
+                .map(|playlist| playlist.source(true))
+                .collect();
+            scheduler::fill(&mut sources, &scheduler).await;
+            log::info!(
+                "{} songs to schedule from",
+                scheduler.lock().expect("Error locking scheduler").len()
+            );
 
-    let (control_sx, control_rx) = mpsc::channel(8);
+            if let Some(secs) = config.refresh {
+                tokio::spawn(scheduler::refresh(
+                    sources,
+                    Duration::from_secs(secs),
+                    Arc::clone(&scheduler),
+                ));
+            }
 
-    let playlist = futures::stream::iter(playlist.into_iter().map(multi))
-        .buffered(3)
-        .filter_map(|x| async { x })
-        .collect::<VecDeque<_>>()
-        .await;
+            Some(scheduler)
+        }
+        None => {
+            let mut source = config.playlist_source();
+            playlist::fill(
+                source.as_mut(),
+                &playlist,
+                history.artist_separation,
+                &recent,
+            )
+            .await;
 
-    let current = Arc::new(Current::new(sender.subscribe()));
+            if let Some(secs) = config.refresh {
+                tokio::spawn(playlist::refresh(
+                    source,
+                    Duration::from_secs(secs),
+                    Arc::clone(&playlist),
+                    Arc::clone(&current),
+                    history.artist_separation,
+                ));
+            }
+
+            None
+        }
+    };
 
-    let tcp = output::tcp::Tcp::new(Arc::clone(&current));
-    tokio::spawn(tcp.run_loop());
+    let mut shuffler = Shuffler::new(config.shuffle.clone().unwrap_or_default());
+    // before restoring, so the saved queue keeps its order
+    if config.shuffle.is_some() && scheduler.is_none() {
+        shuffler.shuffle(&mut playlist.lock().expect("Error locking playlist"), None);
+    }
 
-    let playlist = Arc::new(std::sync::Mutex::new(playlist));
+    if let Some(snapshot) = snapshot {
+        log::info!(
+            "Restoring {} queued songs",
+            snapshot.queue.len() + snapshot.current.is_some() as usize
+        );
+        snapshot.restore(&current, &playlist).await;
+    }
+
+    if let Some(state) = config.state.clone() {
+        tokio::spawn(state::run_loop(
+            state,
+            Arc::clone(&current),
+            Arc::clone(&playlist),
+        ));
+    }
+
+    let hls = match &config.hls {
+        Some(hls) => {
+            let hls = Arc::new(output::hls::Hls::new(hls.clone(), config.format));
+            let rx = current.tail.read().await.clone();
+            tokio::spawn({
+                let hls = Arc::clone(&hls);
+                async move { hls.run_loop(rx).await }
+            });
+            Some(hls)
+        }
+        None => None,
+    };
 
-    let http = output::http::Server::new(
-        Arc::clone(&playlist),
-        Arc::clone(&current),
-        control_sx.clone(),
-    );
-    tokio::spawn(http.run_loop());
+    for output in &config.outputs {
+        match *output {
+            config::Output::Http { bind } => {
+                let http = output::http::Server::new(
+                    bind,
+                    Arc::clone(&playlist),
+                    Arc::clone(&current),
+                    control_sx.clone(),
+                    config.format,
+                    hls.clone(),
+                );
+                tokio::spawn(http.run_loop());
+            }
+            config::Output::Icecast {
+                bind,
+                ref name,
+                metaint,
+            } => {
+                let icecast = output::icy::Icecast::new(
+                    bind,
+                    Arc::clone(&current),
+                    config.format,
+                    name.clone(),
+                    metaint,
+                );
+                tokio::spawn(icecast.run_loop());
+            }
+            config::Output::IcecastSource {
+                ref host,
+                ref mount,
+                ref user,
+                ref password,
+                ref name,
+                ..
+            } => {
+                let relay = output::relay::Relay::new(
+                    host.clone(),
+                    mount.clone(),
+                    user,
+                    password.as_deref().expect("checked by config validation"),
+                    name.clone(),
+                    config.format,
+                    Arc::clone(&current),
+                );
+                tokio::spawn(relay.run_loop());
+            }
+            config::Output::Tcp { bind } => {
+                let tcp = output::tcp::Tcp::new(bind, Arc::clone(&current));
+                tokio::spawn(async move {
+                    if let Err(e) = tcp.run_loop().await {
+                        log::error!("Error running tcp: {:?}", e);
+                    }
+                });
+            }
+        }
+    }
 
     let runner = Runner {
         receiver: control_rx,
         sender,
         playlist,
         current,
+        getters,
+        lookahead: config.lookahead,
+        format: config.format,
+        paused: None,
+        scheduler,
+        shuffler: std::sync::Mutex::new(shuffler),
+        crossfade: config.crossfade.clone(),
     };
 
     runner.run_loop().await?;
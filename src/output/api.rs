@@ -0,0 +1,381 @@
+//! The JSON API served by the HTTP output under `/api/v1`:
+//!
+//! - `GET /api/v1/now`: the current song, with its length and how much of it has played
+//! - `GET /api/v1/now/cover`: the current song's cover, or a redirect to it
+//! - `GET /api/v1/queue`: every queued song, in order
+//! - `GET /api/v1/history`: played songs, most recent first.  `since` and `until` (Unix times)
+//!   narrow it down, and `limit` (default 100) caps how many there are.  With a history log, this
+//!   goes back to the start of the log; otherwise just the last 100 songs are kept.
+//! - `GET /api/v1/history/export?format=csv` (or `json`): the same, oldest first, as a file to
+//!   download.  Without `format`, it's whichever of the two the `Accept` header prefers.
+//! - `GET /api/v1/stats`: uptime, songs played, listeners and queue length
+//!
+//! and to edit the queue:
+//!
+//! - `POST /api/v1/queue`: queues `{"title", "artist"}` or `{"url"}`, at the end or, with
+//!   `"next": true`, to play next.  Replies `201` with the entry's `id` and `position`, or `422` if
+//!   none of the getters can get it.
+//! - `DELETE /api/v1/queue?position=N`, `?id=N` or `?track=T`: takes an entry out of the queue
+//! - `DELETE /api/v1/queue`: clears the queue
+//! - `POST /api/v1/queue/move`: moves `{"position"}`, `{"id"}` or `{"track"}` `"to"` a new
+//!   position
+//! - `POST /api/v1/queue/shuffle`: shuffles the queue, replying with the `seed` it used.  Takes
+//!   an optional body: `"mode"` (`"random"` or `"smart"`) and `"reshuffle"` (whether to shuffle
+//!   again each time the queue plays through) change the station's settings, and `"seed"`
+//!   reproduces an earlier shuffle.
+//!
+//! and to set cue points by hand, which take the place of any found from silence:
+//!
+//! - `GET /api/v1/cues`: every track's cues, by track id, or with `?track=T` just that track's
+//! - `PUT /api/v1/cues?track=T`: sets the track's `{"cue_in", "cue_out", "fade", "rating"}` (each
+//!   optional), where to start and stop playing it and where to start fading into the next song
+//!   when crossfading (at most 30 seconds before it stops).  They're used the next time it's
+//!   played.  The `rating`, from 1 to 5, weights it with `weighting = "rating"` in `[scheduler]`.
+//! - `DELETE /api/v1/cues?track=T`: clears the track's cues
+//!
+//! and to control playback:
+//!
+//! - `POST /api/v1/player/pause` and `POST /api/v1/player/resume`
+//! - `POST /api/v1/player/seek`: jumps `{"to"}` a point in the current song, or `{"by"}` some
+//!   seconds from where it is (back, if negative)
+//! - `POST /api/v1/player/restart`: plays the current song from the start
+//!
+//! Songs are referred to by two ids: `id` is the queue entry, unique even when a track is queued
+//! twice, and `track` is the track itself (see `SongMetadata::track_id`).  `track` targets the first
+//! queued entry of that track.
+//!
+//! Lengths and times are in seconds.  Errors are `{"error": "..."}` with a 4xx status.
+
+use std::{
+    io,
+    sync::{atomic::Ordering, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    playlist::{Playlist, SongMetadata, TrackId},
+    runner::{Control, Current, Played, Target},
+};
+
+#[derive(Debug, Serialize)]
+pub struct NowPlaying {
+    pub id: u64,
+    pub track: TrackId,
+    #[serde(flatten)]
+    pub metadata: SongMetadata,
+    pub duration: f64,
+    pub elapsed: f64,
+    pub remaining: f64,
+    /// How much to turn the song up by, in dB, if loudness normalization left it to players
+    pub gain: Option<f64>,
+    /// Where to get the song's cover, if it has one
+    pub cover: Option<&'static str>,
+    pub paused: bool,
+}
+
+pub const COVER: &str = "/api/v1/now/cover";
+
+#[derive(Debug, Serialize)]
+pub struct Queued {
+    pub id: u64,
+    pub track: TrackId,
+    /// 0 is the next song to play
+    pub position: usize,
+    #[serde(flatten)]
+    pub metadata: SongMetadata,
+    /// Only known once the song has been fetched
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub uptime: f64,
+    pub songs_played: u64,
+    pub listeners: usize,
+    pub queue_length: usize,
+}
+
+/// A song to queue, by its title and artist or where to get it from
+#[derive(Debug, Deserialize)]
+pub struct Enqueue {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub url: Option<String>,
+    /// Play it next, instead of after everything else
+    #[serde(default)]
+    pub next: bool,
+}
+
+impl Enqueue {
+    /// `None` if it has neither a title nor a URL
+    pub fn into_metadata(self) -> Option<SongMetadata> {
+        match (self.title, self.url) {
+            (Some(title), youtube_url) => Some(SongMetadata {
+                title,
+                artist: self.artist.unwrap_or_default(),
+                youtube_url,
+                ..Default::default()
+            }),
+            // the getter fills in the rest once it has the song
+            (None, Some(url)) => Some(SongMetadata {
+                title: url.clone(),
+                artist: self.artist.unwrap_or_default(),
+                youtube_url: Some(url),
+                ..Default::default()
+            }),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Enqueued {
+    pub id: u64,
+    pub track: TrackId,
+    pub position: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Move {
+    #[serde(flatten)]
+    pub target: Target,
+    pub to: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Shuffled {
+    pub seed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Seek {
+    To(f64),
+    By(f64),
+}
+
+impl Seek {
+    /// `None` if it's to a negative or non-finite time
+    pub fn control(self) -> Option<Control> {
+        match self {
+            Seek::To(to) => Duration::try_from_secs_f64(to).ok().map(Control::SeekTo),
+            Seek::By(by) => by.is_finite().then_some(Control::SeekBy(by)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Moved {
+    pub position: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Removed {
+    pub id: u64,
+    pub track: TrackId,
+    #[serde(flatten)]
+    pub metadata: SongMetadata,
+}
+
+/// The entry a query string like `position=2`, `id=17` or `track=<track id>` points to
+pub fn target(query: &str) -> Option<Target> {
+    let (key, value) = query.split_once('=')?;
+
+    match key {
+        "position" => value.parse().ok().map(Target::Position),
+        "id" => value.parse().ok().map(Target::Id),
+        "track" => value.parse().ok().map(Target::Track),
+        _ => None,
+    }
+}
+
+/// The track a query string like `track=<track id>` points to
+pub fn track(query: Option<&str>) -> Option<TrackId> {
+    match target(query?)? {
+        Target::Track(track) => Some(track),
+        Target::Position(_) | Target::Id(_) => None,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Error<'a> {
+    pub error: &'a str,
+}
+
+pub async fn now(current: &Current) -> Option<NowPlaying> {
+    let metadata = current.song.read().await.clone()?;
+    let timing = (*current.timing.read().await)?;
+    let elapsed = timing.elapsed();
+
+    Some(NowPlaying {
+        id: timing.entry,
+        track: metadata.track_id(),
+        cover: metadata.cover.as_ref().map(|_| COVER),
+        paused: timing.paused.is_some(),
+        metadata,
+        duration: timing.duration,
+        elapsed,
+        remaining: timing.duration - elapsed,
+        gain: *current.gain.read().await,
+    })
+}
+
+pub fn queue(playlist: &Mutex<Playlist>) -> Vec<Queued> {
+    playlist
+        .lock()
+        .expect("Error locking playlist to read")
+        .iter()
+        .enumerate()
+        .map(|(position, entry)| Queued {
+            id: entry.id,
+            track: entry.metadata.track_id(),
+            position,
+            metadata: entry.metadata.clone(),
+            duration: entry.duration(),
+        })
+        .collect()
+}
+
+/// Which part of the history to get, from a query string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+    /// Whether `format` asked for CSV, or JSON, if it was given
+    pub csv: Option<bool>,
+}
+
+impl HistoryQuery {
+    const DEFAULT_LIMIT: usize = 100;
+
+    /// `None` if any part of it is invalid
+    pub fn parse(query: Option<&str>, limit: Option<usize>) -> Option<Self> {
+        let mut parsed = Self {
+            since: None,
+            until: None,
+            limit,
+            csv: None,
+        };
+
+        for pair in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+        {
+            let (key, value) = pair.split_once('=')?;
+            match key {
+                "since" => parsed.since = Some(value.parse().ok()?),
+                "until" => parsed.until = Some(value.parse().ok()?),
+                "limit" => parsed.limit = Some(value.parse().ok()?),
+                "format" => {
+                    parsed.csv = Some(match value {
+                        "csv" => true,
+                        "json" => false,
+                        _ => return None,
+                    })
+                }
+                _ => return None,
+            }
+        }
+
+        Some(parsed)
+    }
+
+    /// For `/api/v1/history`
+    pub fn recent(query: Option<&str>) -> Option<Self> {
+        Self::parse(query, Some(Self::DEFAULT_LIMIT))
+    }
+
+    /// Whether the song started in the time asked for
+    pub fn matches(&self, played: &Played) -> bool {
+        self.since.is_none_or(|since| played.started >= since)
+            && self.until.is_none_or(|until| played.started < until)
+    }
+}
+
+/// The played songs that match `query`, most recent first
+pub async fn history(current: &Current, query: &HistoryQuery) -> io::Result<Vec<Played>> {
+    let limit = query.limit.unwrap_or(usize::MAX);
+    if let Some(log) = &current.log {
+        return log.recent(query.since, query.until, limit).await;
+    }
+
+    Ok(current
+        .history
+        .read()
+        .await
+        .iter()
+        .rev()
+        .filter(|played| query.matches(played))
+        .take(limit)
+        .cloned()
+        .collect())
+}
+
+pub fn stats(current: &Current, playlist: &Mutex<Playlist>) -> Stats {
+    Stats {
+        uptime: current.since.elapsed().as_secs_f64(),
+        songs_played: current.songs_played.load(Ordering::Relaxed),
+        listeners: current.listeners.load(Ordering::Relaxed),
+        queue_length: playlist
+            .lock()
+            .expect("Error locking playlist to read")
+            .len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        assert!(matches!(target("position=3"), Some(Target::Position(3))));
+        assert!(matches!(target("id=17"), Some(Target::Id(17))));
+        assert!(target("id=-1").is_none());
+        assert!(matches!(
+            target("track=00000000000000ff"),
+            Some(Target::Track(TrackId(255)))
+        ));
+        assert!(target("title=x").is_none());
+
+        let body: Move = serde_json::from_str(r#"{"id": 4, "to": 0}"#).unwrap();
+        assert!(matches!(body.target, Target::Id(4)));
+        assert_eq!(body.to, 0);
+
+        let body: Enqueue =
+            serde_json::from_str(r#"{"url": "https://youtu.be/x", "next": true}"#).unwrap();
+        assert!(body.next);
+        let song = body.into_metadata().unwrap();
+        assert_eq!(song.title, "https://youtu.be/x");
+        assert_eq!(song.youtube_url.as_deref(), Some("https://youtu.be/x"));
+
+        let body: Enqueue = serde_json::from_str(r#"{"artist": "Artist"}"#).unwrap();
+        assert!(body.into_metadata().is_none());
+
+        assert_eq!(
+            HistoryQuery::recent(Some("since=10&limit=5")),
+            Some(HistoryQuery {
+                since: Some(10),
+                until: None,
+                limit: Some(5),
+                csv: None,
+            })
+        );
+        assert_eq!(
+            HistoryQuery::parse(Some("format=csv"), None).map(|query| (query.csv, query.limit)),
+            Some((Some(true), None))
+        );
+        assert_eq!(HistoryQuery::recent(Some("since=yesterday")), None);
+
+        let seek = |body| serde_json::from_str::<Seek>(body).unwrap().control();
+        assert!(
+            matches!(seek(r#"{"to": 90.5}"#), Some(Control::SeekTo(d)) if d == Duration::from_secs_f64(90.5))
+        );
+        assert!(matches!(seek(r#"{"by": -10}"#), Some(Control::SeekBy(by)) if by == -10.));
+        assert!(seek(r#"{"to": -1}"#).is_none());
+    }
+}
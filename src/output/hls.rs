@@ -0,0 +1,246 @@
+//! HTTP Live Streaming: the stream is cut into segments of a few seconds, and a playlist lists the
+//! latest few of them.  Players fetch the playlist again every segment or so to find new ones.
+//!
+//! Segments are "packed audio": the MP3 or ADTS frames as they are, after an ID3 tag giving the
+//! segment's timestamp.
+//!
+//! see: https://datatracker.ietf.org/doc/html/rfc8216
+
+use std::{collections::VecDeque, fmt::Write};
+
+use hyper::body::Bytes;
+use id3::{Frame, FrameType, Id3};
+use tokio::sync::RwLock;
+
+use crate::{config, playlist::SongMetadata, song::Format};
+
+use super::Message;
+
+/// The PRIV frame owner that marks a segment's timestamp
+const TIMESTAMP_OWNER: &[u8] = b"com.apple.streaming.transportStreamTimestamp\0";
+/// Timestamps are in 90 kHz ticks, wrapping at 33 bits like MPEG-TS
+const TIMESTAMP_RATE: f64 = 90_000.;
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// Segments kept after they leave the playlist, for players that fetched it just before
+const EXTRA_SEGMENTS: usize = 2;
+
+#[derive(Debug)]
+struct Segment {
+    sequence: u64,
+    duration: f64,
+    /// What was playing when the segment started
+    title: String,
+    data: Bytes,
+}
+
+/// The segments that can still be fetched, oldest first
+#[derive(Debug, Default)]
+struct Window {
+    segments: VecDeque<Segment>,
+    /// Sequence number of the next segment
+    next: u64,
+}
+
+impl Window {
+    fn push(&mut self, duration: f64, title: String, data: Bytes, keep: usize) {
+        self.segments.push_back(Segment {
+            sequence: self.next,
+            duration,
+            title,
+            data,
+        });
+        self.next += 1;
+
+        while self.segments.len() > keep {
+            self.segments.pop_front();
+        }
+    }
+
+    /// The media playlist of the last `len` segments, which are at `<sequence>.<ext>`
+    fn playlist(&self, len: usize, target: u64, ext: &str) -> String {
+        let listed = self
+            .segments
+            .range(self.segments.len().saturating_sub(len)..);
+
+        let first = listed
+            .clone()
+            .next()
+            .map_or(self.next, |segment| segment.sequence);
+        let target = listed
+            .clone()
+            .map(|segment| segment.duration.round() as u64)
+            .fold(target, u64::max);
+
+        let mut m3u8 = String::from("#EXTM3U\r\n#EXT-X-VERSION:3\r\n");
+        write!(
+            &mut m3u8,
+            "#EXT-X-TARGETDURATION:{target}\r\n#EXT-X-MEDIA-SEQUENCE:{first}\r\n"
+        )
+        .expect("Error writing to string!");
+
+        for segment in listed {
+            write!(
+                &mut m3u8,
+                "#EXTINF:{:.3},{}\r\n{}.{ext}\r\n",
+                segment.duration, segment.title, segment.sequence,
+            )
+            .expect("Error writing to string!");
+        }
+
+        m3u8
+    }
+
+    fn get(&self, sequence: u64) -> Option<Bytes> {
+        let first = self.segments.front()?.sequence;
+        let i = sequence.checked_sub(first)?;
+        self.segments
+            .get(i as usize)
+            .map(|segment| segment.data.clone())
+    }
+}
+
+/// The ID3 tag at the start of each segment, giving the time (in seconds since the stream
+/// started) of its first sample
+fn timestamp(seconds: f64) -> Vec<u8> {
+    let ticks = (seconds * TIMESTAMP_RATE) as u64 & TIMESTAMP_MASK;
+
+    let mut data = TIMESTAMP_OWNER.to_vec();
+    data.extend(ticks.to_be_bytes());
+
+    Id3::new(vec![Frame::from(FrameType::Other {
+        tag: *b"PRIV",
+        data,
+    })])
+    .as_bytes()
+}
+
+fn title(song: &SongMetadata) -> String {
+    // one line each
+    format!("{} - {}", song.artist, song.title).replace(['\r', '\n'], " ")
+}
+
+#[derive(Debug)]
+pub struct Hls {
+    config: config::Hls,
+    format: Format,
+    window: RwLock<Window>,
+}
+
+impl Hls {
+    pub fn new(config: config::Hls, format: Format) -> Self {
+        Self {
+            config,
+            format,
+            window: Default::default(),
+        }
+    }
+
+    /// Cuts the stream into segments until it ends.
+    pub async fn run_loop(&self, mut rx: lighthouse::Receiver<Message>) {
+        let keep = self.config.window + EXTRA_SEGMENTS;
+
+        // seconds since the stream started
+        let mut elapsed = 0.;
+        let mut title = String::new();
+
+        let mut data = timestamp(elapsed);
+        let mut duration = 0.;
+        let mut segment_title = None;
+
+        while let Ok(msg) = rx.recv().await {
+            match msg.as_ref() {
+                Message::Next(song) => title = self::title(song),
+                Message::Frames(packets) => {
+                    for packet in packets {
+                        segment_title.get_or_insert_with(|| title.clone());
+                        data.extend(&packet.data);
+                        duration += packet.duration;
+                        elapsed += packet.duration;
+
+                        if duration >= self.config.segment as f64 {
+                            let data = std::mem::replace(&mut data, timestamp(elapsed));
+                            self.window.write().await.push(
+                                duration,
+                                segment_title.take().unwrap_or_default(),
+                                Bytes::from(data),
+                                keep,
+                            );
+                            duration = 0.;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn playlist(&self) -> String {
+        self.window.read().await.playlist(
+            self.config.window,
+            self.config.segment,
+            self.format.ext(),
+        )
+    }
+
+    /// The segment called `name`, if it hasn't expired
+    pub async fn segment(&self, name: &str) -> Option<Bytes> {
+        let sequence = name
+            .strip_suffix(self.format.ext())?
+            .strip_suffix('.')?
+            .parse()
+            .ok()?;
+
+        self.window.read().await.get(sequence)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut window = Window::default();
+        assert_eq!(
+            window.playlist(3, 6, "mp3"),
+            "#EXTM3U\r\n#EXT-X-VERSION:3\r\n#EXT-X-TARGETDURATION:6\r\n#EXT-X-MEDIA-SEQUENCE:0\r\n"
+        );
+
+        for i in 0..6 {
+            window.push(6.03, format!("Song {i}"), Bytes::from(vec![i]), 5);
+        }
+        window.push(7.9, "Song 6".into(), Bytes::from(vec![6]), 5);
+
+        let playlist = window.playlist(3, 6, "mp3");
+        let lines: Vec<_> = playlist.split("\r\n").collect();
+        assert_eq!(lines[2], "#EXT-X-TARGETDURATION:8");
+        assert_eq!(lines[3], "#EXT-X-MEDIA-SEQUENCE:4");
+        assert_eq!(&lines[4..6], ["#EXTINF:6.030,Song 4", "4.mp3"]);
+        assert_eq!(&lines[8..10], ["#EXTINF:7.900,Song 6", "6.mp3"]);
+
+        // kept, but not listed
+        assert_eq!(window.get(2).as_deref(), Some(&[2][..]));
+        assert_eq!(window.get(1), None);
+        assert_eq!(window.get(7), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let tag = timestamp(10.);
+        let mut source = std::io::Cursor::new(tag);
+        let id3 = Id3::read(&mut source).unwrap().expect("no tag");
+
+        let [frame] = id3.frames() else {
+            panic!("{:?}", id3.frames());
+        };
+        let FrameType::Other { tag, data } = frame.frame_type() else {
+            panic!("{frame:?}");
+        };
+        assert_eq!(tag, b"PRIV");
+        assert_eq!(data[TIMESTAMP_OWNER.len()..], 900_000u64.to_be_bytes()[..]);
+    }
+}
@@ -3,23 +3,116 @@ use std::{
     fmt::Write,
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use futures::{Stream, StreamExt};
 use hyper::{
     body::Bytes,
     header,
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Body, Request, Response,
+    Body, Method, Request, Response, StatusCode,
 };
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::oneshot;
 
 use crate::{
-    playlist::{Playlist, SongMetadata},
-    runner::{Control, ControlSender, Current},
-    song::mp3::Frame,
+    cues, history,
+    playlist::{Cover, Playlist, SongMetadata},
+    runner::{Control, ControlSender, Current, Played, QueueError, Reply},
+    song::{Format, Packet},
 };
 
-use super::Message;
+use super::{api, hls::Hls, Message};
+
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain";
+const CSV: &str = "text/csv";
+
+/// How long to wait for the runner to take an edit.  It only takes them between sending packets,
+/// not while a song loads or a crossfade mixes.
+const RUNNER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Picks the type in `offers` the request's `Accept` header likes best, or the first one if it
+/// doesn't have one.  `None` if it accepts none of them.
+fn negotiate<'a>(req: &Request<Body>, offers: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    else {
+        return offers.first().copied();
+    };
+
+    // the quality of the most specific range that matches each offer
+    let quality = |offer: &str| {
+        let (kind, _) = offer.split_once('/').unwrap_or((offer, ""));
+
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let range = params.next()?.trim();
+                let q = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.);
+
+                let specificity = if range.eq_ignore_ascii_case(offer) {
+                    2
+                } else if range.strip_suffix("/*") == Some(kind) {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+
+                Some((specificity, q))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0., |(_, q)| q)
+    };
+
+    offers
+        .iter()
+        .map(|&offer| (offer, quality(offer)))
+        .filter(|&(_, q)| q > 0.)
+        // the first of the best, since `max_by` picks the last
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(offer, _)| offer)
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> hyper::http::Result<Response<Body>> {
+    let body = serde_json::to_vec(value).expect("Error serializing response");
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, JSON)
+        .body(Body::from(body))
+}
+
+fn json_error(status: StatusCode, error: &str) -> hyper::http::Result<Response<Body>> {
+    json(status, &api::Error { error })
+}
+
+fn not_acceptable() -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::NOT_ACCEPTABLE)
+        .header(header::CONTENT_TYPE, TEXT)
+        .body(Body::from("Not acceptable"))
+}
+
+/// The runner didn't take an edit in time.  It may still make it once it gets to it.
+fn busy() -> hyper::http::Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::RETRY_AFTER, "1")
+        .header(header::CONTENT_TYPE, JSON)
+        .body(Body::from(r#"{"error":"busy, try again"}"#))
+}
 
 #[derive(Debug)]
 struct BodyStream(hyper::body::Sender);
@@ -38,16 +131,10 @@ impl BodyStream {
         }
     }
 
-    fn frame_to_bytes(frames: &[Frame]) -> Bytes {
-        frames
+    fn frame_to_bytes(packets: &[Packet]) -> Bytes {
+        packets
             .iter()
-            .flat_map(|frame| {
-                frame
-                    .header
-                    .iter()
-                    .copied()
-                    .chain(frame.data.iter().copied())
-            })
+            .flat_map(|packet| packet.data.iter().copied())
             .collect()
     }
 
@@ -78,17 +165,21 @@ struct State {
     playlist: Arc<Mutex<Playlist>>,
     current: Arc<Current>,
     control: ControlSender,
+    format: Format,
+    hls: Option<Arc<Hls>>,
 }
 
 impl State {
     async fn route(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
         match req.uri().path() {
             "/" => self.app().await,
-            "/queue" => self.queue().await,
+            path if path.starts_with("/api/") => self.api(req).await,
+            "/queue" => self.queue(&req).await,
             "/stream" => self.stream().await,
             "/skip/next" => self.skip_next().await,
             "/skip/curr" => self.skip_curr().await,
-            "/now" => self.now().await,
+            "/now" => self.now(&req).await,
+            path if path.starts_with("/hls/") => self.hls(&path["/hls/".len()..]).await,
             path => Self::not_found(path).await,
         }
     }
@@ -103,7 +194,400 @@ impl State {
             .body(Body::from(contents))
     }
 
-    async fn queue(self) -> hyper::http::Result<Response<Body>> {
+    async fn api(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        // these two aren't JSON, so they're negotiated on their own
+        if req.uri().path() == api::COVER && req.method() == Method::GET {
+            return self.cover(&req).await;
+        }
+        // it's a download, in whichever format was asked for
+        if req.uri().path() == "/api/v1/history/export" && req.method() == Method::GET {
+            return self.export(&req).await;
+        }
+
+        if negotiate(&req, &[JSON]).is_none() {
+            return not_acceptable();
+        }
+
+        let allow = match req.uri().path() {
+            "/api/v1/queue" => "GET, POST, DELETE",
+            "/api/v1/queue/move" | "/api/v1/queue/shuffle" => "POST",
+            "/api/v1/cues" => "GET, PUT, DELETE",
+            path if path.starts_with("/api/v1/player/") => "POST",
+            _ => "GET",
+        };
+        if !allow
+            .split(", ")
+            .any(|method| method == req.method().as_str())
+        {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, allow)
+                .header(header::CONTENT_TYPE, JSON)
+                .body(Body::from(r#"{"error":"method not allowed"}"#));
+        }
+
+        match (req.method(), req.uri().path()) {
+            (_, "/api/v1/now") => match api::now(&self.current).await {
+                Some(now) => json(StatusCode::OK, &now),
+                None => json_error(StatusCode::NOT_FOUND, "not playing"),
+            },
+            (&Method::GET, "/api/v1/queue") => json(StatusCode::OK, &api::queue(&self.playlist)),
+            (&Method::POST, "/api/v1/queue") => self.enqueue(req).await,
+            (&Method::DELETE, "/api/v1/queue") => self.remove(req).await,
+            (_, "/api/v1/queue/move") => self.move_entry(req).await,
+            (_, "/api/v1/queue/shuffle") => self.shuffle(req).await,
+            (_, "/api/v1/cues") => self.cues(req).await,
+            (_, "/api/v1/player/pause") => self.edit(Control::Pause).await,
+            (_, "/api/v1/player/resume") => self.edit(Control::Resume).await,
+            (_, "/api/v1/player/restart") => self.edit(Control::Restart).await,
+            (_, "/api/v1/player/seek") => self.seek(req).await,
+            (_, "/api/v1/history") => {
+                let Some(query) = api::HistoryQuery::recent(req.uri().query()) else {
+                    return json_error(StatusCode::BAD_REQUEST, "invalid query");
+                };
+                match api::history(&self.current, &query).await {
+                    Ok(history) => json(StatusCode::OK, &history),
+                    Err(e) => {
+                        log::error!("Error reading history: {}", e);
+                        json_error(StatusCode::INTERNAL_SERVER_ERROR, "error reading history")
+                    }
+                }
+            }
+            (_, "/api/v1/stats") => {
+                json(StatusCode::OK, &api::stats(&self.current, &self.playlist))
+            }
+            _ => json_error(StatusCode::NOT_FOUND, "no such endpoint"),
+        }
+    }
+
+    /// The current song's cover: embedded ones are served, and others redirected to
+    async fn cover(self, req: &Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let cover = self
+            .current
+            .song
+            .read()
+            .await
+            .as_ref()
+            .and_then(|song| song.cover.clone());
+
+        match cover {
+            Some(Cover::Embedded { mime_type, .. }) if negotiate(req, &[&mime_type]).is_none() => {
+                not_acceptable()
+            }
+            Some(Cover::Embedded { mime_type, data }) => Response::builder()
+                .header(header::CONTENT_TYPE, mime_type)
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Body::from(Bytes::copy_from_slice(&data))),
+            Some(Cover::Url(url)) => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, url)
+                .body(Body::empty()),
+            None => json_error(StatusCode::NOT_FOUND, "no cover"),
+        }
+    }
+
+    async fn export(self, req: &Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let Some(query) = api::HistoryQuery::parse(req.uri().query(), None) else {
+            return json_error(StatusCode::BAD_REQUEST, "invalid query");
+        };
+        // `format=csv` asks for CSV outright; otherwise the `Accept` header can
+        let offers: &[&str] = match query.csv {
+            Some(true) => &[CSV],
+            Some(false) => &[JSON],
+            None => &[JSON, CSV],
+        };
+        let Some(offer) = negotiate(req, offers) else {
+            return not_acceptable();
+        };
+
+        let csv = offer == CSV;
+        let (content_type, ext) = if csv {
+            ("text/csv; charset=utf-8", "csv")
+        } else {
+            (JSON, "json")
+        };
+
+        let body = match (&self.current.log, query.limit) {
+            // the whole log, which only grows, is sent as it's read
+            (Some(log), None) => match log.songs().await {
+                Ok(songs) => {
+                    let (sender, body) = Body::channel();
+                    tokio::spawn(Self::export_log(songs, query, csv, BodyStream(sender)));
+                    body
+                }
+                Err(e) => {
+                    log::error!("Error reading history: {}", e);
+                    return json_error(StatusCode::INTERNAL_SERVER_ERROR, "error reading history");
+                }
+            },
+            _ => match api::history(&self.current, &query).await {
+                Ok(mut history) => {
+                    history.reverse();
+                    Body::from(if csv {
+                        history::csv(&history).into_bytes()
+                    } else {
+                        serde_json::to_vec(&history).expect("Error serializing response")
+                    })
+                }
+                Err(e) => {
+                    log::error!("Error reading history: {}", e);
+                    return json_error(StatusCode::INTERNAL_SERVER_ERROR, "error reading history");
+                }
+            },
+        };
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"history.{ext}\""),
+            )
+            .body(body)
+    }
+
+    /// Writes the songs in the log that match `query` to `body`, as CSV or a JSON array
+    async fn export_log(
+        songs: impl Stream<Item = Played>,
+        query: api::HistoryQuery,
+        csv: bool,
+        mut body: BodyStream,
+    ) {
+        let mut songs = std::pin::pin!(songs);
+
+        let start = if csv { history::CSV_HEADER } else { "[" };
+        if !body.send(Bytes::from_static(start.as_bytes())).await {
+            return;
+        }
+
+        let mut first = true;
+        while let Some(played) = songs.next().await {
+            // the log is in the order songs started
+            if query.until.is_some_and(|until| played.started >= until) {
+                break;
+            }
+            if !query.matches(&played) {
+                continue;
+            }
+
+            let row = if csv {
+                history::csv_row(&played).into_bytes()
+            } else {
+                let mut row = if first { Vec::new() } else { vec![b','] };
+                serde_json::to_writer(&mut row, &played).expect("Error serializing response");
+                row
+            };
+            first = false;
+            if !body.send(Bytes::from(row)).await {
+                return;
+            }
+        }
+
+        if !csv {
+            body.send(Bytes::from_static(b"]")).await;
+        }
+    }
+
+    /// Reads a JSON request body
+    async fn body<T: DeserializeOwned>(req: Request<Body>) -> Result<T, String> {
+        let body = hyper::body::to_bytes(req.into_body())
+            .await
+            .map_err(|e| format!("error reading body: {e}"))?;
+
+        serde_json::from_slice(&body).map_err(|e| format!("invalid body: {e}"))
+    }
+
+    /// Sends a queue edit to the runner and waits for its reply
+    async fn ask<T>(
+        &self,
+        control: impl FnOnce(Reply<T>) -> Control,
+    ) -> Result<T, hyper::http::Result<Response<Body>>> {
+        let (sx, rx) = oneshot::channel();
+
+        let reply = tokio::time::timeout(RUNNER_TIMEOUT, async {
+            self.control.send(control(sx)).await.ok()?;
+            rx.await.ok()
+        })
+        .await;
+
+        match reply {
+            Ok(Some(Ok(value))) => Ok(value),
+            Ok(Some(Err(e @ QueueError::NotFound))) => {
+                Err(json_error(StatusCode::NOT_FOUND, &e.to_string()))
+            }
+            Ok(Some(Err(e @ QueueError::CantGet))) => {
+                Err(json_error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()))
+            }
+            Ok(None) => Err(json_error(StatusCode::SERVICE_UNAVAILABLE, "not running")),
+            Err(_) => Err(busy()),
+        }
+    }
+
+    /// Sends an edit that has no reply
+    async fn edit(self, control: Control) -> hyper::http::Result<Response<Body>> {
+        match tokio::time::timeout(RUNNER_TIMEOUT, self.control.send(control)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return json_error(StatusCode::SERVICE_UNAVAILABLE, "not running"),
+            Err(_) => return busy(),
+        }
+
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+    }
+
+    async fn shuffle(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let bytes = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return json_error(StatusCode::BAD_REQUEST, &format!("error reading body: {e}"))
+            }
+        };
+        // the body is optional
+        let change = if bytes.iter().all(u8::is_ascii_whitespace) {
+            Default::default()
+        } else {
+            match serde_json::from_slice(&bytes) {
+                Ok(change) => change,
+                Err(e) => {
+                    return json_error(StatusCode::BAD_REQUEST, &format!("invalid body: {e}"))
+                }
+            }
+        };
+
+        match self.ask(|reply| Control::Shuffle { change, reply }).await {
+            Ok(seed) => json(StatusCode::OK, &api::Shuffled { seed }),
+            Err(response) => response,
+        }
+    }
+
+    async fn cues(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let track = api::track(req.uri().query());
+        let store = &self.current.cues;
+
+        let (method, track) = match (req.method(), track) {
+            (&Method::GET, None) if req.uri().query().is_none() => {
+                return json(StatusCode::OK, &store.all())
+            }
+            (&Method::GET, Some(track)) => {
+                return match store.get(track) {
+                    Some(manual) => json(StatusCode::OK, &manual),
+                    None => json_error(StatusCode::NOT_FOUND, "no cues"),
+                }
+            }
+            (method, Some(track)) => (method.clone(), track),
+            (_, None) => return json_error(StatusCode::BAD_REQUEST, "expected track=T"),
+        };
+
+        let manual = if method == Method::PUT {
+            let manual: cues::Manual = match Self::body(req).await {
+                Ok(body) => body,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+            };
+            if !manual.valid() {
+                return json_error(StatusCode::BAD_REQUEST, "cues out of order or bad rating");
+            }
+            Some(manual)
+        } else {
+            None
+        };
+
+        match store.set(track, manual).await {
+            Ok(_) if manual.is_some() => json(StatusCode::OK, &manual),
+            Ok(Some(_)) => Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty()),
+            Ok(None) => json_error(StatusCode::NOT_FOUND, "no cues"),
+            Err(e) => {
+                log::error!("Error saving cues: {}", e);
+                json_error(StatusCode::INTERNAL_SERVER_ERROR, "error saving cues")
+            }
+        }
+    }
+
+    async fn enqueue(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let body: api::Enqueue = match Self::body(req).await {
+            Ok(body) => body,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+        };
+        let next = body.next;
+        let Some(song) = body.into_metadata() else {
+            return json_error(StatusCode::BAD_REQUEST, "needs a title or url");
+        };
+        let track = song.track_id();
+
+        match self
+            .ask(|reply| Control::Enqueue {
+                song: Box::new(song),
+                next,
+                reply,
+            })
+            .await
+        {
+            Ok((id, position)) => json(
+                StatusCode::CREATED,
+                &api::Enqueued {
+                    id,
+                    track,
+                    position,
+                },
+            ),
+            Err(res) => res,
+        }
+    }
+
+    async fn remove(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let Some(query) = req.uri().query() else {
+            return self.edit(Control::Clear).await;
+        };
+        let Some(target) = api::target(query) else {
+            return json_error(StatusCode::BAD_REQUEST, "expected position=N or id=N");
+        };
+
+        match self.ask(|reply| Control::Remove { target, reply }).await {
+            Ok((id, metadata)) => json(
+                StatusCode::OK,
+                &api::Removed {
+                    id,
+                    track: metadata.track_id(),
+                    metadata,
+                },
+            ),
+            Err(res) => res,
+        }
+    }
+
+    async fn seek(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let seek: api::Seek = match Self::body(req).await {
+            Ok(body) => body,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+        };
+
+        match seek.control() {
+            Some(control) => self.edit(control).await,
+            None => json_error(StatusCode::BAD_REQUEST, "invalid time"),
+        }
+    }
+
+    async fn move_entry(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let api::Move { target, to } = match Self::body(req).await {
+            Ok(body) => body,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+        };
+
+        match self.ask(|reply| Control::Move { target, to, reply }).await {
+            Ok(position) => json(StatusCode::OK, &api::Moved { position }),
+            Err(res) => res,
+        }
+    }
+
+    /// The queue as JSON, or the next 5 songs' titles and artists on alternating lines
+    async fn queue(self, req: &Request<Body>) -> hyper::http::Result<Response<Body>> {
+        match negotiate(req, &[TEXT, JSON]) {
+            Some(JSON) => return json(StatusCode::OK, &api::queue(&self.playlist)),
+            Some(_) => (),
+            None => return not_acceptable(),
+        }
+
         let mut writer = String::new();
         let playlist_guard = self
             .playlist
@@ -151,19 +635,36 @@ impl State {
             .body(Body::from("OK"))
     }
 
-    async fn now(self) -> hyper::http::Result<Response<Body>> {
-        let guard = self.current.song.read().await;
+    /// The current song as JSON, or its title, artist and gain (if any) on separate lines
+    async fn now(self, req: &Request<Body>) -> hyper::http::Result<Response<Body>> {
+        let format = match negotiate(req, &[TEXT, JSON]) {
+            Some(format) => format,
+            None => return not_acceptable(),
+        };
 
-        if let Some(song) = guard.as_ref() {
-            Response::builder()
-                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
-                .body(Body::from(format!("{}\n{}", song.title, song.artist)))
-        } else {
-            Response::builder()
-                .status(400)
-                .header(header::CONTENT_TYPE, "text/plain")
-                .body(Body::from("not playing"))
+        let Some(now) = api::now(&self.current).await else {
+            return match format {
+                JSON => json_error(StatusCode::NOT_FOUND, "not playing"),
+                _ => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header(header::CONTENT_TYPE, TEXT)
+                    .body(Body::from("not playing")),
+            };
+        };
+
+        if format == JSON {
+            return json(StatusCode::OK, &now);
         }
+
+        let mut body = format!("{}\n{}", now.metadata.title, now.metadata.artist);
+        // for players to apply, since it wasn't applied to the stream
+        if let Some(gain) = now.gain {
+            write!(body, "\n{gain:+.2} dB").expect("Error writing to buffer");
+        }
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(body))
     }
 
     async fn stream(self) -> hyper::http::Result<Response<Body>> {
@@ -199,7 +700,10 @@ impl State {
             (None, None) => (),
         };
 
+        let listener = self.current.listen();
         tokio::spawn(async move {
+            let _listener = listener;
+
             while let Ok(msg) = rx.recv().await {
                 let data = BodyStream::message_to_bytes(msg.as_ref());
                 drop(msg);
@@ -211,12 +715,45 @@ impl State {
         });
 
         Response::builder()
-            .header(header::CONTENT_TYPE, "application/x-mp3+info")
+            // the stream's own MIME type, and that song info is mixed in
+            .header(
+                header::CONTENT_TYPE,
+                format!("application/x-{}+info", self.format.ext()),
+            )
+            .header("X-Audio-Type", self.format.mime_type())
             .body(body)
     }
 
+    async fn hls(self, name: &str) -> hyper::http::Result<Response<Body>> {
+        let Some(hls) = self.hls else {
+            return Self::not_found(name).await;
+        };
+
+        let res = Response::builder().header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+
+        if name == "live.m3u8" {
+            return res
+                .header(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Body::from(hls.playlist().await));
+        }
+
+        match hls.segment(name).await {
+            Some(segment) => res
+                .header(header::CONTENT_TYPE, hls.format().mime_type())
+                // a segment never changes, but it expires soon after it leaves the playlist
+                .header(header::CACHE_CONTROL, "max-age=60")
+                .body(Body::from(segment)),
+            None => res
+                .status(404)
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("No such segment")),
+        }
+    }
+
     async fn not_found(_path: &str) -> hyper::http::Result<Response<Body>> {
         Response::builder()
+            .status(StatusCode::NOT_FOUND)
             .header(header::CONTENT_TYPE, "text/plain")
             .body(Body::from("Invalid path"))
     }
@@ -224,27 +761,32 @@ impl State {
 
 #[derive(Debug)]
 pub struct Server {
+    addr: SocketAddr,
     state: State,
 }
 
 impl Server {
     pub fn new(
+        addr: SocketAddr,
         playlist: Arc<Mutex<Playlist>>,
         current: Arc<Current>,
         control: ControlSender,
+        format: Format,
+        hls: Option<Arc<Hls>>,
     ) -> Self {
         Self {
+            addr,
             state: State {
                 current,
                 playlist,
                 control,
+                format,
+                hls,
             },
         }
     }
 
     pub async fn run_loop(self) {
-        let addr = SocketAddr::from(([0, 0, 0, 0], 6912));
-
         let state = self.state.clone();
         let make_service = make_service_fn(|_: &AddrStream| {
             let state = state.clone();
@@ -254,10 +796,131 @@ impl Server {
             async move { Ok::<_, Infallible>(service) }
         });
 
-        let server = hyper::Server::bind(&addr).serve(make_service);
+        let server = hyper::Server::bind(&self.addr).serve(make_service);
 
         if let Err(e) = server.await {
             log::error!("Error running http: {:?}", e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder();
+        if let Some(accept) = accept {
+            req = req.header(header::ACCEPT, accept);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let (control, _receiver) = tokio::sync::mpsc::channel(1);
+        let sender = lighthouse::Sender::new();
+        let log = history::Log {
+            path: std::env::temp_dir().join(format!("sandy-export-{}.jsonl", std::process::id())),
+        };
+        let mut current = Current::new(sender.subscribe());
+        current.log = Some(log.clone());
+        let state = State {
+            playlist: Default::default(),
+            current: Arc::new(current),
+            control,
+            format: Format::Mp3,
+            hls: None,
+        };
+        for started in [100, 200, 300] {
+            log.append(&Played {
+                id: started,
+                track: crate::playlist::TrackId(started),
+                metadata: crate::playlist::tests::song("Title", "Artist", None),
+                started,
+                duration: 100.,
+                played: 100.,
+                skipped: false,
+            })
+            .await
+            .unwrap();
+        }
+        let get = |path: &str| {
+            Request::get(path)
+                .header(header::ACCEPT, CSV)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for path in [
+            "/api/v1/history/export",
+            "/api/v1/history/export?format=csv",
+        ] {
+            let res = state.clone().route(get(path)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{path}");
+            assert!(res.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with(CSV));
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert_eq!(body.split(|&b| b == b'\n').count(), 1 + 3 + 1, "{path}");
+        }
+
+        // streamed from the log, or read back from its end with a limit
+        for (path, ids) in [
+            (
+                "/api/v1/history/export?format=json&since=200",
+                vec![200, 300],
+            ),
+            (
+                "/api/v1/history/export?format=json&until=300&limit=1",
+                vec![200],
+            ),
+        ] {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let res = state.clone().route(req).await.unwrap();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let played: Vec<Played> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                played.iter().map(|p| p.id).collect::<Vec<_>>(),
+                ids,
+                "{path}"
+            );
+        }
+
+        // everything else is only JSON
+        let res = state.clone().route(get("/api/v1/queue")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        let res = state
+            .route(get("/api/v1/history/export?format=json"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+
+        std::fs::remove_file(log.path).unwrap();
+    }
+
+    #[test]
+    fn negotiation() {
+        let table = [
+            (None, Some(TEXT)),
+            (Some("*/*"), Some(TEXT)),
+            (Some("application/json"), Some(JSON)),
+            (
+                Some("text/html, application/*;q=0.9, */*;q=0.8"),
+                Some(JSON),
+            ),
+            (Some("application/json;q=0.5, text/plain"), Some(TEXT)),
+            (Some("text/*;q=0, */*"), Some(JSON)),
+            (Some("image/png"), None),
+        ];
+
+        for (accept, expected) in table {
+            assert_eq!(
+                negotiate(&request(accept), &[TEXT, JSON]),
+                expected,
+                "{accept:?}"
+            );
+        }
+    }
+}
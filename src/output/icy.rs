@@ -0,0 +1,288 @@
+//! The Icecast/SHOUTcast stream protocol: plain audio over HTTP, with the song title mixed in every
+//! `icy-metaint` bytes for players that ask for it with `Icy-MetaData: 1`.  This is what VLC, mpv,
+//! hardware players and radio directories understand.
+//!
+//! see: https://cast.readme.io/docs/icy
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    body::Bytes,
+    header,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response,
+};
+
+use crate::{
+    playlist::{Cover, SongMetadata},
+    runner::Current,
+    song::Format,
+};
+
+use super::Message;
+
+/// Metadata blocks are a length byte, then up to 255 16-byte chunks
+const MAX_METADATA: usize = 255 * 16;
+
+/// The metadata for a song: its `StreamTitle`, and its cover's URL as `StreamUrl` if it fits
+fn stream_title(song: &SongMetadata) -> String {
+    let mut title = format!("StreamTitle='{} - {}';", song.artist, song.title);
+
+    if title.len() > MAX_METADATA {
+        let mut end = MAX_METADATA - "';".len();
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        title.truncate(end);
+        title.push_str("';");
+    }
+
+    if let Some(Cover::Url(url)) = &song.cover {
+        let stream_url = format!("StreamUrl='{url}';");
+        if title.len() + stream_url.len() <= MAX_METADATA {
+            title.push_str(&stream_url);
+        }
+    }
+
+    title
+}
+
+/// Mixes metadata blocks into the audio, one every `metaint` bytes.  Every block is empty (a
+/// single 0 byte) except the first one after the song changes.
+#[derive(Debug)]
+struct Interleaver {
+    metaint: usize,
+    /// Bytes of audio until the next metadata block
+    remaining: usize,
+    /// Sent in the next metadata block
+    pending: Option<String>,
+}
+
+impl Interleaver {
+    fn new(metaint: usize) -> Self {
+        Self {
+            metaint,
+            remaining: metaint,
+            pending: None,
+        }
+    }
+
+    fn set_song(&mut self, song: &SongMetadata) {
+        self.pending = Some(stream_title(song));
+    }
+
+    fn metadata_block(&mut self) -> Vec<u8> {
+        let Some(title) = self.pending.take() else {
+            return vec![0];
+        };
+
+        let chunks = title.len().div_ceil(16);
+        let mut block = Vec::with_capacity(1 + chunks * 16);
+        block.push(chunks as u8);
+        block.extend(title.as_bytes());
+        block.resize(1 + chunks * 16, 0);
+        block
+    }
+
+    /// Adds `data` to `out`, with metadata blocks where they're due.
+    fn push(&mut self, mut data: &[u8], out: &mut Vec<u8>) {
+        while data.len() >= self.remaining {
+            let (now, rest) = data.split_at(self.remaining);
+            out.extend(now);
+            out.extend(self.metadata_block());
+            data = rest;
+            self.remaining = self.metaint;
+        }
+
+        out.extend(data);
+        self.remaining -= data.len();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    current: Arc<Current>,
+    format: Format,
+    name: String,
+    metaint: usize,
+}
+
+impl State {
+    async fn stream(self, req: Request<Body>) -> hyper::http::Result<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Response::builder()
+                .status(405)
+                .header(header::ALLOW, "GET, HEAD")
+                .body(Body::empty());
+        }
+
+        let wants_metadata = req
+            .headers()
+            .get("Icy-MetaData")
+            .is_some_and(|value| value.as_bytes().trim_ascii() == b"1");
+
+        let mut res = Response::builder()
+            .header(header::CONTENT_TYPE, self.format.mime_type())
+            .header(header::CACHE_CONTROL, "no-cache, no-store")
+            .header("icy-name", &self.name)
+            .header("icy-pub", "0");
+        if wants_metadata {
+            res = res.header("icy-metaint", self.metaint);
+        }
+
+        if req.method() == Method::HEAD {
+            return res.body(Body::empty());
+        }
+
+        let mut rx = self.current.tail.read().await.clone();
+        let mut interleaver = wants_metadata.then(|| Interleaver::new(self.metaint));
+
+        let mut first = Vec::new();
+        if let (Some(interleaver), Some(song)) =
+            (&mut interleaver, self.current.song.read().await.as_ref())
+        {
+            interleaver.set_song(song);
+        }
+        if let Some(packets) = self.current.chunk.read().await.as_deref() {
+            for packet in packets {
+                match &mut interleaver {
+                    Some(interleaver) => interleaver.push(&packet.data, &mut first),
+                    None => first.extend(&packet.data),
+                }
+            }
+        }
+
+        let (mut sx, body) = Body::channel();
+
+        let listener = self.current.listen();
+        tokio::spawn(async move {
+            let _listener = listener;
+
+            if !first.is_empty() && sx.send_data(Bytes::from(first)).await.is_err() {
+                return;
+            }
+
+            while let Ok(msg) = rx.recv().await {
+                let mut data = Vec::new();
+                match (msg.as_ref(), &mut interleaver) {
+                    (Message::Next(song), Some(interleaver)) => interleaver.set_song(song),
+                    (Message::Next(_), None) => (),
+                    (Message::Frames(packets), Some(interleaver)) => {
+                        for packet in packets {
+                            interleaver.push(&packet.data, &mut data);
+                        }
+                    }
+                    (Message::Frames(packets), None) => {
+                        for packet in packets {
+                            data.extend(&packet.data);
+                        }
+                    }
+                }
+                drop(msg);
+
+                if data.is_empty() {
+                    continue;
+                }
+
+                if let Err(e) = sx.send_data(Bytes::from(data)).await {
+                    if !e.is_closed() {
+                        log::warn!("Error writing to icecast stream: {:?}", e);
+                    }
+                    break;
+                }
+            }
+        });
+
+        res.body(body)
+    }
+}
+
+#[derive(Debug)]
+pub struct Icecast {
+    addr: SocketAddr,
+    state: State,
+}
+
+impl Icecast {
+    pub fn new(
+        addr: SocketAddr,
+        current: Arc<Current>,
+        format: Format,
+        name: String,
+        metaint: usize,
+    ) -> Self {
+        Self {
+            addr,
+            state: State {
+                current,
+                format,
+                name,
+                metaint,
+            },
+        }
+    }
+
+    pub async fn run_loop(self) {
+        let state = self.state.clone();
+        let make_service = make_service_fn(|_: &AddrStream| {
+            let state = state.clone();
+
+            let service = service_fn(move |req| state.clone().stream(req));
+
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = hyper::Server::bind(&self.addr).serve(make_service);
+
+        if let Err(e) = server.await {
+            log::error!("Error running icecast: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    #[test]
+    fn interleave() {
+        let mut interleaver = Interleaver::new(4);
+        let mut out = Vec::new();
+
+        interleaver.set_song(&song("Title", "Artist", None));
+        interleaver.push(&[1, 2, 3], &mut out);
+        interleaver.push(&[4, 5, 6, 7, 8, 9, 10], &mut out);
+
+        let title = b"StreamTitle='Artist - Title';";
+        let mut expected = vec![1, 2, 3, 4, 2];
+        expected.extend(title);
+        expected.resize(5 + 32, 0);
+        expected.extend([5, 6, 7, 8, 0, 9, 10]);
+        assert_eq!(out, expected);
+
+        // exactly up to the next block
+        out.clear();
+        interleaver.push(&[11, 12], &mut out);
+        assert_eq!(out, [11, 12, 0]);
+    }
+
+    #[test]
+    fn long_title() {
+        let title = stream_title(&song(&"é".repeat(MAX_METADATA), "Artist", None));
+        assert!(title.len() <= MAX_METADATA);
+        assert!(title.ends_with("éé';"), "{title}");
+    }
+
+    #[test]
+    fn cover_url() {
+        let mut song = song("Title", "Artist", None);
+        song.cover = Some(Cover::Url("https://example.com/a.jpg".into()));
+        assert_eq!(
+            stream_title(&song),
+            "StreamTitle='Artist - Title';StreamUrl='https://example.com/a.jpg';"
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use std::{fmt::Write, io};
 
-use crate::song::{mp3::Mp3, Song};
+use crate::song::Song;
 
-pub fn generate_m3u8(
-    list: &[Song<Mp3>],
-    writer: impl Fn(&mut String, &Song<Mp3>) -> std::fmt::Result,
+#[allow(dead_code)]
+pub fn generate_m3u8<C>(
+    list: &[Song<C>],
+    writer: impl Fn(&mut String, &Song<C>) -> std::fmt::Result,
 ) -> io::Result<String> {
     let mut m3u8 = String::from("#EXTM3U\r\n");
     for song in list {
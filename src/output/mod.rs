@@ -1,11 +1,15 @@
-use crate::{playlist::SongMetadata, song::mp3::Frame};
+use crate::{playlist::SongMetadata, song::Packet};
 
+pub mod api;
+pub mod hls;
 pub mod http;
+pub mod icy;
 pub mod m3u;
+pub mod relay;
 pub mod tcp;
 
 #[derive(Debug)]
 pub enum Message {
-    Next(SongMetadata),
-    Frames(Vec<Frame>),
+    Next(Box<SongMetadata>),
+    Frames(Vec<Packet>),
 }
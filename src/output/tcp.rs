@@ -11,20 +11,22 @@ use super::Message;
 
 #[derive(Debug)]
 pub struct Tcp {
+    addr: SocketAddr,
     current: Arc<Current>,
 }
 
 impl Tcp {
-    pub fn new(current: Arc<Current>) -> Self {
-        Self { current }
+    pub fn new(addr: SocketAddr, current: Arc<Current>) -> Self {
+        Self { addr, current }
     }
 
     pub async fn run_loop(self) -> io::Result<()> {
         let server = {
             let current = self.current;
+            let addr = self.addr;
 
             async move {
-                let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], 3615))).await?;
+                let listener = TcpListener::bind(addr).await?;
 
                 loop {
                     match listener.accept().await {
@@ -56,10 +58,12 @@ async fn writer_loop(
     mut writer: OwnedWriteHalf,
     current: Arc<Current>,
 ) -> io::Result<()> {
+    let _listener = current.listen();
+
     let guard = current.chunk.read().await;
-    if let Some(frames) = guard.as_ref() {
-        for frame in frames {
-            frame.write(&mut writer).await?;
+    if let Some(packets) = guard.as_ref() {
+        for packet in packets {
+            packet.write(&mut writer).await?;
         }
         writer.flush().await?;
     }
@@ -72,9 +76,9 @@ async fn writer_loop(
                 // writer.write(&id3).await?;
                 // jk dont do anything - no metadata allowed in the middle of a stream
             }
-            Message::Frames(frames) => {
-                for frame in frames.iter() {
-                    frame.write(&mut writer).await?;
+            Message::Frames(packets) => {
+                for packet in packets.iter() {
+                    packet.write(&mut writer).await?;
                 }
 
                 writer.flush().await?;
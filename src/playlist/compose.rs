@@ -0,0 +1,116 @@
+//! Combinators for building one playlist out of several sources
+
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+
+use super::{Error, PlaylistSource, SongMetadata};
+
+/// Plays every song from each source, one source after another
+pub struct Chain(pub Vec<Box<dyn PlaylistSource>>);
+
+impl PlaylistSource for Chain {
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+        stream::iter(self.0.iter_mut())
+            .flat_map(|source| source.songs())
+            .boxed()
+    }
+}
+
+/// Interleaves songs from each source in proportion to its weight, so a source with weight 2 gets
+/// twice as many songs as a source with weight 1.  Once a source runs out, the rest keep going.
+pub struct Weighted(pub Vec<(u32, Box<dyn PlaylistSource>)>);
+
+impl Weighted {
+    /// Interleaves the sources evenly
+    pub fn even(sources: Vec<Box<dyn PlaylistSource>>) -> Self {
+        Self(sources.into_iter().map(|source| (1, source)).collect())
+    }
+}
+
+impl PlaylistSource for Weighted {
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+        // (weight, credit, stream)
+        let streams: Vec<_> = self
+            .0
+            .iter_mut()
+            .filter(|(weight, _)| *weight > 0)
+            .map(|(weight, source)| (*weight as i64, 0, source.songs()))
+            .collect();
+
+        stream::unfold(streams, |mut streams| async move {
+            loop {
+                // smooth weighted round-robin: everyone earns their weight, the richest source
+                // plays and pays back the total
+                let total: i64 = streams.iter().map(|(weight, ..)| weight).sum();
+                for (weight, credit, _) in &mut streams {
+                    *credit += *weight;
+                }
+
+                let (i, _) = streams
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(i, (_, credit, _))| (*credit, std::cmp::Reverse(*i)))?;
+
+                streams[i].1 -= total;
+
+                match streams[i].2.next().await {
+                    Some(song) => break Some((song, streams)),
+                    None => drop(streams.remove(i)),
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct List(&'static str, usize);
+
+    impl PlaylistSource for List {
+        fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+            let artist = self.0;
+            stream::iter((0..self.1).map(move |i| {
+                Ok(SongMetadata {
+                    title: i.to_string(),
+                    artist: artist.into(),
+                    youtube_url: None,
+                    ..Default::default()
+                })
+            }))
+            .boxed()
+        }
+    }
+
+    async fn artists(mut source: impl PlaylistSource) -> String {
+        source
+            .songs()
+            .map(|song| song.unwrap().artist)
+            .collect::<Vec<_>>()
+            .await
+            .concat()
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let chain = Chain(vec![Box::new(List("a", 2)), Box::new(List("b", 1))]);
+        assert_eq!(artists(chain).await, "aab");
+    }
+
+    #[tokio::test]
+    async fn weighted() {
+        let even = Weighted::even(vec![Box::new(List("a", 3)), Box::new(List("b", 1))]);
+        assert_eq!(artists(even).await, "abaa");
+
+        let weighted = Weighted(vec![
+            (2, Box::new(List("a", 4))),
+            (1, Box::new(List("b", 3))),
+            (0, Box::new(List("c", 3))),
+        ]);
+        assert_eq!(artists(weighted).await, "abaabab");
+    }
+}
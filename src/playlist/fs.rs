@@ -1,9 +1,57 @@
-use std::{collections::VecDeque, io, path::Path};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
-use super::SongMetadata;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use rand::seq::SliceRandom;
 
-pub async fn glob(
-    playlist: &mut VecDeque<SongMetadata>,
+use crate::getter::fs::Ext;
+
+use super::{Error, PlaylistSource, SongMetadata};
+
+/// Every playable file in an `artist/title.ext` directory tree
+#[derive(Debug, Clone)]
+pub struct Dir {
+    dir: PathBuf,
+    /// Whether the songs are shuffled afterwards anyway, in which case they're listed in order
+    shuffled: bool,
+}
+
+impl Dir {
+    pub fn new(dir: impl Into<PathBuf>, shuffled: bool) -> Self {
+        Self {
+            dir: dir.into(),
+            shuffled,
+        }
+    }
+}
+
+impl PlaylistSource for Dir {
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+        let mut songs = Vec::new();
+
+        match glob(&mut songs, &self.dir, |path| Ext::of(path).is_some()) {
+            Ok(()) => {
+                // directories list in no particular order.  Songs that are shuffled afterwards
+                // start out sorted, so a shuffle's seed always gives the same order.
+                if self.shuffled {
+                    songs.sort_by(|a, b| (&a.artist, &a.title).cmp(&(&b.artist, &b.title)));
+                } else {
+                    songs.shuffle(&mut rand::thread_rng());
+                }
+                stream::iter(songs.into_iter().map(Ok)).boxed()
+            }
+            Err(e) => stream::once(async { Err(e.into()) }).boxed(),
+        }
+    }
+}
+
+fn glob(
+    playlist: &mut Vec<SongMetadata>,
     dir: impl AsRef<Path>,
     mut ok: impl FnMut(&Path) -> bool,
 ) -> io::Result<()> {
@@ -15,8 +63,6 @@ pub async fn glob(
         ));
     }
 
-    let mut i = 0;
-
     for entry in dir.read_dir()? {
         let entry = entry?;
         if !entry.file_type()?.is_dir() {
@@ -45,16 +91,10 @@ pub async fn glob(
                     artist: artist.clone(),
                     title,
                     youtube_url: None,
+                    ..Default::default()
                 };
 
-                // "random"
-                if ((((i % 11) % 7) % 5) % 3) % 2 == 0 {
-                    playlist.push_back(song);
-                } else {
-                    playlist.push_front(song);
-                }
-
-                i += 1;
+                playlist.push(song);
             }
         }
     }
@@ -1,9 +1,9 @@
-use std::{
-    borrow::Cow,
-    collections::{HashMap, VecDeque},
-    fmt::Write,
-};
+use std::{borrow::Cow, collections::HashMap, fmt::Write};
 
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
 use hyper::{
     body::HttpBody, client::HttpConnector, header, http::uri::PathAndQuery, Body, HeaderMap,
     Request, Uri,
@@ -11,7 +11,7 @@ use hyper::{
 use hyper_tls::HttpsConnector;
 use scraper::{Html, Selector};
 
-use super::SongMetadata;
+use super::{Error, PlaylistSource, SongMetadata};
 
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
 
@@ -76,7 +76,7 @@ impl Client {
         }
     }
 
-    async fn login(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn login(&mut self) -> Result<(), Error> {
         let mut uri = Uri::from_static("https://www.last.fm/login");
 
         loop {
@@ -123,85 +123,105 @@ impl Client {
         }
     }
 
-    pub async fn scrape_recommendations(
-        &mut self,
-        playlist: &mut VecDeque<SongMetadata>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// Scrapes one page of recommendations
+    async fn scrape_page(&mut self, url: &'static str) -> Result<Vec<SongMetadata>, Error> {
         // should be consts or lazy_statics
         let song_selector = Selector::parse(".recommended-tracks-item").unwrap();
         let title_selector = Selector::parse(r#"[itemprop="name"]"#).unwrap();
         let artist_selector = Selector::parse(r#"[itemprop="byArtist"]"#).unwrap();
         let link_selector = Selector::parse(r#".desktop-playlink"#).unwrap();
 
-        self.login().await?;
+        let mut songs = Vec::new();
 
-        for url in URLS {
-            let req = Request::builder()
-                .uri(Uri::from_static(url))
-                .method("GET")
-                .header(header::USER_AGENT, USER_AGENT)
-                .header(header::COOKIE, self.cookies_header())
-                .body(Body::empty())?;
+        let req = Request::builder()
+            .uri(Uri::from_static(url))
+            .method("GET")
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::COOKIE, self.cookies_header())
+            .body(Body::empty())?;
 
-            let mut res = self.http.request(req).await?;
+        let mut res = self.http.request(req).await?;
 
-            let mut buf = Vec::with_capacity(
-                res.headers()
-                    .get(header::CONTENT_LENGTH)
-                    .and_then(|x| x.to_str().ok())
-                    .and_then(|x| x.parse().ok())
-                    .unwrap_or(1 << 16),
-            );
+        let mut buf = Vec::with_capacity(
+            res.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(1 << 16),
+        );
 
-            while let Some(chunk) = res.body_mut().data().await {
-                buf.extend(chunk?);
-            }
+        while let Some(chunk) = res.body_mut().data().await {
+            buf.extend(chunk?);
+        }
 
-            let text = String::from_utf8(buf)?;
-
-            let html = Html::parse_document(&text);
-
-            log::debug!(
-                "Scraping page {} (url {})",
-                html.select(&Selector::parse("title").unwrap())
-                    .next()
-                    .unwrap()
-                    .text()
-                    .collect::<String>(),
-                url
-            );
-
-            for song in html.select(&song_selector) {
-                let title = song
-                    .select(&title_selector)
-                    .next()
-                    .expect("No title elem!")
-                    .text()
-                    .map(&str::trim)
-                    .collect();
-
-                let artist = song
-                    .select(&artist_selector)
-                    .next()
-                    .expect("No artist elem!")
-                    .text()
-                    .map(&str::trim)
-                    .collect();
-
-                let youtube_url = song
-                    .select(&link_selector)
-                    .next()
-                    .and_then(|elem| elem.value().attr("href"))
-                    .map(|s| s.trim().to_owned());
-
-                playlist.push_back(SongMetadata {
-                    title,
-                    artist,
-                    youtube_url,
-                })
-            }
+        let text = String::from_utf8(buf)?;
+
+        let html = Html::parse_document(&text);
+
+        log::debug!(
+            "Scraping page {} (url {})",
+            html.select(&Selector::parse("title").unwrap())
+                .next()
+                .unwrap()
+                .text()
+                .collect::<String>(),
+            url
+        );
+
+        for song in html.select(&song_selector) {
+            let title = song
+                .select(&title_selector)
+                .next()
+                .expect("No title elem!")
+                .text()
+                .map(&str::trim)
+                .collect();
+
+            let artist = song
+                .select(&artist_selector)
+                .next()
+                .expect("No artist elem!")
+                .text()
+                .map(&str::trim)
+                .collect();
+
+            let youtube_url = song
+                .select(&link_selector)
+                .next()
+                .and_then(|elem| elem.value().attr("href"))
+                .map(|s| s.trim().to_owned());
+
+            songs.push(SongMetadata {
+                title,
+                artist,
+                youtube_url,
+                ..Default::default()
+            })
         }
 
-        Ok(())
+        Ok(songs)
+    }
+}
+
+impl PlaylistSource for Client {
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+        stream::unfold((self, 0), |(client, page)| async move {
+            let url = *URLS.get(page)?;
+
+            let songs = async {
+                if page == 0 {
+                    client.login().await?;
+                }
+                client.scrape_page(url).await
+            }
+            .await;
+
+            // stop after the first error
+            let next = if songs.is_ok() { page + 1 } else { URLS.len() };
+            Some((songs, (client, next)))
+        })
+        .map_ok(|songs| stream::iter(songs.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
     }
 }
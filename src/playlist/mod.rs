@@ -1,15 +1,439 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::song::{mp3::Mp3, Song};
+use futures::{stream::BoxStream, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::{config, getter, history, runner::Current, song};
+
+pub mod compose;
 pub mod fs;
 pub mod lastfm;
+pub mod shuffle;
 
-#[derive(Debug, Clone, PartialEq)]
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct SongMetadata {
     pub title: String,
     pub artist: String,
+    #[serde(default)]
     pub youtube_url: Option<String>,
+    #[serde(default)]
+    pub album: Option<String>,
+    #[serde(default)]
+    pub album_artist: Option<String>,
+    /// Track number on the album.  `track` is the track id wherever songs are listed, so this
+    /// goes by another name.
+    #[serde(default, rename = "track_number", alias = "track")]
+    pub track: Option<u32>,
+    #[serde(default)]
+    pub year: Option<u32>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    /// Served separately, since it can be big
+    #[serde(skip)]
+    pub cover: Option<Cover>,
+    /// The getter the song came from
+    #[serde(default)]
+    pub source: Option<String>,
+    /// The file the getter left the song in, if it keeps it, so it can be loaded from there again
+    /// instead of being fetched.  Kept out of the API, but saved with the state.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// Album art, embedded in the song's tags or somewhere else online
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cover {
+    Embedded { mime_type: String, data: Arc<[u8]> },
+    Url(String),
+}
+
+impl SongMetadata {
+    /// Fills in whatever isn't known yet from `other`.  The title and artist are kept, since
+    /// they're what the song was asked for by, unless they're stand-ins: a song queued by URL
+    /// alone has the URL for a title and no artist.
+    pub fn fill(&mut self, other: SongMetadata) {
+        if (self.title.is_empty() || self.youtube_url.as_ref() == Some(&self.title))
+            && !other.title.is_empty()
+        {
+            self.title = other.title;
+        }
+        if self.artist.is_empty() {
+            self.artist = other.artist;
+        }
+        self.youtube_url = self.youtube_url.take().or(other.youtube_url);
+        self.album = self.album.take().or(other.album);
+        self.album_artist = self.album_artist.take().or(other.album_artist);
+        self.track = self.track.or(other.track);
+        self.year = self.year.or(other.year);
+        self.genre = self.genre.take().or(other.genre);
+        self.cover = self.cover.take().or(other.cover);
+        self.source = self.source.take().or(other.source);
+        self.path = self.path.take().or(other.path);
+    }
+
+    /// Identifies the track itself, so the same song queued twice has the same id.  It's a hash of
+    /// where the getters find it: its URL, or else its artist and title (which is its path to the
+    /// `fs` getter), ignoring case.
+    pub fn track_id(&self) -> TrackId {
+        // FNV-1a, since it has to stay the same across builds and restarts
+        const OFFSET: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        let hash = |hash: u64, bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(PRIME))
+        };
+
+        TrackId(match &self.youtube_url {
+            Some(url) => hash(OFFSET, url.as_bytes()),
+            None => {
+                let artist = hash(OFFSET, self.artist.to_lowercase().as_bytes());
+                hash(hash(artist, &[0]), self.title.to_lowercase().as_bytes())
+            }
+        })
+    }
+}
+
+/// The content id of a track, written as 16 hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrackId(pub u64);
+
+impl fmt::Display for TrackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
 }
 
-pub type Playlist = VecDeque<Song<Mp3>>;
+impl FromStr for TrackId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Self)
+    }
+}
+
+impl Serialize for TrackId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TrackId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+pub type Playlist = VecDeque<Entry>;
+
+#[derive(Debug)]
+enum Load {
+    Pending,
+    Loaded(Box<song::Any>),
+    Failed,
+}
+
+/// A queued song.  Only the songs near the front of the queue are actually fetched and loaded;
+/// the rest are just metadata.
+#[derive(Debug)]
+pub struct Entry {
+    /// Unique among every entry queued since the station started
+    pub id: u64,
+    pub metadata: SongMetadata,
+    /// Seconds into the song to start playing from, like where it was before a restart
+    pub start: f64,
+    song: Arc<tokio::sync::Mutex<Load>>,
+}
+
+impl Entry {
+    pub fn new(metadata: SongMetadata) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            metadata,
+            start: 0.,
+            song: Arc::new(tokio::sync::Mutex::new(Load::Pending)),
+        }
+    }
+
+    /// Starts loading the song in the background, unless it is loaded or already loading.
+    pub fn prefetch(&self, getters: &Arc<getter::Chain>) {
+        let Ok(mut guard) = Arc::clone(&self.song).try_lock_owned() else {
+            // someone else is loading it
+            return;
+        };
+
+        if !matches!(*guard, Load::Pending) {
+            return;
+        }
+
+        let getters = Arc::clone(getters);
+        let metadata = self.metadata.clone();
+
+        tokio::spawn(async move {
+            log::debug!("Prefetching {} - {}", metadata.title, metadata.artist);
+            *guard = match getters.load(metadata).await {
+                Some(song) => Load::Loaded(Box::new(song)),
+                None => Load::Failed,
+            };
+        });
+    }
+
+    /// The song's length, if it has been loaded
+    pub fn duration(&self) -> Option<f64> {
+        self.with_loaded(|song| song.duration())
+    }
+
+    /// Lends the song to `f` without taking it out of the entry, if it has been loaded
+    pub fn with_loaded<T>(&self, f: impl FnOnce(&mut song::Any) -> T) -> Option<T> {
+        match &mut *self.song.try_lock().ok()? {
+            Load::Loaded(song) => Some(f(song)),
+            Load::Pending | Load::Failed => None,
+        }
+    }
+
+    /// Where the song can be loaded from without fetching it, if it has been fetched to a file
+    /// that's kept
+    pub fn path(&self) -> Option<PathBuf> {
+        self.metadata
+            .path
+            .clone()
+            .or_else(|| match &*self.song.try_lock().ok()? {
+                Load::Loaded(song) => song.metadata().path.clone(),
+                Load::Pending | Load::Failed => None,
+            })
+    }
+
+    /// Waits for the song to finish loading (or loads it now, if it was never prefetched).
+    pub async fn load(self, getters: &getter::Chain) -> Option<song::Any> {
+        let mut guard = self.song.lock().await;
+
+        match std::mem::replace(&mut *guard, Load::Failed) {
+            Load::Pending => getters.load(self.metadata).await,
+            Load::Loaded(song) => Some(*song),
+            Load::Failed => None,
+        }
+    }
+}
+
+/// Somewhere to get songs to queue from
+pub trait PlaylistSource: Send {
+    /// Lists the songs this source has right now.  Called again every time the playlist is
+    /// refreshed, so sources can return different songs each time.
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>>;
+}
+
+impl<S: PlaylistSource + ?Sized> PlaylistSource for Box<S> {
+    fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+        (**self).songs()
+    }
+}
+
+impl config::Playlist {
+    /// `shuffled` is whether its songs get shuffled after they're listed, like by a `[shuffle]`
+    /// or the scheduler.  If not, sources with no order of their own list them at random.
+    pub fn source(&self, shuffled: bool) -> Box<dyn PlaylistSource> {
+        match self {
+            Self::Lastfm { sid, .. } => Box::new(lastfm::Client::new(
+                sid.clone().expect("sid is filled in by config"),
+            )),
+            Self::Fs { dir, .. } => Box::new(fs::Dir::new(dir, shuffled)),
+        }
+    }
+}
+
+impl config::Config {
+    /// Combines all of the configured playlist sources into one, to queue in order
+    pub fn playlist_source(&self) -> Box<dyn PlaylistSource> {
+        let shuffled = self.shuffle.is_some();
+        let sources = self
+            .playlists
+            .iter()
+            .map(|playlist| playlist.source(shuffled));
+
+        match self.mix {
+            config::Mix::Chain => Box::new(compose::Chain(sources.collect())),
+            config::Mix::Interleave => Box::new(compose::Weighted::even(sources.collect())),
+            config::Mix::Weighted => Box::new(compose::Weighted(
+                self.playlists
+                    .iter()
+                    .map(|playlist| playlist.weight())
+                    .zip(sources)
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// Queues every song from `source` that isn't already queued onto the end of the playlist.
+///
+/// A song whose artist is among the last `separation` songs queued (or, before those, in `recent`,
+/// most recent first) is held back until enough other songs are queued after that artist.  Songs
+/// that never fit are queued at the end anyway.
+pub async fn fill(
+    source: &mut dyn PlaylistSource,
+    playlist: &Mutex<Playlist>,
+    separation: usize,
+    recent: &[String],
+) {
+    let mut songs = source.songs();
+    let mut held = Vec::new();
+
+    let fits = |queue: &Playlist, song: &SongMetadata| {
+        let before = queue
+            .iter()
+            .rev()
+            .map(|entry| entry.metadata.artist.as_str())
+            .chain(recent.iter().map(String::as_str));
+        history::separated(before, &song.artist, separation)
+    };
+
+    while let Some(song) = songs.next().await {
+        match song {
+            Ok(song) => {
+                let mut guard = playlist.lock().expect("Error locking playlist to add song");
+
+                let track = song.track_id();
+                if guard
+                    .iter()
+                    .map(|queued| &queued.metadata)
+                    .chain(&held)
+                    .any(|queued| queued.track_id() == track)
+                {
+                    continue;
+                }
+
+                if !fits(&guard, &song) {
+                    held.push(song);
+                    continue;
+                }
+                guard.push_back(Entry::new(song));
+
+                // each song queued might make room for one held back
+                while let Some(i) = held.iter().position(|song| fits(&guard, song)) {
+                    guard.push_back(Entry::new(held.remove(i)));
+                }
+            }
+            Err(e) => log::error!("Error reading playlist source: {:?}", e),
+        }
+    }
+
+    if !held.is_empty() {
+        log::info!(
+            "Queued {} songs too close to others by the same artist",
+            held.len()
+        );
+        playlist
+            .lock()
+            .expect("Error locking playlist to add song")
+            .extend(held.into_iter().map(Entry::new));
+    }
+}
+
+/// Refills the playlist from `source` every `interval`, forever.
+pub async fn refresh(
+    mut source: Box<dyn PlaylistSource>,
+    interval: Duration,
+    playlist: Arc<Mutex<Playlist>>,
+    current: Arc<Current>,
+    separation: usize,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        log::info!("Refreshing playlist");
+        let recent = current.recent_artists(separation).await;
+        fill(source.as_mut(), &playlist, separation, &recent).await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn song(title: &str, artist: &str, url: Option<&str>) -> SongMetadata {
+        SongMetadata {
+            title: title.into(),
+            artist: artist.into(),
+            youtube_url: url.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn track_ids() {
+        let id = song("Title", "Artist", None).track_id();
+        assert_eq!(id, song("title", "ARTIST", None).track_id());
+        assert_ne!(id, song("Artist", "Title", None).track_id());
+        // not just the two joined together
+        assert_ne!(id, song("leTitle", "Artist", None).track_id());
+        assert_ne!(
+            id,
+            song("Title", "Artist", Some("https://youtu.be/x")).track_id()
+        );
+
+        let text = id.to_string();
+        assert_eq!(text.len(), 16);
+        assert_eq!(text.parse(), Ok(id));
+        assert_eq!(serde_json::to_string(&id).unwrap(), format!("\"{text}\""));
+    }
+
+    #[test]
+    fn filled() {
+        let url = "https://youtu.be/x";
+        let details = SongMetadata {
+            album: Some("album".into()),
+            ..song("Video", "Uploader", None)
+        };
+
+        let mut queued = song("title", "artist", Some(url));
+        queued.fill(details.clone());
+        assert_eq!((&*queued.title, &*queued.artist), ("title", "artist"));
+        assert_eq!(queued.album.as_deref(), Some("album"));
+
+        // queued by URL alone
+        let mut queued = song(url, "", Some(url));
+        queued.fill(details);
+        assert_eq!((&*queued.title, &*queued.artist), ("Video", "Uploader"));
+        assert_eq!(queued.youtube_url.as_deref(), Some(url));
+    }
+
+    #[tokio::test]
+    async fn separation() {
+        struct Songs(Vec<SongMetadata>);
+
+        impl PlaylistSource for Songs {
+            fn songs(&mut self) -> BoxStream<'_, Result<SongMetadata, Error>> {
+                futures::stream::iter(self.0.clone().into_iter().map(Ok)).boxed()
+            }
+        }
+
+        let mut source = Songs(
+            [("1", "A"), ("2", "A"), ("3", "B"), ("4", "A"), ("5", "C")]
+                .into_iter()
+                .map(|(title, artist)| song(title, artist, None))
+                .collect(),
+        );
+        let playlist = Mutex::new(Playlist::new());
+
+        fill(&mut source, &playlist, 1, &["B".into()]).await;
+
+        let queue = playlist.into_inner().unwrap();
+        let titles: Vec<_> = queue.iter().map(|entry| &*entry.metadata.title).collect();
+        assert_eq!(titles, ["1", "3", "2", "5", "4"]);
+    }
+}
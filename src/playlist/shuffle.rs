@@ -0,0 +1,215 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::config::{self, ShuffleMode};
+
+use super::Playlist;
+
+/// Changes to the shuffle settings from the API.  Whatever isn't set stays as it was.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Change {
+    pub mode: Option<ShuffleMode>,
+    /// Just for this shuffle, e.g. to reproduce one from the log
+    pub seed: Option<u64>,
+    pub reshuffle: Option<bool>,
+}
+
+/// Shuffles the queue, and again each time it has played through if set to
+#[derive(Debug, Default)]
+pub struct Shuffler {
+    pub config: config::Shuffle,
+    /// Songs that went back around to the end of the queue since it was last shuffled
+    looped: usize,
+}
+
+impl Shuffler {
+    pub fn new(config: config::Shuffle) -> Self {
+        Self { config, looped: 0 }
+    }
+
+    /// Shuffles the queue with `seed`, or the configured seed, or a random one, and returns it.
+    /// The seed is logged, so that the order can be reproduced.
+    pub fn shuffle(&mut self, playlist: &mut Playlist, seed: Option<u64>) -> u64 {
+        self.shuffle_from(playlist, 0, seed)
+    }
+
+    /// Shuffles the queue past its first `from` songs
+    fn shuffle_from(&mut self, playlist: &mut Playlist, from: usize, seed: Option<u64>) -> u64 {
+        let seed = seed
+            .or(self.config.seed)
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        let from = from.min(playlist.len());
+        log::info!(
+            "Shuffling {} queued songs ({:?}) with seed {}",
+            playlist.len() - from,
+            self.config.mode,
+            seed
+        );
+
+        let entries = playlist.drain(from..).collect();
+        playlist.extend(order(entries, self.config.mode, seed, |entry| {
+            &entry.metadata.artist
+        }));
+        self.looped = 0;
+
+        seed
+    }
+
+    /// Applies changes from the API and shuffles the queue
+    pub fn change(&mut self, change: Change, playlist: &mut Playlist) -> u64 {
+        if let Some(mode) = change.mode {
+            self.config.mode = mode;
+        }
+        if let Some(reshuffle) = change.reshuffle {
+            self.config.reshuffle = reshuffle;
+        }
+
+        self.shuffle(playlist, change.seed)
+    }
+
+    /// Called after a song goes back around to the end of the queue.  Once every song has, the
+    /// queue is shuffled again, if set to, except for the first `lookahead` songs, which are
+    /// already being fetched.  The song that comes first after those isn't by the same artist as
+    /// the one before it (or the one that just played, with no lookahead), if it can help it.
+    pub fn looped(&mut self, playlist: &mut Playlist, lookahead: usize) {
+        self.looped += 1;
+        if !self.config.reshuffle || self.looped < playlist.len() {
+            return;
+        }
+
+        let kept = lookahead.min(playlist.len());
+        let before = match kept {
+            0 => playlist.back(),
+            _ => playlist.get(kept - 1),
+        };
+        let artist = before.map(|entry| entry.metadata.artist.to_lowercase());
+
+        self.shuffle_from(playlist, kept, None);
+
+        let Some(artist) = artist else { return };
+        let same = |i: usize| playlist[i].metadata.artist.to_lowercase() == artist;
+        if kept < playlist.len() && same(kept) {
+            if let Some(other) = (kept + 1..playlist.len()).find(|&i| !same(i)) {
+                playlist.swap(kept, other);
+            }
+        }
+    }
+}
+
+/// Puts `items` in an order decided by `seed`.  Smart shuffling spaces each artist's songs out
+/// evenly, starting at a random point and jittered a little, so they don't bunch up the way they
+/// can in a random order.
+fn order<T>(
+    mut items: Vec<T>,
+    mode: ShuffleMode,
+    seed: u64,
+    artist: impl Fn(&T) -> &str,
+) -> Vec<T> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    items.shuffle(&mut rng);
+
+    if mode == ShuffleMode::Random || items.is_empty() {
+        return items;
+    }
+
+    // in order of first appearance, so the same seed gives the same order
+    let mut artists: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let name = artist(item).to_lowercase();
+        match artists.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, songs)) => songs.push(i),
+            None => artists.push((name, vec![i])),
+        }
+    }
+
+    let len = items.len() as f64;
+    let mut positions = vec![0.; items.len()];
+    for (_, songs) in &artists {
+        let spacing = len / songs.len() as f64;
+        let offset = rng.gen_range(0. ..spacing);
+        for (n, &i) in songs.iter().enumerate() {
+            let jitter = rng.gen_range(-0.1..0.1) * spacing;
+            positions[i] = offset + n as f64 * spacing + jitter;
+        }
+    }
+
+    let mut items: Vec<_> = positions.into_iter().zip(items).collect();
+    items.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::playlist::{tests::song, Entry};
+
+    use super::*;
+
+    fn songs() -> Vec<(String, String)> {
+        ["a", "b", "c"]
+            .iter()
+            .flat_map(|artist| {
+                (0..4).map(move |title| (artist.to_string(), format!("{artist}{title}")))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeded() {
+        for mode in [ShuffleMode::Random, ShuffleMode::Smart] {
+            let first = order(songs(), mode, 7, |(artist, _)| artist);
+            let second = order(songs(), mode, 7, |(artist, _)| artist);
+            assert_eq!(first, second);
+            assert_ne!(first, order(songs(), mode, 8, |(artist, _)| artist));
+
+            let mut sorted = first.clone();
+            sorted.sort();
+            assert_eq!(sorted, songs());
+        }
+    }
+
+    #[test]
+    fn smart() {
+        for seed in 0..20 {
+            let order = order(songs(), ShuffleMode::Smart, seed, |(artist, _)| artist);
+            // 4 songs by each of 3 artists, 3 apart give or take the jitter
+            for window in order.windows(3) {
+                assert!(
+                    !(window[0].0 == window[1].0 && window[1].0 == window[2].0),
+                    "{order:?}"
+                );
+            }
+        }
+    }
+    #[test]
+    fn reshuffled() {
+        for seed in 0..20 {
+            let mut shuffler = Shuffler::new(config::Shuffle {
+                seed: Some(seed),
+                reshuffle: true,
+                ..Default::default()
+            });
+            let mut playlist: Playlist = songs()
+                .into_iter()
+                .map(|(artist, title)| Entry::new(song(&title, &artist, None)))
+                .collect();
+            let ids = |playlist: &Playlist| playlist.iter().map(|e| e.id).collect::<Vec<_>>();
+            let before = ids(&playlist);
+
+            for _ in 1..playlist.len() {
+                shuffler.looped(&mut playlist, 2);
+            }
+            assert_eq!(ids(&playlist), before);
+
+            // every song has gone around, so all but the first two are shuffled
+            shuffler.looped(&mut playlist, 2);
+            let after = ids(&playlist);
+            assert_eq!(after[..2], before[..2]);
+            assert_ne!(after, before);
+            assert_ne!(
+                playlist[2].metadata.artist, playlist[1].metadata.artist,
+                "seed {seed}"
+            );
+        }
+    }
+}
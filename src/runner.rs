@@ -1,42 +1,277 @@
 use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    future::Future,
     io,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
 };
 
+use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{mpsc, RwLock},
+    sync::{mpsc, oneshot, RwLock},
+    task::JoinHandle,
     time::Instant,
 };
 
 use crate::{
+    config, cues, getter, history,
     output::Message,
-    playlist::{Playlist, SongMetadata},
-    song::mp3::Frame,
+    playlist::{
+        shuffle::{self, Shuffler},
+        Entry, Playlist, SongMetadata, TrackId,
+    },
+    scheduler::Scheduler,
+    song::{self, Format, Packet},
+    transcode,
 };
 
-#[derive(Debug, Clone, Copy)]
+/// A queued entry, by where it is in the queue or by its id
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Position(usize),
+    Id(u64),
+    /// The first entry of this track
+    Track(TrackId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// No entry at that position or with that id
+    NotFound,
+    /// None of the getters can get the song
+    CantGet,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::NotFound => f.write_str("no such entry in the queue"),
+            QueueError::CantGet => f.write_str("no getter can get that song"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Where the runner sends the result of a queue edit
+pub type Reply<T> = oneshot::Sender<Result<T, QueueError>>;
+
+#[derive(Debug)]
 pub enum Control {
     SkipCurr,
+    /// Stops sending audio until `Resume`.  Listeners hear out what they already have.
+    Pause,
+    Resume,
+    /// Jumps to a point in the current song
+    SeekTo(Duration),
+    /// Jumps forward in the current song by this many seconds, or back if it's negative
+    SeekBy(f64),
+    /// Plays the current song again from the start
+    Restart,
+    /// Queues a song at the end, or to play next, and replies with its id and position
+    Enqueue {
+        song: Box<SongMetadata>,
+        next: bool,
+        reply: Reply<(u64, usize)>,
+    },
+    /// Takes an entry out of the queue, replying with its id and song
+    Remove {
+        target: Target,
+        reply: Reply<(u64, SongMetadata)>,
+    },
+    /// Moves an entry to a new position (or the end, if it's past the end)
+    Move {
+        target: Target,
+        to: usize,
+        reply: Reply<usize>,
+    },
+    /// Replies with the seed
+    Shuffle {
+        change: shuffle::Change,
+        reply: Reply<u64>,
+    },
+    Clear,
 }
 
 pub type ControlSender = mpsc::Sender<Control>;
 
+/// Why the runner stopped playing the current song where it was
+#[derive(Debug, Clone, Copy)]
+enum Interrupt {
+    Skip,
+    /// To this many seconds into the song
+    Seek(f64),
+    SeekBy(f64),
+}
+
+/// How much of a song was sent, and why it stopped
+#[derive(Debug, Clone, Copy)]
+struct Sent {
+    /// Seconds of it sent, not counting what was seeked or cut past
+    seconds: f64,
+    skipped: bool,
+    /// Where in the song the packets it didn't get to start, to pick up from
+    reached: f64,
+    /// Where it was last seeked to, to read on to `reached` from
+    seeked: f64,
+}
+
+/// Number of songs kept in the history
+const HISTORY_LEN: usize = 100;
+
+/// Seconds before a fade that the next song starts being mixed into it, so the mix is ready by
+/// the time the fade is
+const MIX_AHEAD: f64 = 10.;
+
+/// Which queue entry is playing, when it started, and how long it is
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    pub entry: u64,
+    /// When the song would have started, had it played straight through to where it is now
+    pub started: Instant,
+    pub started_at: SystemTime,
+    pub duration: f64,
+    /// When it was paused, if it is
+    pub paused: Option<Instant>,
+}
+
+impl Timing {
+    /// Seconds of the song played so far
+    pub fn elapsed(&self) -> f64 {
+        let now = self.paused.unwrap_or_else(Instant::now);
+        now.saturating_duration_since(self.started)
+            .as_secs_f64()
+            .min(self.duration)
+    }
+}
+
+/// A song that has played
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Played {
+    /// The queue entry it played from
+    pub id: u64,
+    pub track: TrackId,
+    #[serde(flatten)]
+    pub metadata: SongMetadata,
+    /// Seconds since the Unix epoch
+    pub started: u64,
+    pub duration: f64,
+    /// How many seconds of it were actually sent to listeners, however it was seeked
+    pub played: f64,
+    pub skipped: bool,
+}
+
 #[derive(Debug)]
 pub struct Current {
     pub song: RwLock<Option<SongMetadata>>,
-    pub chunk: RwLock<Option<Vec<Frame>>>,
+    pub timing: RwLock<Option<Timing>>,
+    /// How much players should turn the current song up by, in dB, if known
+    pub gain: RwLock<Option<f64>>,
+    pub chunk: RwLock<Option<Vec<Packet>>>,
     pub tail: RwLock<lighthouse::Receiver<Message>>,
+    /// The last `HISTORY_LEN` songs played, most recent last
+    pub history: RwLock<VecDeque<Played>>,
+    /// When the station started
+    pub since: Instant,
+    pub songs_played: AtomicU64,
+    pub listeners: AtomicUsize,
+    /// Where every song played is logged, if anywhere
+    pub log: Option<history::Log>,
+    /// Cue points set through the API, which take the place of the ones found from silence
+    pub cues: cues::Store,
 }
 
 impl Current {
     pub fn new(tail: lighthouse::Receiver<Message>) -> Self {
         Self {
             song: Default::default(),
+            timing: Default::default(),
+            gain: Default::default(),
             chunk: Default::default(),
             tail: RwLock::new(tail),
+            history: Default::default(),
+            since: Instant::now(),
+            songs_played: Default::default(),
+            listeners: Default::default(),
+            log: None,
+            cues: Default::default(),
         }
     }
+
+    /// The artists of the current song and the ones before it, most recent first, up to `n`
+    pub async fn recent_artists(&self, n: usize) -> Vec<String> {
+        let current = self
+            .song
+            .read()
+            .await
+            .as_ref()
+            .map(|song| song.artist.clone());
+        let history = self.history.read().await;
+
+        current
+            .into_iter()
+            .chain(
+                history
+                    .iter()
+                    .rev()
+                    .map(|played| played.metadata.artist.clone()),
+            )
+            .take(n)
+            .collect()
+    }
+
+    /// Counts a listener until the returned guard is dropped.
+    pub fn listen(self: &Arc<Self>) -> Listener {
+        self.listeners.fetch_add(1, Ordering::Relaxed);
+        Listener(Arc::clone(self))
+    }
+
+    async fn played(&self, metadata: SongMetadata, timing: Timing, sent: Sent) {
+        self.songs_played.fetch_add(1, Ordering::Relaxed);
+
+        let started = timing
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        let played = Played {
+            id: timing.entry,
+            track: metadata.track_id(),
+            metadata,
+            started,
+            duration: timing.duration,
+            played: sent.seconds,
+            skipped: sent.skipped,
+        };
+
+        if let Some(log) = &self.log {
+            if let Err(e) = log.append(&played).await {
+                log::error!("Error logging to {}: {}", log.path.display(), e);
+            }
+        }
+
+        let mut history = self.history.write().await;
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(played);
+    }
+}
+
+/// A connected listener, counted in `Current::listeners`
+#[derive(Debug)]
+pub struct Listener(Arc<Current>);
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.0.listeners.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug)]
@@ -45,23 +280,63 @@ pub struct Runner {
     pub sender: lighthouse::Sender<Message>,
     pub playlist: Arc<Mutex<Playlist>>,
     pub current: Arc<Current>,
+    pub getters: Arc<getter::Chain>,
+    /// Number of songs to load ahead of the current one
+    pub lookahead: usize,
+    /// What the station streams.  Songs in other formats can't be played.
+    pub format: Format,
+    /// When playback was paused, if it is
+    pub paused: Option<Instant>,
+    /// Picks songs to queue once the queue runs low, if set.  Otherwise songs loop.
+    pub scheduler: Option<Arc<Mutex<Scheduler>>>,
+    pub shuffler: Mutex<Shuffler>,
+    /// Fades each song into the next, if set.  Needs the getters to transcode.
+    pub crossfade: Option<config::Crossfade>,
 }
 
-async fn control_sleep(rx: &mut mpsc::Receiver<Control>, until: Instant) -> Result<(), Control> {
-    while let Some(dur) = until.checked_duration_since(Instant::now()) {
-        if let Ok(Some(msg)) = tokio::time::timeout(dur, rx.recv()).await {
-            match msg {
-                Control::SkipCurr => {
-                    // flush queue
-                    while rx.try_recv().is_ok() {}
-                    // with this, we lose the ~3 second buffer from each client
-                    // time::sleep_until(until.into()).await;
-                    return Err(msg);
-                }
+/// The start of a song, mixed with the end of the one before it
+#[derive(Debug)]
+struct Intro {
+    /// The queue entry it's the start of
+    entry: u64,
+    packets: Vec<Packet>,
+    /// Seconds of the song that the packets stand in for
+    covers: f64,
+}
+
+/// A song that's loaded and ready to play next
+#[derive(Debug)]
+struct Next {
+    id: u64,
+    start: f64,
+    song: song::Any,
+}
+
+/// The intro of the song at the front of the queue, mixing in the background
+#[derive(Debug)]
+struct Mixing(JoinHandle<Result<Intro, transcode::Error>>);
+
+impl Mixing {
+    /// Waits for the intro to be mixed, unless the song before it was `skipped`, which leaves the
+    /// next song to start from the top
+    async fn finish(self, skipped: bool) -> Option<Intro> {
+        if skipped {
+            // dropping the handle leaves the mix to finish and clean up after itself
+            return None;
+        }
+
+        match self.0.await {
+            Ok(Ok(intro)) => Some(intro),
+            Ok(Err(e)) => {
+                log::error!("Error crossfading: {}", e);
+                None
+            }
+            Err(e) => {
+                log::error!("Error crossfading: {}", e);
+                None
             }
         }
     }
-    Ok(())
 }
 
 async fn send(
@@ -70,13 +345,30 @@ async fn send(
     current: &Current,
 ) -> Result<(), lighthouse::SendError> {
     sx.send(msg)?;
-    current.tail.write().await.try_recv().expect("Error advancing current");
+    current
+        .tail
+        .write()
+        .await
+        .try_recv()
+        .expect("Error advancing current");
     Ok(())
 }
 
 impl Runner {
-    async fn send_frame(&mut self, buffer: Vec<Frame>, duration: Duration) -> Result<(), Control> {
-        let until = Instant::now() + duration;
+    /// Sends the packets, then waits for them to play.  If it's interrupted, the error has how
+    /// many seconds of them had played by then.
+    async fn send_frame(
+        &mut self,
+        buffer: Vec<Packet>,
+        duration: Duration,
+    ) -> Result<(), (Interrupt, f64)> {
+        // nothing is sent while paused
+        self.control_sleep(Instant::now())
+            .await
+            .map_err(|interrupt| (interrupt, 0.))?;
+
+        let sent = Instant::now();
+        let until = sent + duration;
 
         send(
             &mut self.sender,
@@ -86,74 +378,948 @@ impl Runner {
         .await
         .expect("Error sending");
 
-        let writer = async {
-            *self.current.chunk.write().await = Some(buffer);
+        *self.current.chunk.write().await = Some(buffer);
+
+        self.control_sleep(until)
+            .await
+            .map_err(|interrupt| (interrupt, sent.elapsed().min(duration).as_secs_f64()))
+    }
+
+    /// Waits until `until`, or for as long as playback is paused, editing the queue and pausing or
+    /// resuming as asked in the meantime.  Returns early if the current song is skipped or seeked.
+    async fn control_sleep(&mut self, mut until: Instant) -> Result<(), Interrupt> {
+        loop {
+            let msg = if self.paused.is_some() {
+                self.receiver.recv().await
+            } else {
+                match until.checked_duration_since(Instant::now()) {
+                    Some(dur) => match tokio::time::timeout(dur, self.receiver.recv()).await {
+                        Ok(msg) => msg,
+                        Err(_) => return Ok(()),
+                    },
+                    // out of time, but anything already sent is still handled
+                    None => match self.receiver.try_recv() {
+                        Ok(msg) => Some(msg),
+                        Err(_) => return Ok(()),
+                    },
+                }
+            };
+
+            let Some(msg) = msg else {
+                // nothing can resume it anymore
+                self.paused = None;
+                tokio::time::sleep_until(until).await;
+                return Ok(());
+            };
+
+            match msg {
+                Control::SkipCurr => {
+                    // anything else already sent still happens, but repeated skips don't
+                    // skip the next song too, and seeks were meant for this one
+                    while let Ok(msg) = self.receiver.try_recv() {
+                        match msg {
+                            Control::SkipCurr
+                            | Control::SeekTo(_)
+                            | Control::SeekBy(_)
+                            | Control::Restart => (),
+                            Control::Pause => self.pause().await,
+                            Control::Resume => {
+                                self.resume().await;
+                            }
+                            msg => self.edit(msg),
+                        }
+                    }
+                    // with this, we lose the ~3 second buffer from each client
+                    // time::sleep_until(until.into()).await;
+                    return Err(Interrupt::Skip);
+                }
+                Control::Pause => self.pause().await,
+                Control::Resume => {
+                    if let Some(paused) = self.resume().await {
+                        until += paused;
+                    }
+                }
+                Control::SeekTo(to) => return Err(Interrupt::Seek(to.as_secs_f64())),
+                Control::SeekBy(by) => return Err(Interrupt::SeekBy(by)),
+                Control::Restart => return Err(Interrupt::Seek(0.)),
+                msg => self.edit(msg),
+            }
+        }
+    }
+
+    async fn pause(&mut self) {
+        if self.paused.is_some() {
+            return;
+        }
+
+        let now = Instant::now();
+        self.paused = Some(now);
+        if let Some(timing) = self.current.timing.write().await.as_mut() {
+            timing.paused = Some(now);
+        }
+        log::info!("Paused");
+    }
+
+    /// Returns how long it was paused for, if it was
+    async fn resume(&mut self) -> Option<Duration> {
+        let paused = self.paused.take()?.elapsed();
+
+        if let Some(timing) = self.current.timing.write().await.as_mut() {
+            timing.paused = None;
+            timing.started += paused;
+        }
+        log::info!("Resumed after {:.0?}", paused);
+
+        Some(paused)
+    }
+
+    /// Sends the song from `position` seconds in until it ends, is skipped, or gets to `stop`,
+    /// starting over from wherever it is seeked to, and leaving out the gaps in its cues.  Until
+    /// it's seeked, `intro` stands in for the song from `position` to as far as it covers.
+    ///
+    /// The packets are read on to `position` from where the song was `seeked` to, which it jumps
+    /// to if it can.  Playing on from where it got to (`Sent::reached`, from `Sent::seeked`) sends
+    /// each packet once.
+    async fn play(
+        &mut self,
+        song: &mut song::Any,
+        mut seeked: f64,
+        mut position: f64,
+        mut intro: Option<Intro>,
+        stop: f64,
+    ) -> io::Result<Sent> {
+        const BUFFER_SIZE: usize = 128;
+
+        let duration = song.duration();
+        let gaps = song.cues().gaps.clone();
+        let mut sent = Sent {
+            seconds: 0.,
+            skipped: false,
+            reached: position,
+            seeked,
         };
 
-        let controller_sleeper = control_sleep(&mut self.receiver, until);
+        'seek: loop {
+            let intro = intro.take();
+            let from = intro.as_ref().map_or(position, |intro| intro.covers);
+
+            sent.seeked = seeked;
+            // the headers at the start (FLAC's stream info, Ogg's header pages) take no time, but
+            // nothing after them can be decoded without them
+            let (mut skipped, packets) = song.packets_from(seeked)?;
+            let mut packets = packets.peekable();
+            let headers: Vec<_> =
+                std::iter::from_fn(|| packets.next_if(|packet| packet.duration == 0.)).collect();
 
-        tokio::join!(writer, controller_sleeper).1
+            // most songs can only be read from the start, so everything else before `from` is
+            // skipped over
+            while let Some(packet) =
+                packets.next_if(|packet| skipped < from && skipped + packet.duration <= from)
+            {
+                skipped += packet.duration;
+            }
+            if intro.is_none() {
+                position = skipped;
+            }
+            // how far into the song the packets read go, not counting the intro's
+            let read = Cell::new(skipped);
+            let mut packets = headers
+                .into_iter()
+                .chain(intro.map(|intro| intro.packets).unwrap_or_default())
+                .chain(packets.inspect(|packet| read.set(read.get() + packet.duration)));
+
+            if let Some(timing) = self.current.timing.write().await.as_mut() {
+                let now = Instant::now();
+                timing.started = now
+                    .checked_sub(Duration::from_secs_f64(position))
+                    .unwrap_or(now);
+                timing.paused = self.paused.map(|_| now);
+            }
+
+            let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+            let mut buffered = 0.;
+            // seconds of gaps left out of the buffer
+            let mut dropped = 0.;
+
+            loop {
+                // the packet past `stop` is left unread, for whatever plays next to start from
+                let packet = if position + buffered + dropped < stop {
+                    packets.next()
+                } else {
+                    None
+                };
+                let done = packet.is_none();
+                sent.reached = read.get();
+                if let Some(packet) = packet {
+                    let at = position + buffered + dropped;
+                    if packet.duration > 0.
+                        && gaps.iter().any(|&(from, to)| (from..to).contains(&at))
+                    {
+                        dropped += packet.duration;
+                        continue;
+                    }
+
+                    buffered += packet.duration;
+                    buffer.push(packet);
+                    if buffer.len() < BUFFER_SIZE {
+                        continue;
+                    }
+                } else if buffer.is_empty() {
+                    return Ok(sent);
+                }
+
+                let chunk = std::mem::replace(&mut buffer, Vec::with_capacity(BUFFER_SIZE));
+                let result = self
+                    .send_frame(chunk, Duration::from_secs_f64(buffered))
+                    .await;
+                let interrupt = match result {
+                    Ok(()) => None,
+                    Err((interrupt, heard)) => {
+                        sent.seconds += heard;
+                        Some(interrupt)
+                    }
+                };
+
+                match interrupt {
+                    None => {
+                        sent.seconds += buffered;
+                        position += std::mem::take(&mut buffered);
+
+                        // the song jumps ahead past the gaps
+                        let dropped = std::mem::take(&mut dropped);
+                        if dropped > 0. {
+                            position += dropped;
+                            if let Some(timing) = self.current.timing.write().await.as_mut() {
+                                timing.started = timing
+                                    .started
+                                    .checked_sub(Duration::from_secs_f64(dropped))
+                                    .unwrap_or(timing.started);
+                            }
+                        }
+                    }
+                    Some(Interrupt::Skip) => {
+                        sent.skipped = true;
+                        return Ok(sent);
+                    }
+                    Some(Interrupt::Seek(to)) => {
+                        position = to.clamp(0., duration);
+                        seeked = position;
+                        continue 'seek;
+                    }
+                    Some(Interrupt::SeekBy(by)) => {
+                        // from what listeners are hearing, not what was last sent
+                        let heard = self
+                            .current
+                            .timing
+                            .read()
+                            .await
+                            .map_or(position, |t| t.elapsed());
+                        position = (heard + by).clamp(0., duration);
+                        seeked = position;
+                        continue 'seek;
+                    }
+                }
+
+                if done {
+                    return Ok(sent);
+                }
+            }
+        }
+    }
+
+    /// Edits the queue.  The runner takes songs off the front of the same queue, so each edit
+    /// is done under one lock.
+    fn edit(&self, control: Control) {
+        let mut playlist = self.playlist.lock().expect("Error locking playlist mutex");
+
+        let position = |playlist: &Playlist, target| match target {
+            Target::Position(i) => (i < playlist.len()).then_some(i),
+            Target::Id(id) => playlist.iter().position(|entry| entry.id == id),
+            Target::Track(track) => playlist
+                .iter()
+                .position(|entry| entry.metadata.track_id() == track),
+        };
+
+        // the reply is dropped if whoever asked stopped waiting, which is fine
+        match control {
+            Control::SkipCurr
+            | Control::Pause
+            | Control::Resume
+            | Control::SeekTo(_)
+            | Control::SeekBy(_)
+            | Control::Restart => (),
+            Control::Enqueue { song, next, reply } => {
+                if !self.getters.can_get(&song) {
+                    let _ = reply.send(Err(QueueError::CantGet));
+                    return;
+                }
+
+                log::info!("Queued {} - {}", song.title, song.artist);
+                let entry = Entry::new(*song);
+                let id = entry.id;
+                let position = if next {
+                    playlist.push_front(entry);
+                    0
+                } else {
+                    playlist.push_back(entry);
+                    playlist.len() - 1
+                };
+                let _ = reply.send(Ok((id, position)));
+            }
+            Control::Remove { target, reply } => {
+                let removed = position(&playlist, target)
+                    .and_then(|i| playlist.remove(i))
+                    .map(|entry| (entry.id, entry.metadata))
+                    .ok_or(QueueError::NotFound);
+                let _ = reply.send(removed);
+            }
+            Control::Move { target, to, reply } => {
+                let moved = position(&playlist, target)
+                    .and_then(|i| playlist.remove(i))
+                    .map(|entry| {
+                        let to = to.min(playlist.len());
+                        playlist.insert(to, entry);
+                        to
+                    })
+                    .ok_or(QueueError::NotFound);
+                let _ = reply.send(moved);
+            }
+            Control::Shuffle { change, reply } => {
+                let seed = self
+                    .shuffler
+                    .lock()
+                    .expect("Error locking shuffler")
+                    .change(change, &mut playlist);
+                let _ = reply.send(Ok(seed));
+            }
+            Control::Clear => playlist.clear(),
+        }
+
+        drop(playlist);
+        // the songs at the front may have changed
+        self.prefetch();
+    }
+
+    /// Starts loading the next `lookahead` songs, so they are ready by the time they play.
+    fn prefetch(&self) {
+        let guard = self.playlist.lock().expect("Error locking playlist mutex");
+        for entry in guard.iter().take(self.lookahead) {
+            entry.prefetch(&self.getters);
+        }
+    }
+
+    /// Tops the queue up from the scheduler, so there's always a song after the ones loaded ahead
+    fn schedule(&self) {
+        let Some(scheduler) = &self.scheduler else {
+            return;
+        };
+        let scheduler = scheduler.lock().expect("Error locking scheduler");
+        let mut playlist = self
+            .playlist
+            .lock()
+            .expect("Error locking playlist to schedule");
+
+        let hour = scheduler.hour();
+        while playlist.len() <= self.lookahead {
+            let upcoming: Vec<_> = playlist.iter().map(|entry| &entry.metadata).collect();
+            let rating = |track| {
+                self.current
+                    .cues
+                    .get(track)
+                    .and_then(|manual| manual.rating)
+            };
+            let Some(song) = scheduler.next(&upcoming, hour, rating, &mut rand::thread_rng())
+            else {
+                break;
+            };
+            playlist.push_back(Entry::new(song));
+        }
+    }
+
+    /// Loads an entry taken off the queue, unless it can't be played
+    async fn load(&self, entry: Entry) -> Option<Next> {
+        let id = entry.id;
+        let start = entry.start;
+        // failures are already logged by the getters
+        let mut song = entry.load(&self.getters).await?;
+
+        if song.format() != self.format {
+            log::warn!(
+                "Skipping {} - {}: it's {}, but the station plays {}",
+                song.metadata().title,
+                song.metadata().artist,
+                song.format(),
+                self.format,
+            );
+            return None;
+        }
+
+        self.manual_cues(&mut song);
+
+        Some(Next { id, start, song })
+    }
+
+    /// Puts the cues set through the API in place of the ones the song came with
+    fn manual_cues(&self, song: &mut song::Any) {
+        if let Some(manual) = self.current.cues.get(song.metadata().track_id()) {
+            let mut cues = song.cues().clone();
+            manual.apply(&mut cues);
+            song.set_cues(cues);
+        }
+    }
+
+    /// Starts mixing the end of `song`, from `stop` to `end`, into the start of the next song in
+    /// the queue.  `None` if the next song isn't loaded yet, or can't be mixed in, which leaves it
+    /// to play as usual once this one is over.  The next song stays queued until it starts, so it
+    /// can still be edited out of the way of its intro.
+    fn crossfade(&self, song: &mut song::Any, stop: f64, end: f64) -> Option<Mixing> {
+        let curve = self.crossfade.as_ref()?.curve;
+
+        self.schedule();
+        let playlist = self.playlist.lock().expect("Error locking playlist mutex");
+        let entry = playlist.front()?;
+        // picking up from before a restart
+        if entry.start > 0. {
+            return None;
+        }
+
+        let mix = entry.with_loaded(|next| {
+            if next.format() != self.format {
+                return None;
+            }
+            self.manual_cues(next);
+            Some(self.mix(song, stop, end, next, entry.id, curve))
+        })??;
+        drop(playlist);
+
+        match mix {
+            Ok(mix) => Some(Mixing(tokio::spawn(mix))),
+            Err(e) => {
+                log::error!("Error crossfading: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Reads the packets to mix out of both songs, and returns the mixing itself, to be run in
+    /// the background.  `next` is queued as `entry`.
+    fn mix(
+        &self,
+        song: &mut song::Any,
+        stop: f64,
+        end: f64,
+        next: &mut song::Any,
+        entry: u64,
+        curve: config::Curve,
+    ) -> Result<impl Future<Output = Result<Intro, transcode::Error>>, transcode::Error> {
+        // a few packets from before `stop`, for the decoder to get going on, since MP3 frames can
+        // need the ones before them
+        const PREROLL: f64 = 0.1;
+        // the most of the next song to mix past the fade, looking for a packet to cut back to it on
+        const LEAD_OUT: f64 = 2.;
+
+        let transcoder = self.getters.transcoder.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "crossfading needs transcoding")
+        })?;
+
+        let mut tail = Vec::new();
+        let mut preroll = 0.;
+        let mut overlap = 0.;
+        let mut position = 0.;
+        for packet in song.packets()? {
+            if position >= end {
+                break;
+            } else if position >= stop {
+                tail.extend(&packet.data);
+                overlap += packet.duration;
+            } else if position + PREROLL >= stop {
+                tail.extend(&packet.data);
+                preroll += packet.duration;
+            }
+            position += packet.duration;
+        }
+
+        // from where the next song's sound starts, through to a packet the song can pick up
+        // from after the mix, or as near as the reservoir allows
+        let cue = next.cues().start;
+        let format = next.format();
+        let mut head = Vec::new();
+        let mut heard = 0.;
+        let mut covers = 0.;
+        for packet in next.packets()? {
+            if heard >= overlap && (format.standalone(&packet.data) || heard >= overlap + LEAD_OUT)
+            {
+                break;
+            }
+            if covers + packet.duration > cue {
+                head.extend(&packet.data);
+                heard += packet.duration;
+            }
+            covers += packet.duration;
+        }
+
+        Ok(async move {
+            let mixed = transcoder
+                .crossfade(&tail, preroll, &head, overlap.min(heard), curve)
+                .await?;
+
+            // just the frames that hold the mix, not the encoder's delay before it or its
+            // padding after
+            let length = overlap.max(heard);
+            let mut kept = 0.;
+            let packets = song::Any::load(SongMetadata::default(), mixed)?
+                .packets()?
+                .skip(transcoder.primed())
+                .take_while(|packet| {
+                    let keep = kept < length - 1e-3;
+                    kept += packet.duration;
+                    keep
+                })
+                .collect();
+
+            Ok(Intro {
+                entry,
+                packets,
+                covers,
+            })
+        })
     }
 
     pub async fn run_loop(mut self) -> io::Result<()> {
-        while let Some(song) = {
-            let mut guard = self.playlist.lock().expect("Error locking playlist mutex");
-            let song = guard.pop_front();
-            drop(guard);
+        // mixed into the start of the next song, if that's still the one it was mixed for
+        let mut mixed: Option<Intro> = None;
+
+        loop {
+            self.schedule();
+            let entry = self
+                .playlist
+                .lock()
+                .expect("Error locking playlist mutex")
+                .pop_front();
+            let Some(entry) = entry else {
+                break;
+            };
+            self.prefetch();
+
+            let intro = mixed.take().filter(|intro| intro.entry == entry.id);
+            let Some(Next {
+                id,
+                start,
+                mut song,
+            }) = self.load(entry).await
+            else {
+                continue;
+            };
 
-            song
-        } {
             log::info!(
-                "Now playing: {} - {}",
-                song.metadata.title,
-                song.metadata.artist,
+                "Now playing: {} - {} ({:.0}s)",
+                song.metadata().title,
+                song.metadata().artist,
+                song.duration(),
             );
 
+            if let Some(scheduler) = &self.scheduler {
+                scheduler
+                    .lock()
+                    .expect("Error locking scheduler")
+                    .started(song.metadata());
+            }
+
             send(
                 &mut self.sender,
-                Message::Next(song.metadata.clone()),
+                Message::Next(Box::new(song.metadata().clone())),
                 &self.current,
             )
             .await
             .expect("Error sending");
-            *self.current.song.write().await = Some(song.metadata.clone());
-            const BUFFER_SIZE: usize = 128;
+            *self.current.song.write().await = Some(song.metadata().clone());
+            let timing = Timing {
+                entry: id,
+                started: Instant::now(),
+                started_at: SystemTime::now(),
+                duration: song.duration(),
+                paused: self.paused.map(|_| Instant::now()),
+            };
+            *self.current.timing.write().await = Some(timing);
+            *self.current.gain.write().await = song.gain();
+            // picking up from before a restart jumps there like a seek
+            let seeked = start;
+            // from where it was before a restart, or else where its sound starts
+            let cues = song.cues();
+            let start = if start > 0. { start } else { cues.start };
+            let end = cues.end.unwrap_or(f64::INFINITY).min(song.duration());
 
-            let mut buffer = Vec::with_capacity(BUFFER_SIZE);
-            let mut duration = 0.;
+            // stops early to fade into the next song: from its fade point if it has one, or else
+            // if there's enough of it to
+            let overlap = self
+                .crossfade
+                .as_ref()
+                .and_then(|crossfade| match cues.fade {
+                    // no longer than any other fade, however early the point is set
+                    Some(fade) if fade > start && fade < end => {
+                        Some((end - fade).min(config::Crossfade::MAX))
+                    }
+                    _ => Some(crossfade.duration).filter(|&overlap| end - start > 2. * overlap),
+                });
+            let stop = overlap.map_or(end, |overlap| end - overlap);
+
+            // the next song is mixed in while the last of this one before the fade plays, from
+            // past the intro, which is played whole
+            let from = intro.as_ref().map_or(start, |intro| intro.covers);
+            let ahead = overlap.map_or(stop, |_| (stop - MIX_AHEAD).max(from).min(stop));
+
+            let mut sent = match self.play(&mut song, seeked, start, intro, ahead).await {
+                Ok(sent) => sent,
+                Err(e) => {
+                    log::error!("Error reading song: {:?}", e);
+                    continue;
+                }
+            };
 
-            // TODO: add skipping mid-song with recv_until and Instant
+            if !sent.skipped && overlap.is_some() {
+                let mixing = self.crossfade(&mut song, stop, end);
+                // from where it got to, which is past `ahead` if it was seeked there
+                match self
+                    .play(&mut song, sent.seeked, sent.reached, None, stop)
+                    .await
+                {
+                    Ok(rest) => {
+                        sent.seconds += rest.seconds;
+                        sent.skipped = rest.skipped;
+                        sent.reached = rest.reached;
+                        sent.seeked = rest.seeked;
+                    }
+                    Err(e) => log::error!("Error reading song: {:?}", e),
+                }
+                mixed = match mixing {
+                    Some(mixing) => mixing.finish(sent.skipped).await,
+                    None => None,
+                };
+                // unless the song it was mixed for was taken off the queue or moved meanwhile
+                mixed = mixed.filter(|intro| {
+                    let playlist = self.playlist.lock().expect("Error locking playlist mutex");
+                    playlist
+                        .front()
+                        .is_some_and(|entry| entry.id == intro.entry)
+                });
 
-            for frame in song.frames() {
-                duration += frame.header.duration();
-                buffer.push(frame);
-                if buffer.len() == BUFFER_SIZE {
-                    if let Err(Control::SkipCurr) = self
-                        .send_frame(buffer, Duration::from_secs_f64(duration))
+                if !sent.skipped && mixed.is_none() {
+                    // no fade after all, so the rest of the song plays as usual
+                    match self
+                        .play(&mut song, sent.seeked, sent.reached, None, end)
                         .await
                     {
-                        buffer = Vec::with_capacity(0);
-                        break;
+                        Ok(rest) => {
+                            sent.seconds += rest.seconds;
+                            sent.skipped = rest.skipped;
+                        }
+                        Err(e) => log::error!("Error reading song: {:?}", e),
                     }
-                    buffer = Vec::with_capacity(BUFFER_SIZE);
-                    duration = 0.;
                 }
             }
 
-            if !buffer.is_empty() {
-                if let Err(Control::SkipCurr) = self
-                    .send_frame(buffer, Duration::from_secs_f64(duration))
-                    .await
-                {
-                    // song is already skipped, do nothing
-                }
+            // seeking and pausing move when it started
+            let timing = (*self.current.timing.read().await).unwrap_or(timing);
+            self.current
+                .played(song.metadata().clone(), timing, sent)
+                .await;
+
+            if self.scheduler.is_some() {
+                continue;
             }
 
-            // loop song at the end
-            self.playlist
+            // loop song at the end, unloaded so it doesn't take up memory until it comes around again
+            let mut playlist = self
+                .playlist
                 .lock()
-                .expect("Error locking playlist mutex to loop")
-                .push_back(song);
+                .expect("Error locking playlist mutex to loop");
+            playlist.push_back(Entry::new(song.into_metadata()));
+            self.shuffler
+                .lock()
+                .expect("Error locking shuffler")
+                .looped(&mut playlist, self.lookahead);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{getter::youtube_dl::YoutubeDl, playlist::tests::song};
+
+    use super::*;
+
+    fn ask<T>(runner: &Runner, control: impl FnOnce(Reply<T>) -> Control) -> Result<T, QueueError> {
+        let (sx, mut rx) = oneshot::channel();
+        runner.edit(control(sx));
+        rx.try_recv().expect("no reply")
+    }
+
+    fn runner(titles: &[&str], receiver: mpsc::Receiver<Control>) -> Runner {
+        let sender = lighthouse::Sender::new();
+
+        Runner {
+            receiver,
+            current: Arc::new(Current::new(sender.subscribe())),
+            sender,
+            playlist: Arc::new(Mutex::new(
+                titles
+                    .iter()
+                    .map(|title| Entry::new(song(title, "Artist", None)))
+                    .collect(),
+            )),
+            getters: Arc::new(getter::Chain {
+                getters: vec![getter::Any::YoutubeDl(YoutubeDl {
+                    executable: "false".into(),
+                    ffmpeg: "ffmpeg".into(),
+                    fs: None,
+                })],
+                ..Default::default()
+            }),
+            lookahead: 0,
+            format: Format::Aac,
+            paused: None,
+            scheduler: None,
+            shuffler: Default::default(),
+            crossfade: None,
+        }
+    }
+
+    /// A song made of `data`, to play straight from memory
+    fn load(data: Vec<u8>) -> io::Result<song::Any> {
+        song::Any::load(
+            song("a", "Artist", None),
+            getter::Source::Buffer(io::Cursor::new(data)),
+        )
+    }
+
+    /// 200 frames of 1024 samples at 44.1 kHz, about 4.6 seconds
+    fn frames() -> io::Result<song::Any> {
+        load(
+            (0..200)
+                .flat_map(|_| song::adts::tests::frame(100, 1))
+                .collect(),
+        )
+    }
+
+    /// A runner with nothing queued, and what it sends
+    async fn player(receiver: mpsc::Receiver<Control>) -> (Runner, lighthouse::Receiver<Message>) {
+        let runner = runner(&[], receiver);
+        let rx = runner.current.tail.read().await.clone();
+        (runner, rx)
+    }
+
+    /// The packets in the next message, which has to be some
+    async fn sent(rx: &mut lighthouse::Receiver<Message>) -> Vec<Packet> {
+        let msg = rx.recv().await.unwrap();
+        let Message::Frames(packets) = msg.as_ref() else {
+            panic!("{msg:?}");
+        };
+        packets.clone()
+    }
+
+    #[test]
+    fn edits() {
+        let (_, receiver) = mpsc::channel(1);
+        let runner = runner(&["a", "b", "c"], receiver);
+        let titles = || {
+            runner
+                .playlist
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|entry| entry.metadata.title.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let result = ask(&runner, |reply| Control::Enqueue {
+            song: Box::new(song("d", "Artist", None)),
+            next: false,
+            reply,
+        });
+        assert_eq!(result, Err(QueueError::CantGet));
+
+        let (id, position) = ask(&runner, |reply| Control::Enqueue {
+            song: Box::new(song("d", "Artist", Some("https://youtu.be/d"))),
+            next: true,
+            reply,
+        })
+        .unwrap();
+        assert_eq!(position, 0);
+        assert_eq!(titles(), ["d", "a", "b", "c"]);
+
+        let moved = ask(&runner, |reply| Control::Move {
+            target: Target::Id(id),
+            to: 10,
+            reply,
+        });
+        assert_eq!(moved, Ok(3));
+        assert_eq!(titles(), ["a", "b", "c", "d"]);
+
+        let (removed, _) = ask(&runner, |reply| Control::Remove {
+            target: Target::Position(3),
+            reply,
+        })
+        .unwrap();
+        assert_eq!(removed, id);
+        let result = ask(&runner, |reply| Control::Remove {
+            target: Target::Id(id),
+            reply,
+        });
+        assert_eq!(result, Err(QueueError::NotFound));
+
+        runner.edit(Control::Clear);
+        assert!(titles().is_empty());
+    }
+
+    #[tokio::test]
+    async fn seek() -> io::Result<()> {
+        let mut song = frames()?;
+        let (control, receiver) = mpsc::channel(1);
+        let (mut runner, mut rx) = player(receiver).await;
+
+        // before anything is sent
+        control
+            .send(Control::SeekTo(Duration::from_secs(4)))
+            .await
+            .unwrap();
+        let played = runner.play(&mut song, 0., 0., None, f64::INFINITY).await?;
+
+        let packets = sent(&mut rx).await;
+        // the frame that 4 seconds is in, and everything after it
+        assert_eq!(packets.len(), 200 - (4. * 44100. / 1024.) as usize);
+        // which is all that counts as played
+        let seconds: f64 = packets.iter().map(|packet| packet.duration).sum();
+        assert!((played.seconds - seconds).abs() < 1e-9, "{played:?}");
+        assert!(!played.skipped);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn seam() -> io::Result<()> {
+        let mut song = frames()?;
+        let frame = song.packets()?.next().unwrap().duration;
+        let (control, receiver) = mpsc::channel(1);
+        let (mut runner, mut rx) = player(receiver).await;
+
+        // stopping partway through the 100th frame, and picking up from there
+        let played = runner.play(&mut song, 0., 0., None, 99.5 * frame).await?;
+        assert_eq!(sent(&mut rx).await.len(), 100);
+        runner
+            .play(
+                &mut song,
+                played.seeked,
+                played.reached,
+                None,
+                f64::INFINITY,
+            )
+            .await?;
+        assert_eq!(sent(&mut rx).await.len(), 100);
+
+        // seeked past where it was going to stop
+        control
+            .send(Control::SeekTo(Duration::from_secs(4)))
+            .await
+            .unwrap();
+        let played = runner.play(&mut song, 0., 0., None, 50. * frame).await?;
+        runner
+            .play(
+                &mut song,
+                played.seeked,
+                played.reached,
+                None,
+                f64::INFINITY,
+            )
+            .await?;
+        let packets = sent(&mut rx).await;
+        assert_eq!(packets.len(), 200 - (4. * 44100. / 1024.) as usize);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn intro() -> io::Result<()> {
+        let mut song = frames()?;
+        let packets: Vec<_> = song.packets()?.collect();
+        let frame = packets[0].duration;
+
+        let (_control, receiver) = mpsc::channel(1);
+        let (mut runner, mut rx) = player(receiver).await;
+
+        // mixed packets in place of the first 10, then up to the 100th
+        let mixed: Vec<_> = packets[..10]
+            .iter()
+            .map(|packet| Packet {
+                data: Vec::new(),
+                duration: packet.duration,
+            })
+            .collect();
+        let intro = Intro {
+            entry: 0,
+            covers: mixed.iter().map(|packet| packet.duration).sum(),
+            packets: mixed,
+        };
+        runner
+            .play(&mut song, 0., 0., Some(intro), 99.5 * frame)
+            .await?;
+
+        let sent = sent(&mut rx).await;
+        assert_eq!(sent.len(), 100);
+        assert!(sent[..10].iter().all(|packet| packet.data.is_empty()));
+        assert_eq!(sent[10].data, packets[10].data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gaps() -> io::Result<()> {
+        let mut song = frames()?;
+        let frame = song.packets()?.next().unwrap().duration;
+        song.set_cues(song::Cues {
+            start: 10. * frame,
+            end: Some(99.5 * frame),
+            gaps: vec![(49.5 * frame, 59.5 * frame)],
+            fade: None,
+        });
+
+        let (_control, receiver) = mpsc::channel(1);
+        let (mut runner, mut rx) = player(receiver).await;
+
+        runner
+            .play(&mut song, 0., 10. * frame, None, 99.5 * frame)
+            .await?;
+
+        let sent = sent(&mut rx).await;
+        // the 10th to the 99th, but not the 50th to the 59th
+        assert_eq!(sent.len(), 90 - 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn headers() -> io::Result<()> {
+        use song::flac::tests::{file, frame};
+
+        let frames: Vec<_> = (0..20).map(|n| frame(n, Some(256), 100)).collect();
+        let mut song = load(file(20 * 256, &frames))?;
+        let header = song.packets()?.next().unwrap();
+        assert_eq!(header.duration, 0.);
+
+        let (_control, receiver) = mpsc::channel(1);
+        let (mut runner, mut rx) = player(receiver).await;
+
+        // from the start, and from the middle
+        for (from, len) in [(0., 21), (10. * 256. / 44100., 11)] {
+            runner
+                .play(&mut song, 0., from, None, f64::INFINITY)
+                .await?;
+
+            let sent = sent(&mut rx).await;
+            assert_eq!(sent.len(), len);
+            assert_eq!(sent[0].data, header.data);
+            assert_eq!(sent[1].data, frames[21 - len]);
         }
 
         Ok(())
@@ -0,0 +1,303 @@
+//! Picks what plays next from a pool of every song the playlist sources list, so the station
+//! doesn't just loop through the same order.  Separation rules keep artists and songs from coming
+//! up too close together, weights decide between the songs that are left, and dayparts limit which
+//! sources play at which times of day.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use futures::StreamExt;
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+
+use crate::{
+    config::{self, Weighting},
+    playlist::{PlaylistSource, SongMetadata, TrackId},
+};
+
+/// What songs that haven't been rated count as, out of 5
+const UNRATED: u8 = 3;
+
+#[derive(Debug)]
+struct Candidate {
+    metadata: SongMetadata,
+    track: TrackId,
+    /// Index of the playlist source it came from
+    playlist: usize,
+    /// Value of `Scheduler::plays` when it last started, if it has
+    last: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    config: config::Scheduler,
+    /// Weight of each playlist source
+    weights: Vec<u32>,
+    pool: Vec<Candidate>,
+    /// Number of songs started
+    plays: u64,
+    /// Artists and tracks of the songs that started, most recent first
+    recent: VecDeque<(String, TrackId)>,
+}
+
+impl Scheduler {
+    pub fn new(config: config::Scheduler, weights: Vec<u32>) -> Self {
+        Self {
+            config,
+            weights,
+            pool: Vec::new(),
+            plays: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Adds songs from a playlist source to the pool, unless they're already in it
+    pub fn add(&mut self, playlist: usize, songs: impl IntoIterator<Item = SongMetadata>) {
+        for metadata in songs {
+            let track = metadata.track_id();
+            if self.pool.iter().any(|candidate| candidate.track == track) {
+                continue;
+            }
+
+            self.pool.push(Candidate {
+                metadata,
+                track,
+                playlist,
+                last: None,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Records that a song started playing, whether or not it was scheduled
+    pub fn started(&mut self, song: &SongMetadata) {
+        let track = song.track_id();
+        if let Some(candidate) = self.pool.iter_mut().find(|c| c.track == track) {
+            candidate.last = Some(self.plays);
+        }
+        self.plays += 1;
+
+        self.recent.push_front((song.artist.clone(), track));
+        let keep = self
+            .config
+            .artist_separation
+            .max(self.config.title_separation);
+        self.recent.truncate(keep);
+    }
+
+    /// The current hour of the day, for dayparts
+    pub fn hour(&self) -> u32 {
+        let secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        (secs / 3600 + self.config.utc_offset as i64).rem_euclid(24) as u32
+    }
+
+    /// Picks the song to play after `upcoming` (the queue, in order), at `hour` of the day, with
+    /// songs rated from 1 to 5 by `rating`.  `None` if the pool is empty.
+    pub fn next(
+        &self,
+        upcoming: &[&SongMetadata],
+        hour: u32,
+        rating: impl Fn(TrackId) -> Option<u8>,
+        rng: &mut impl Rng,
+    ) -> Option<SongMetadata> {
+        // most recent first
+        let recent: Vec<(&str, TrackId)> = upcoming
+            .iter()
+            .rev()
+            .map(|song| (song.artist.as_str(), song.track_id()))
+            .chain(
+                self.recent
+                    .iter()
+                    .map(|(artist, track)| (&**artist, *track)),
+            )
+            .collect();
+
+        let in_daypart = |candidate: &&Candidate| match self
+            .config
+            .dayparts
+            .iter()
+            .find(|daypart| daypart.contains(hour))
+        {
+            Some(daypart) => daypart.playlists.contains(&(candidate.playlist + 1)),
+            None => true,
+        };
+        let artist_ok = |candidate: &&Candidate| {
+            !recent
+                .iter()
+                .take(self.config.artist_separation)
+                .any(|(artist, _)| artist.eq_ignore_ascii_case(&candidate.metadata.artist))
+        };
+        let title_ok = |candidate: &&Candidate| {
+            !recent
+                .iter()
+                .take(self.config.title_separation)
+                .any(|&(_, track)| track == candidate.track)
+        };
+
+        // the rules are let go of one by one, the least important first, until something fits
+        let candidates = (0..4)
+            .map(|relaxed| {
+                self.pool
+                    .iter()
+                    .filter(|c| relaxed > 2 || in_daypart(c))
+                    .filter(|c| relaxed > 1 || title_ok(c))
+                    .filter(|c| relaxed > 0 || artist_ok(c))
+                    .collect::<Vec<_>>()
+            })
+            .find(|candidates| !candidates.is_empty())?;
+
+        let weights = candidates.iter().map(|candidate| {
+            let weight = self.weights.get(candidate.playlist).copied().unwrap_or(1) as f64;
+            match self.config.weighting {
+                Weighting::Even => weight,
+                // songs that never played count as the longest ago
+                Weighting::Recency => {
+                    let since = candidate
+                        .last
+                        .map_or(self.plays + 1, |last| self.plays - last);
+                    weight * since as f64
+                }
+                Weighting::Rating => {
+                    weight * rating(candidate.track).unwrap_or(UNRATED).clamp(1, 5) as f64
+                }
+            }
+        });
+
+        let i = match WeightedIndex::new(weights) {
+            Ok(index) => index.sample(rng),
+            // every weight is 0
+            Err(_) => rng.gen_range(0..candidates.len()),
+        };
+
+        Some(candidates[i].metadata.clone())
+    }
+}
+
+/// Adds every song from each source to the pool.
+pub async fn fill(sources: &mut [Box<dyn PlaylistSource>], scheduler: &Mutex<Scheduler>) {
+    for (i, source) in sources.iter_mut().enumerate() {
+        let songs: Vec<_> = source
+            .songs()
+            .filter_map(|song| async move {
+                song.map_err(|e| log::error!("Error reading playlist source: {:?}", e))
+                    .ok()
+            })
+            .collect()
+            .await;
+
+        scheduler
+            .lock()
+            .expect("Error locking scheduler to add songs")
+            .add(i, songs);
+    }
+}
+
+/// Refills the pool from `sources` every `interval`, forever.
+pub async fn refresh(
+    mut sources: Vec<Box<dyn PlaylistSource>>,
+    interval: Duration,
+    scheduler: Arc<Mutex<Scheduler>>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        log::info!("Refreshing song pool");
+        fill(&mut sources, &scheduler).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{config::Daypart, playlist::tests::song};
+
+    use super::*;
+
+    fn scheduler(config: config::Scheduler) -> Scheduler {
+        let mut scheduler = Scheduler::new(config, vec![1, 1]);
+        scheduler.add(
+            0,
+            [
+                song("1", "A", None),
+                song("2", "A", None),
+                song("3", "B", None),
+            ],
+        );
+        scheduler.add(1, [song("4", "C", None), song("1", "A", None)]);
+        scheduler
+    }
+
+    #[test]
+    fn separation() {
+        let mut scheduler = scheduler(config::Scheduler {
+            artist_separation: 1,
+            title_separation: 2,
+            ..Default::default()
+        });
+        assert_eq!(scheduler.len(), 4);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mut played = Vec::new();
+        for _ in 0..12 {
+            let next = scheduler.next(&[], 0, |_| None, &mut rng).unwrap();
+            scheduler.started(&next);
+            played.push(next);
+        }
+
+        for pair in played.windows(2) {
+            assert_ne!(pair[0].artist, pair[1].artist, "{played:?}");
+        }
+        for window in played.windows(3) {
+            assert_ne!(window[0].title, window[2].title, "{played:?}");
+        }
+    }
+
+    #[test]
+    fn dayparts() {
+        let scheduler = scheduler(config::Scheduler {
+            artist_separation: 2,
+            dayparts: vec![Daypart {
+                from: 22,
+                to: 2,
+                playlists: vec![2],
+            }],
+            ..Default::default()
+        });
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..10 {
+            let next = scheduler.next(&[], 23, |_| None, &mut rng).unwrap();
+            assert_eq!(next.title, "4");
+        }
+
+        // separation gives way before the daypart does
+        let upcoming = song("4", "C", None);
+        let next = scheduler.next(&[&upcoming], 1, |_| None, &mut rng).unwrap();
+        assert_eq!(next.title, "4");
+    }
+
+    #[test]
+    fn ratings() {
+        let scheduler = scheduler(config::Scheduler {
+            weighting: Weighting::Rating,
+            ..Default::default()
+        });
+        let mut rng = StdRng::seed_from_u64(1);
+        let top = song("4", "C", None).track_id();
+        let rating = |track| Some(if track == top { 5 } else { 1 });
+
+        // 5 to 1 against each of the other three
+        let picked = (0..800)
+            .filter(|_| scheduler.next(&[], 0, rating, &mut rng).unwrap().title == "4")
+            .count();
+        assert!((420..580).contains(&picked), "{picked}");
+    }
+}
@@ -0,0 +1,232 @@
+//! Finds where each song's sound starts and ends, so it can be played without the silence (or
+//! quiet video intro) around it.
+//!
+//! ffmpeg's `silencedetect` filter finds the silences, which takes about as long as decoding the
+//! song, so like measuring loudness it happens when the song is fetched.
+
+use std::{
+    fmt,
+    io::{self, Seek},
+    process::{ExitStatus, Stdio},
+};
+
+use tokio::{io::AsyncReadExt, process::Command};
+
+use crate::{
+    config,
+    getter::Source,
+    playlist::SongMetadata,
+    song::{self, Cues},
+    transcode,
+};
+
+/// Silences within this many seconds of either end of a song count as being at that end
+const EDGE: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct Detector {
+    pub config: config::Silence,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ffmpeg(ExitStatus),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO Error: {e}"),
+            Error::Ffmpeg(status) => write!(f, "ffmpeg failed: {status}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Finds the silences `silencedetect` logs, e.g.
+///
+/// ```text
+/// [silencedetect @ 0x...] silence_start: 0
+/// [silencedetect @ 0x...] silence_end: 2.5 | silence_duration: 2.5
+/// [silencedetect @ 0x...] silence_start: 181.2
+/// ```
+///
+/// A silence that lasts until the end of the song may have no end.
+fn parse_silencedetect(output: &str) -> Vec<(f64, Option<f64>)> {
+    let mut silences = Vec::new();
+
+    for line in output.lines() {
+        let value = |key: &str| {
+            let value = &line[line.find(key)? + key.len()..];
+            let value = value.split('|').next()?.trim();
+            value.parse::<f64>().ok().filter(|v| v.is_finite())
+        };
+
+        if let Some(start) = value("silence_start:") {
+            silences.push((start.max(0.), None));
+        } else if let Some(end) = value("silence_end:") {
+            if let Some((_, last @ None)) = silences.last_mut() {
+                *last = Some(end);
+            }
+        }
+    }
+
+    silences
+}
+
+/// Where to start and stop a song `duration` seconds long with these silences in it.  Silences in
+/// the middle are skipped if they're longer than `max_gap`.
+fn cues(silences: &[(f64, Option<f64>)], duration: f64, max_gap: Option<f64>) -> Cues {
+    let mut cues = Cues::default();
+
+    for &(start, end) in silences {
+        let end = end.unwrap_or(duration).min(duration);
+
+        if start <= EDGE {
+            cues.start = end;
+        } else if end >= duration - EDGE {
+            cues.end = Some(start);
+        } else if max_gap.is_some_and(|gap| end - start > gap) {
+            cues.gaps.push((start, end));
+        }
+    }
+
+    // all silence, or as good as
+    if cues.start >= cues.end.unwrap_or(duration) {
+        return Cues::default();
+    }
+
+    cues
+}
+
+impl Detector {
+    async fn measure(&self, source: &mut Source) -> Result<Vec<(f64, Option<f64>)>, Error> {
+        let config::Silence {
+            threshold,
+            min_duration,
+            ..
+        } = self.config;
+
+        let mut child = Command::new(&self.config.ffmpeg)
+            .args(["-hide_banner", "-nostats", "-i", "pipe:0", "-vn", "-af"])
+            .arg(format!(
+                "silencedetect=noise={threshold}dB:d={min_duration}"
+            ))
+            .args(["-f", "null", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let mut output = Vec::new();
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        // read stderr as we go, so ffmpeg doesn't block writing to it
+        let taken = std::mem::replace(source, Source::Buffer(Default::default()));
+        let ((taken, fed), read) = tokio::join!(
+            transcode::feed(stdin, taken),
+            stderr.read_to_end(&mut output)
+        );
+        *source = taken;
+        source.rewind()?;
+        fed?;
+        read?;
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(Error::Ffmpeg(status));
+        }
+
+        Ok(parse_silencedetect(&String::from_utf8_lossy(&output)))
+    }
+
+    /// Finds the silences in the song, which is rewound afterwards.  `None` if it can't be
+    /// checked.
+    pub async fn silences(
+        &self,
+        song: &SongMetadata,
+        source: &mut Source,
+    ) -> Option<Vec<(f64, Option<f64>)>> {
+        match self.measure(source).await {
+            Ok(silences) => Some(silences),
+            Err(e) => {
+                log::error!(
+                    "Error finding silence in {} - {}: {}",
+                    song.title,
+                    song.artist,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Where the song's sound starts and ends, given the silences found in it
+    pub fn cues(&self, song: &song::Any, silences: &[(f64, Option<f64>)]) -> Cues {
+        let cues = cues(silences, song.duration(), self.config.max_gap);
+        log::debug!(
+            "{} - {} plays from {:.1}s to {:.1}s, skipping {} gaps",
+            song.metadata().title,
+            song.metadata().artist,
+            cues.start,
+            cues.end.unwrap_or(song.duration()),
+            cues.gaps.len()
+        );
+        cues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silencedetect() {
+        let output = "\
+Input #0, mp3, from 'pipe:0':
+[silencedetect @ 0x55d1] silence_start: -0.0123
+[silencedetect @ 0x55d1] silence_end: 2.5 | silence_duration: 2.51
+size=N/A time=00:01:00.00 bitrate=N/A speed= 300x
+[silencedetect @ 0x55d1] silence_start: 90
+[silencedetect @ 0x55d1] silence_end: 97.25 | silence_duration: 7.25
+[silencedetect @ 0x55d1] silence_start: 181.2
+";
+        assert_eq!(
+            parse_silencedetect(output),
+            [(0., Some(2.5)), (90., Some(97.25)), (181.2, None)]
+        );
+    }
+
+    #[test]
+    fn cue_points() {
+        let silences = [(0., Some(2.5)), (90., Some(97.25)), (181.2, None)];
+
+        assert_eq!(
+            cues(&silences, 185., None),
+            Cues {
+                start: 2.5,
+                end: Some(181.2),
+                gaps: Vec::new(),
+                fade: None,
+            }
+        );
+        assert_eq!(cues(&silences, 185., Some(5.)).gaps, [(90., 97.25)]);
+        assert_eq!(cues(&silences, 185., Some(10.)).gaps, []);
+
+        // the end silence ended right at the end, and there's none at the start
+        let cue = cues(&[(1., Some(2.)), (60., Some(61.95))], 62., None);
+        assert_eq!((cue.start, cue.end), (0., Some(60.)));
+
+        assert_eq!(cues(&[(0., None)], 10., None), Cues::default());
+    }
+}
@@ -0,0 +1,186 @@
+//! AAC in ADTS frames, which (like MP3 frames) each start with a header that gives their size.
+//!
+//! see: https://wiki.multimedia.cx/index.php/ADTS
+
+use std::io::{self, BufReader, Seek};
+
+use id3::Id3;
+
+use crate::{getter::Source, playlist::SongMetadata};
+
+use super::{
+    reader::{FrameHeader, FrameReader, READ_AHEAD},
+    Codec, Packet, Packets, Song,
+};
+
+/// Samples in each raw data block
+const SAMPLES_PER_BLOCK: u32 = 1024;
+
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+#[derive(Debug)]
+pub struct Adts {
+    /// Offset of the first frame, after any ID3 tag
+    start: u64,
+}
+
+/// [0..2]: 0xFFF sync, then 0bvllp where v is MPEG version, l is layer (always 0), and p is set
+/// when there is no CRC
+/// [2]: 0bPPSSSSpC where P is profile, S is sample rate index, and C is the top bit of the
+/// channel configuration
+/// [3..7]: the rest of the channel configuration, then frame length (13 bits, including the
+/// header), buffer fullness (11 bits), and number of raw data blocks minus one (2 bits)
+#[derive(Debug, Clone, Copy)]
+pub struct Header([u8; 7]);
+
+impl Header {
+    #[inline]
+    pub fn sample_rate(self) -> u32 {
+        SAMPLE_RATES[((self.0[2] >> 2) & 0b1111) as usize]
+    }
+
+    #[inline]
+    pub fn samples(self) -> u32 {
+        ((self.0[6] & 0b11) as u32 + 1) * SAMPLES_PER_BLOCK
+    }
+
+    #[inline]
+    pub fn duration(self) -> f64 {
+        self.samples() as f64 / self.sample_rate() as f64
+    }
+}
+
+impl FrameHeader for Header {
+    const LEN: usize = 7;
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let header = Self(bytes.get(..7)?.try_into().ok()?);
+
+        (bytes[0] == 0xFF
+            && bytes[1] & 0b11110110 == 0b11110000
+            && ((bytes[2] >> 2) & 0b1111) < SAMPLE_RATES.len() as u8
+            && header.frame_len() > Self::LEN)
+            .then_some(header)
+    }
+
+    /// The version, profile, sample rate and channels can't change between frames.
+    #[inline]
+    fn same_stream(self, other: Self) -> bool {
+        self.0[1] == other.0[1]
+            && self.0[2] & 0b11111101 == other.0[2] & 0b11111101
+            && self.0[3] & 0b11000000 == other.0[3] & 0b11000000
+    }
+
+    #[inline]
+    fn frame_len(self) -> usize {
+        ((self.0[3] as usize & 0b11) << 11) | (self.0[4] as usize) << 3 | (self.0[5] as usize) >> 5
+    }
+}
+
+impl Codec for Adts {
+    const MIME_TYPE: &'static str = "audio/aac";
+
+    fn load(metadata: SongMetadata, mut source: Source) -> io::Result<Song<Self>> {
+        let mut reader = BufReader::with_capacity(READ_AHEAD, &mut source);
+
+        let _id3 = Id3::read(&mut reader)?;
+        let start = reader.stream_position()?;
+
+        let mut frames = FrameReader::<_, Header>::new(reader);
+        let mut duration = 0.;
+        while let Some(header) = frames.skip_frame()? {
+            duration += header.duration();
+        }
+
+        if frames.skipped > 0 {
+            log::warn!(
+                "Skipped {} bytes that aren't ADTS frames in {} - {}",
+                frames.skipped,
+                metadata.title,
+                metadata.artist,
+            );
+        }
+        drop(frames);
+
+        Ok(Song {
+            metadata,
+            source,
+            duration,
+            gain: None,
+            cues: Default::default(),
+            codec: Adts { start },
+        })
+    }
+
+    fn packets(song: &mut Song<Self>) -> io::Result<Packets<'_>> {
+        song.source.seek(io::SeekFrom::Start(song.codec.start))?;
+
+        let mut frames = FrameReader::<_, Header>::new(&mut song.source);
+
+        Ok(Box::new(std::iter::from_fn(move || {
+            match frames.next_frame() {
+                Ok(frame) => frame.map(|(header, data)| {
+                    let mut bytes = header.0.to_vec();
+                    bytes.extend(data);
+
+                    Packet {
+                        data: bytes,
+                        duration: header.duration(),
+                    }
+                }),
+                Err(e) => {
+                    log::error!("Error reading frame: {:?}", e);
+                    None
+                }
+            }
+        })))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    /// An AAC-LC frame at 44.1 kHz, stereo, with `blocks` raw data blocks and `len` bytes in all
+    pub(crate) fn frame(len: usize, blocks: u8) -> Vec<u8> {
+        let mut frame = vec![
+            0xFF,
+            0xF1,
+            0b0101_0000,
+            0b1000_0000 | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len as u8) << 5) | 0b11111,
+            0b1111_1100 | (blocks - 1),
+        ];
+        frame.resize(len, 0xAA);
+        frame
+    }
+
+    #[test]
+    fn frames() -> io::Result<()> {
+        let mut data = Vec::new();
+        for i in 0..10 {
+            data.extend(frame(200 + i, 1));
+        }
+        // junk in the middle, then a frame with two blocks
+        data.extend([0xFF, 0xF1, 0, 0]);
+        data.extend(frame(300, 2));
+
+        let mut song = Song::<Adts>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data)),
+        )?;
+        assert!((song.duration - 12. * 1024. / 44100.).abs() < 1e-9);
+
+        let packets: Vec<_> = song.packets()?.collect();
+        assert_eq!(packets.len(), 11);
+        assert_eq!(packets[3].data.len(), 203);
+        assert!((packets[10].duration - 2048. / 44100.).abs() < 1e-9);
+
+        Ok(())
+    }
+}
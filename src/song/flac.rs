@@ -0,0 +1,408 @@
+//! Native FLAC.  Unlike MP3 and ADTS, FLAC frames don't say how long they are, so a frame ends
+//! where the next valid frame header starts.
+//!
+//! see: https://xiph.org/flac/format.html
+
+use std::io::{self, BufReader, Read, Seek};
+
+use id3::Id3;
+
+use crate::{getter::Source, playlist::SongMetadata};
+
+use super::{parse_comments, reader::READ_AHEAD, Codec, Packet, Packets, Song};
+
+const STREAMINFO: u8 = 0;
+const STREAMINFO_LEN: usize = 34;
+const LAST_BLOCK: u8 = 0x80;
+const VORBIS_COMMENT: u8 = 4;
+
+/// Longest possible frame header, including the CRC
+const MAX_HEADER: usize = 16;
+
+#[derive(Debug)]
+pub struct Flac {
+    /// Offset of the first frame, after the metadata blocks
+    start: u64,
+    sample_rate: u32,
+    /// Sent before the first frame, so each song can be decoded on its own
+    stream_info: [u8; STREAMINFO_LEN],
+}
+
+#[inline]
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ b, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[inline]
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Parses the frame header at the start of `data`, returning the number of samples in the frame.
+/// Anything that isn't a complete, valid header (with a matching CRC) is `None`.
+fn block_size(data: &[u8]) -> Option<u32> {
+    let [0xFF, 0xF8 | 0xF9, sizes, format, ..] = *data else {
+        return None;
+    };
+
+    let (block, rate) = (sizes >> 4, sizes & 0b1111);
+    let (channels, bits) = (format >> 4, (format >> 1) & 0b111);
+    if block == 0 || rate == 0b1111 || channels > 10 || bits == 0b011 || format & 1 != 0 {
+        return None;
+    }
+
+    // the frame or sample number, UTF-8 style
+    let first = *data.get(4)?;
+    let len = match first.leading_ones() {
+        0 => 1,
+        n @ 2..=7 => n as usize,
+        _ => return None,
+    };
+    let number = data.get(5..4 + len)?;
+    if number.iter().any(|&b| b & 0b11000000 != 0b10000000) {
+        return None;
+    }
+
+    let mut at = 4 + len;
+    let samples = match block {
+        1 => 192,
+        2..=5 => 576 << (block - 2),
+        6 => {
+            at += 1;
+            *data.get(at - 1)? as u32 + 1
+        }
+        7 => {
+            at += 2;
+            u16::from_be_bytes(data.get(at - 2..at)?.try_into().ok()?) as u32 + 1
+        }
+        _ => 256 << (block - 8),
+    };
+
+    at += match rate {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+
+    (crc8(data.get(..at)?) == *data.get(at)?).then_some(samples)
+}
+
+/// Splits a stream of bytes into frames.
+#[derive(Debug)]
+struct Frames<R> {
+    source: R,
+    buf: Vec<u8>,
+    eof: bool,
+    /// Number of bytes thrown away because they weren't part of a frame
+    skipped: u64,
+}
+
+impl<R: Read> Frames<R> {
+    fn new(source: R) -> Self {
+        Self {
+            source,
+            buf: Vec::with_capacity(READ_AHEAD),
+            eof: false,
+            skipped: 0,
+        }
+    }
+
+    /// Reads more of the source onto the end of the buffer.
+    fn fill(&mut self) -> io::Result<()> {
+        let len = self.buf.len();
+        self.buf.resize(len + READ_AHEAD, 0);
+
+        let read = loop {
+            match self.source.read(&mut self.buf[len..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        };
+
+        self.buf.truncate(len + read);
+        self.eof = read == 0;
+        Ok(())
+    }
+
+    /// Finds the first frame header at or after `from`.  With `end_of` set, the header also has to
+    /// end a frame that started at 0, so that a header-like run of bytes inside a frame doesn't
+    /// cut it short.
+    fn find(&self, from: usize, end_of: bool) -> Option<(usize, u32)> {
+        (from..self.buf.len().saturating_sub(1)).find_map(|i| {
+            let samples = block_size(&self.buf[i..])?;
+
+            if end_of {
+                let crc = u16::from_be_bytes(self.buf.get(i - 2..i)?.try_into().ok()?);
+                if crc16(&self.buf[..i - 2]) != crc {
+                    return None;
+                }
+            }
+
+            Some((i, samples))
+        })
+    }
+
+    /// Reads the next frame, returning the number of samples in it and the whole frame.
+    fn next_frame(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        // find the start of a frame
+        let samples = loop {
+            if let Some((i, samples)) = self.find(0, false) {
+                self.buf.drain(..i);
+                self.skipped += i as u64;
+                break samples;
+            }
+
+            // the end of the buffer could be the start of a header
+            let keep = self.buf.len().min(MAX_HEADER);
+            let junk = self.buf.len() - keep;
+            self.buf.drain(..junk);
+            self.skipped += junk as u64;
+
+            if self.eof {
+                self.skipped += self.buf.len() as u64;
+                self.buf.clear();
+                return Ok(None);
+            }
+            self.fill()?;
+        };
+
+        // and where the next one starts
+        let mut from = 2;
+        loop {
+            if let Some((end, _)) = self.find(from, true) {
+                return Ok(Some((samples, self.buf.drain(..end).collect())));
+            }
+
+            if self.eof {
+                return Ok(Some((samples, std::mem::take(&mut self.buf))));
+            }
+
+            from = self.buf.len().saturating_sub(MAX_HEADER).max(2);
+            self.fill()?;
+        }
+    }
+}
+
+/// Reads the Vorbis comments from the metadata blocks at the start of a FLAC file.
+pub(super) fn comments(mut r: impl Read + Seek) -> io::Result<Vec<(String, String)>> {
+    let _id3 = Id3::read(&mut r)?;
+
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut marker = [0u8; 4];
+    r.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(invalid("Not a FLAC file"));
+    }
+
+    loop {
+        let mut header = [0u8; 4];
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        if header[0] & !LAST_BLOCK == VORBIS_COMMENT {
+            let mut block = vec![0; len];
+            r.read_exact(&mut block)?;
+            return parse_comments(&block).ok_or_else(|| invalid("Invalid Vorbis comments"));
+        }
+        if header[0] & LAST_BLOCK != 0 {
+            return Ok(Vec::new());
+        }
+
+        r.seek(io::SeekFrom::Current(len as i64))?;
+    }
+}
+
+impl Codec for Flac {
+    const MIME_TYPE: &'static str = "audio/flac";
+
+    fn load(metadata: SongMetadata, mut source: Source) -> io::Result<Song<Self>> {
+        let mut reader = BufReader::with_capacity(READ_AHEAD, &mut source);
+        let _id3 = Id3::read(&mut reader)?;
+
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut marker = [0u8; 4];
+        reader.read_exact(&mut marker)?;
+        if &marker != b"fLaC" {
+            return Err(invalid("Not a FLAC file"));
+        }
+
+        let mut stream_info = None;
+        loop {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+            if header[0] & !LAST_BLOCK == STREAMINFO && len == STREAMINFO_LEN {
+                let mut block = [0u8; STREAMINFO_LEN];
+                reader.read_exact(&mut block)?;
+                stream_info = Some(block);
+            } else {
+                reader.seek_relative(len as i64)?;
+            }
+
+            if header[0] & LAST_BLOCK != 0 {
+                break;
+            }
+        }
+
+        let stream_info = stream_info.ok_or_else(|| invalid("No STREAMINFO block"))?;
+        let start = reader.stream_position()?;
+
+        // sample rate: 20 bits, then 3 of channels, 5 of bits per sample, and 36 of total samples
+        let sample_rate =
+            u32::from_be_bytes([0, stream_info[10], stream_info[11], stream_info[12]]) >> 4;
+        let total = ((stream_info[13] & 0b1111) as u64) << 32
+            | u32::from_be_bytes(stream_info[14..18].try_into().expect("4 bytes")) as u64;
+        if sample_rate == 0 {
+            return Err(invalid("Invalid sample rate"));
+        }
+
+        let duration = if total > 0 {
+            total as f64 / sample_rate as f64
+        } else {
+            // unknown, so count every frame
+            let mut frames = Frames::new(reader);
+            let mut samples = 0;
+            while let Some((n, _)) = frames.next_frame()? {
+                samples += n as u64;
+            }
+            samples as f64 / sample_rate as f64
+        };
+
+        Ok(Song {
+            metadata,
+            source,
+            duration,
+            gain: None,
+            cues: Default::default(),
+            codec: Flac {
+                start,
+                sample_rate,
+                stream_info,
+            },
+        })
+    }
+
+    fn packets(song: &mut Song<Self>) -> io::Result<Packets<'_>> {
+        song.source.seek(io::SeekFrom::Start(song.codec.start))?;
+
+        let mut header = b"fLaC".to_vec();
+        header.extend([LAST_BLOCK | STREAMINFO, 0, 0, STREAMINFO_LEN as u8]);
+        header.extend(song.codec.stream_info);
+
+        let sample_rate = song.codec.sample_rate as f64;
+        let mut frames = Frames::new(&mut song.source);
+
+        let frames = std::iter::from_fn(move || match frames.next_frame() {
+            Ok(frame) => frame.map(|(samples, data)| Packet {
+                data,
+                duration: samples as f64 / sample_rate,
+            }),
+            Err(e) => {
+                log::error!("Error reading frame: {:?}", e);
+                None
+            }
+        });
+
+        Ok(Box::new(
+            std::iter::once(Packet {
+                data: header,
+                duration: 0.,
+            })
+            .chain(frames),
+        ))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    /// A frame of 4096 samples (or `samples`, if it's an uncommon size), numbered `n`, with `len`
+    /// bytes of subframe data
+    pub(crate) fn frame(n: u8, samples: Option<u16>, len: usize) -> Vec<u8> {
+        let block = if samples.is_some() { 0b0111 } else { 0b1100 };
+        // 44.1 kHz, stereo, 16 bits per sample
+        let mut frame = vec![0xFF, 0xF8, block << 4 | 0b1001, 0b0001_1000, n];
+        if let Some(samples) = samples {
+            frame.extend((samples - 1).to_be_bytes());
+        }
+        frame.push(crc8(&frame));
+
+        // data that looks like the start of a frame header
+        frame.extend([0xFF, 0xF8]);
+        frame.resize(frame.len() + len - 2, n);
+        frame.extend(crc16(&frame).to_be_bytes());
+        frame
+    }
+
+    /// A file of `total` samples at 44.1 kHz, with the stream info and some padding before
+    /// `frames`
+    pub(crate) fn file(total: u64, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut info = [0u8; STREAMINFO_LEN];
+        info[10..18]
+            .copy_from_slice(&((44100u64 << 44) | (1 << 41) | (15 << 36) | total).to_be_bytes());
+
+        let mut data = b"fLaC".to_vec();
+        data.extend([STREAMINFO, 0, 0, STREAMINFO_LEN as u8]);
+        data.extend(info);
+        // padding
+        data.extend([LAST_BLOCK | 1, 0, 0, 10]);
+        data.extend([0; 10]);
+        data.extend(frames.concat());
+        data
+    }
+
+    #[test]
+    fn frames() -> io::Result<()> {
+        let frames = [
+            frame(0, None, 1000),
+            frame(1, None, 70000),
+            frame(2, Some(100), 10),
+        ];
+        let total = 4096 * 2 + 100;
+
+        for total_in_header in [total, 0] {
+            let data = file(total_in_header, &frames);
+            let mut song = Song::<Flac>::load(
+                song("title", "artist", None),
+                Source::Buffer(io::Cursor::new(data)),
+            )?;
+            assert!((song.duration - total as f64 / 44100.).abs() < 1e-9);
+
+            let packets: Vec<_> = song.packets()?.collect();
+            assert_eq!(packets.len(), 4);
+            assert!(packets[0].data.starts_with(b"fLaC"));
+            assert_eq!(packets[0].duration, 0.);
+            for (packet, frame) in packets[1..].iter().zip(&frames) {
+                assert_eq!(&packet.data, frame);
+            }
+        }
+
+        Ok(())
+    }
+}
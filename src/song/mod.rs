@@ -1,17 +1,416 @@
-use crate::playlist::SongMetadata;
+use std::io::{self, Read, Seek};
 
-use self::mp3::Mp3;
+use id3::Id3;
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use crate::{
+    getter::Source,
+    playlist::{Cover, SongMetadata},
+};
+
+use self::{adts::Adts, flac::Flac, mp3::Mp3, ogg::Ogg};
+
+pub mod adts;
+pub mod flac;
 pub mod mp3;
+pub mod ogg;
+mod reader;
 
 #[derive(Debug)]
 pub struct Song<C = Mp3> {
     pub metadata: SongMetadata,
-    pub data: Vec<u8>,
+    /// Where the song is read from as it plays.  Only a bounded window of it is ever in memory
+    /// (unless the getter returned an in-memory buffer).
+    pub source: Source,
     pub duration: f64,
+    /// How much players should turn the song up by, in dB, if loudness normalization is on and
+    /// it wasn't applied while transcoding
+    pub gain: Option<f64>,
+    pub cues: Cues,
     pub codec: C,
 }
 
-pub trait Codec {
-	const MIME_TYPE: &'static str;
-}
\ No newline at end of file
+/// Where in a song to start and stop playing it, and what to skip in between, in seconds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cues {
+    pub start: f64,
+    /// The end of the song, if not set
+    pub end: Option<f64>,
+    /// Stretches to skip, in order
+    pub gaps: Vec<(f64, f64)>,
+    /// Where to start fading into the next song, if crossfading and not just before the end
+    pub fade: Option<f64>,
+}
+
+/// A piece of a song that can be sent on its own: an MP3/ADTS/FLAC frame or an Ogg page
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Seconds of audio in the packet
+    pub duration: f64,
+}
+
+impl Packet {
+    pub async fn write(&self, mut w: impl AsyncWrite + Unpin) -> io::Result<usize> {
+        w.write(&self.data).await
+    }
+}
+
+pub type Packets<'a> = Box<dyn Iterator<Item = Packet> + Send + 'a>;
+
+pub trait Codec: Sized {
+    const MIME_TYPE: &'static str;
+
+    /// Finds where the song's packets are and how long it is, reading as little of the source as
+    /// the format allows.
+    fn load(metadata: SongMetadata, source: Source) -> io::Result<Song<Self>>;
+
+    /// Reads packets from the start of the song, holding only a bounded window of the source in
+    /// memory at a time.
+    fn packets(song: &mut Song<Self>) -> io::Result<Packets<'_>>;
+}
+
+impl<C: Codec> Song<C> {
+    #[inline]
+    pub fn load(metadata: SongMetadata, source: Source) -> io::Result<Self> {
+        C::load(metadata, source)
+    }
+
+    #[inline]
+    pub fn packets(&mut self) -> io::Result<Packets<'_>> {
+        C::packets(self)
+    }
+}
+
+/// Container/codec of a song or stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    Mp3,
+    /// Vorbis or Opus
+    Ogg,
+    /// AAC in ADTS frames
+    Aac,
+    Flac,
+}
+
+impl Format {
+    pub const fn mime_type(self) -> &'static str {
+        match self {
+            Self::Mp3 => Mp3::MIME_TYPE,
+            Self::Ogg => Ogg::MIME_TYPE,
+            Self::Aac => Adts::MIME_TYPE,
+            Self::Flac => Flac::MIME_TYPE,
+        }
+    }
+
+    /// The usual file extension
+    pub const fn ext(self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Ogg => "ogg",
+            Self::Aac => "aac",
+            Self::Flac => "flac",
+        }
+    }
+
+    /// Whether a packet can be decoded without the ones before it, so a stream can cut to it from
+    /// anything else
+    pub fn standalone(self, packet: &[u8]) -> bool {
+        match self {
+            Self::Mp3 => mp3::standalone(packet),
+            _ => true,
+        }
+    }
+
+    /// Guesses the format from the start of the file, after any ID3 tag, and rewinds the source.
+    /// Anything unrecognizable is assumed to be MP3, which can skip junk to find its first frame.
+    pub fn sniff(source: &mut (impl Read + Seek)) -> io::Result<Self> {
+        let start = source.stream_position()?;
+        // an ID3 tag is read past, or rewound if there isn't one
+        let id3_end = match Id3::read(&mut *source) {
+            Ok(_) => source.stream_position()?,
+            // too short to be a tag
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => start,
+            Err(e) => return Err(e),
+        };
+        source.seek(io::SeekFrom::Start(id3_end))?;
+
+        let mut magic = [0u8; 4];
+        let mut len = 0;
+        while len < magic.len() {
+            match source.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        source.seek(io::SeekFrom::Start(start))?;
+
+        Ok(match magic {
+            [b'O', b'g', b'g', b'S'] => Self::Ogg,
+            [b'f', b'L', b'a', b'C'] => Self::Flac,
+            // the layer bits, which MP3 never sets to 00
+            [0xFF, b, ..] if b & 0b11110110 == 0b11110000 => Self::Aac,
+            _ => Self::Mp3,
+        })
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.ext())
+    }
+}
+
+/// Reads what the song's ID3 tag says about it, other than its title and artist, and rewinds the
+/// source.
+pub fn tags(source: &mut (impl Read + Seek)) -> io::Result<SongMetadata> {
+    let start = source.stream_position()?;
+    let id3 = match Id3::read(&mut *source) {
+        Ok(id3) => id3,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(e) => return Err(e),
+    };
+    source.seek(io::SeekFrom::Start(start))?;
+
+    let Some(id3) = id3 else {
+        return Ok(SongMetadata::default());
+    };
+
+    // "3/12" is track 3 of 12, and v2.4 dates start with the year
+    let number = |text: String| {
+        let digits = text
+            .trim_start()
+            .split(|c: char| !c.is_ascii_digit())
+            .next()?;
+        digits.parse().ok()
+    };
+
+    Ok(SongMetadata {
+        album: id3.text(b"TALB"),
+        album_artist: id3.text(b"TPE2"),
+        track: id3.text(b"TRCK").and_then(number),
+        year: id3
+            .text(b"TDRC")
+            .or_else(|| id3.text(b"TYER"))
+            .and_then(number),
+        genre: id3.text(b"TCON").map(|genre| {
+            // ID3v1 genres are referred to by number, like "(17)Rock" or "(17)"
+            match genre
+                .strip_prefix('(')
+                .and_then(|rest| rest.split_once(')'))
+            {
+                Some((_, name)) if !name.is_empty() => name.to_owned(),
+                _ => genre,
+            }
+        }),
+        cover: id3.picture().map(|picture| Cover::Embedded {
+            mime_type: picture.mime_type.to_owned(),
+            data: picture.data.into(),
+        }),
+        ..Default::default()
+    })
+}
+
+/// Reads the Vorbis comments of an Ogg or FLAC song, like `("REPLAYGAIN_TRACK_GAIN", "-6.5 dB")`,
+/// and rewinds the source.  MP3 and AAC songs have ID3 tags instead, so they have none.
+pub fn comments(source: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    let start = source.stream_position()?;
+    let comments = match Format::sniff(source)? {
+        Format::Ogg => ogg::comments(&mut *source),
+        Format::Flac => flac::comments(&mut *source),
+        Format::Mp3 | Format::Aac => Ok(Vec::new()),
+    };
+    source.seek(io::SeekFrom::Start(start))?;
+
+    comments
+}
+
+/// Parses a Vorbis comment header: a vendor string, then a count of `KEY=value` comments, each
+/// string with its length before it (little-endian).  `None` if it's cut short.
+fn parse_comments(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        let taken = data.get(..n)?;
+        *data = &data[n..];
+        Some(taken)
+    }
+
+    fn len(data: &mut &[u8]) -> Option<usize> {
+        Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?) as usize)
+    }
+
+    let vendor = len(&mut data)?;
+    take(&mut data, vendor)?;
+
+    let count = len(&mut data)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let n = len(&mut data)?;
+        let comment = String::from_utf8_lossy(take(&mut data, n)?);
+        // ones without a `=` aren't valid, and are left out
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_owned(), value.to_owned()));
+        }
+    }
+
+    Some(comments)
+}
+
+/// A loaded song of any supported format
+#[derive(Debug)]
+pub enum Any {
+    Mp3(Song<Mp3>),
+    Ogg(Song<Ogg>),
+    Aac(Song<Adts>),
+    Flac(Song<Flac>),
+}
+
+macro_rules! each {
+    ($self:expr, $song:ident => $e:expr) => {
+        match $self {
+            Any::Mp3($song) => $e,
+            Any::Ogg($song) => $e,
+            Any::Aac($song) => $e,
+            Any::Flac($song) => $e,
+        }
+    };
+}
+
+impl Any {
+    /// Loads a song, working out its format from its contents and filling in its metadata from
+    /// its tags.
+    pub fn load(mut metadata: SongMetadata, mut source: Source) -> io::Result<Self> {
+        metadata.fill(tags(&mut source)?);
+
+        Ok(match Format::sniff(&mut source)? {
+            Format::Mp3 => Self::Mp3(Song::load(metadata, source)?),
+            Format::Ogg => Self::Ogg(Song::load(metadata, source)?),
+            Format::Aac => Self::Aac(Song::load(metadata, source)?),
+            Format::Flac => Self::Flac(Song::load(metadata, source)?),
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        match self {
+            Self::Mp3(_) => Format::Mp3,
+            Self::Ogg(_) => Format::Ogg,
+            Self::Aac(_) => Format::Aac,
+            Self::Flac(_) => Format::Flac,
+        }
+    }
+
+    pub fn metadata(&self) -> &SongMetadata {
+        each!(self, song => &song.metadata)
+    }
+
+    pub fn into_metadata(self) -> SongMetadata {
+        each!(self, song => song.metadata)
+    }
+
+    pub fn duration(&self) -> f64 {
+        each!(self, song => song.duration)
+    }
+
+    pub fn gain(&self) -> Option<f64> {
+        each!(self, song => song.gain)
+    }
+
+    pub fn set_gain(&mut self, gain: Option<f64>) {
+        each!(self, song => song.gain = gain)
+    }
+
+    pub fn cues(&self) -> &Cues {
+        each!(self, song => &song.cues)
+    }
+
+    pub fn set_cues(&mut self, cues: Cues) {
+        each!(self, song => song.cues = cues)
+    }
+
+    pub fn packets(&mut self) -> io::Result<Packets<'_>> {
+        each!(self, song => song.packets())
+    }
+
+    /// Reads packets from about `position` seconds in.  MP3s jump straight there with their table
+    /// of contents (or bitrate); other formats start from the top.  Also returns where in the song
+    /// the packets start.
+    pub fn packets_from(&mut self, position: f64) -> io::Result<(f64, Packets<'_>)> {
+        match self {
+            Self::Mp3(song) => {
+                let (at, frames) = song.frames_from(position)?;
+                Ok((at, Box::new(frames.map(Packet::from))))
+            }
+            _ => Ok((0., self.packets()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff() -> io::Result<()> {
+        let mut id3 = b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec();
+        id3.extend(b"fLaC");
+
+        let table: [(&[u8], Format); 6] = [
+            (b"OggS\0\x02", Format::Ogg),
+            (b"fLaC\0\0\0\x22", Format::Flac),
+            (&id3, Format::Flac),
+            (&[0xFF, 0xF1, 0x50, 0x80], Format::Aac),
+            (&[0xFF, 0xFB, 0x90, 0x00], Format::Mp3),
+            (b"??", Format::Mp3),
+        ];
+
+        for (data, format) in table {
+            let mut source = io::Cursor::new(data);
+            assert_eq!(Format::sniff(&mut source)?, format, "{data:02X?}");
+            assert_eq!(source.position(), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tag_metadata() -> io::Result<()> {
+        use id3::{Frame, FrameType};
+
+        let frame = |tag: &[u8; 4], data: &[u8]| {
+            Frame::from(FrameType::Other {
+                tag: *tag,
+                data: data.to_vec(),
+            })
+        };
+        let mut data = Id3::new(vec![
+            frame(b"TALB", b"\x03Album"),
+            frame(b"TRCK", b"\x034/12"),
+            frame(b"TDRC", b"\x031999-03-01"),
+            frame(b"TCON", b"\x03(17)Rock"),
+            frame(b"APIC", b"\x03image/png\x00\x03\x00png"),
+        ])
+        .as_bytes();
+        data.extend([0xFF, 0xFB, 0x90, 0x00]);
+
+        let mut source = io::Cursor::new(data);
+        let metadata = tags(&mut source)?;
+        assert_eq!(source.position(), 0);
+
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.album_artist, None);
+        assert_eq!(metadata.track, Some(4));
+        assert_eq!(metadata.year, Some(1999));
+        assert_eq!(metadata.genre.as_deref(), Some("Rock"));
+        assert_eq!(
+            metadata.cover,
+            Some(Cover::Embedded {
+                mime_type: "image/png".into(),
+                data: b"png"[..].into(),
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! see: https://www.datavoyage.com/mpgscript/mpeghdr.htm
 
+use super::HeaderError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
     V1,
@@ -18,13 +20,39 @@ pub enum Layer {
 use Layer::*;
 use Version::*;
 
-/// Returns the bitrate (kb/s) for the index where the first four bits represent the MPEG version and layer, and the last four bits represent the bitrate index for that version/layer combo.
-/// Versions: 00 => 2.5 (unimplemented), 01 => invalid, 10 => V2, 11 => V1
-/// Layers: 00 => invalid, 01 => L3, 10 => L2, 11 => L1
-#[inline]
-pub(super) const fn get_bitrate(ver: Version, layer: Layer, bitrate_idx: u8) -> i64 {
-    let bitrate_idx = ((bitrate_idx - 1) & 0b1111) as usize;
-    (match (ver, layer) {
+/// Versions: 00 => V2.5, 01 => reserved, 10 => V2, 11 => V1
+pub(super) const fn get_version(bits: u8) -> Result<Version, HeaderError> {
+    match bits & 0b11 {
+        0b11 => Ok(V1),
+        0b10 => Ok(V2),
+        0b00 => Ok(V2_5),
+        _ => Err(HeaderError::Version),
+    }
+}
+
+/// Layers: 00 => reserved, 01 => L3, 10 => L2, 11 => L1
+pub(super) const fn get_layer(bits: u8) -> Result<Layer, HeaderError> {
+    match bits & 0b11 {
+        0b11 => Ok(L1),
+        0b10 => Ok(L2),
+        0b01 => Ok(L3),
+        _ => Err(HeaderError::Layer),
+    }
+}
+
+/// Returns the bitrate (kb/s) for the bitrate index of the given MPEG version and layer.  Index
+/// 0 is "free format", which we can't find the frame size of, and index 15 is reserved.
+pub(super) const fn get_bitrate(
+    ver: Version,
+    layer: Layer,
+    bitrate_idx: u8,
+) -> Result<i64, HeaderError> {
+    let bitrate_idx = match bitrate_idx & 0b1111 {
+        0 => return Err(HeaderError::FreeFormat),
+        0b1111 => return Err(HeaderError::Bitrate),
+        idx => (idx - 1) as usize,
+    };
+    Ok((match (ver, layer) {
         (V1, L1) => [
             32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
         ],
@@ -38,17 +66,21 @@ pub(super) const fn get_bitrate(ver: Version, layer: Layer, bitrate_idx: u8) ->
             32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
         ],
         (V2 | V2_5, L2 | L3) => [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
-    })[bitrate_idx]
+    })[bitrate_idx])
 }
 
-/// Gets the sample rate, in Hz, of the frame depending on the MPEG version and the sample index
-pub(super) const fn get_sample_rate(ver: Version, sample_idx: u8) -> i64 {
-    let sample_idx = (sample_idx & 0b11) as usize;
-    (match ver {
+/// Gets the sample rate, in Hz, of the frame depending on the MPEG version and the sample index.
+/// Index 3 is reserved.
+pub(super) const fn get_sample_rate(ver: Version, sample_idx: u8) -> Result<i64, HeaderError> {
+    let sample_idx = match sample_idx & 0b11 {
+        0b11 => return Err(HeaderError::SampleRate),
+        idx => idx as usize,
+    };
+    Ok((match ver {
         V1 => [44100, 48000, 32000],
         V2 => [22050, 24000, 16000],
         V2_5 => [11025, 12000, 8000],
-    })[sample_idx]
+    })[sample_idx])
 }
 
 pub(super) const fn get_samples_per_frame(version: Version, layer: Layer) -> i64 {
@@ -57,6 +89,16 @@ pub(super) const fn get_samples_per_frame(version: Version, layer: Layer) -> i64
         (V1, L2 | L3) => 1152,
         (V2 | V2_5, L1) => 384,
         (V2 | V2_5, L2) => 1152,
-        (V2 | V2_5, L3) => 476,
+        (V2 | V2_5, L3) => 576,
+    }
+}
+
+/// Bytes per frame are `coefficient * bitrate / sample rate`, plus padding.  (Layer I is counted
+/// in 4-byte slots instead, so its coefficient is in slots.)
+pub(super) const fn get_size_coefficient(version: Version, layer: Layer) -> i64 {
+    match (version, layer) {
+        (_, L1) => 12,
+        (_, L2) | (V1, L3) => 144,
+        (V2 | V2_5, L3) => 72,
     }
 }
@@ -0,0 +1,321 @@
+//! Xing/Info and VBRI headers: an extra frame at the start of the file, written by the encoder,
+//! that describes the rest of the stream.
+//!
+//! see: https://www.codeproject.com/Articles/8295/MPEG-Audio-Frame-Header#XINGHeader and
+//! http://gabriel.mp3-tech.org/mp3infotag.html
+
+use super::{data::Version, Frame};
+
+#[derive(Debug, Clone)]
+pub struct Info {
+    /// Number of audio frames, not counting this one
+    pub frames: Option<u32>,
+    /// Size of the stream in bytes, counting this frame
+    pub bytes: Option<u32>,
+    pub toc: Toc,
+    pub lame: Option<Lame>,
+}
+
+/// Table of contents, for seeking in VBR files
+#[derive(Debug, Clone)]
+pub enum Toc {
+    None,
+    /// `toc[i]` is the position of `i`% of the way through the song, as a fraction of the stream
+    /// size out of 256.
+    Xing(Box<[u8; 100]>),
+    /// Each entry is the size in bytes of the next `frames_per_entry` frames.
+    Vbri {
+        entries: Vec<u32>,
+        frames_per_entry: u32,
+    },
+}
+
+/// The LAME extension to the Xing header
+#[derive(Debug, Clone, Copy)]
+pub struct Lame {
+    /// Number of samples of silence the encoder added to the start
+    pub delay: u16,
+    /// Number of samples of silence the encoder added to the end
+    pub padding: u16,
+}
+
+/// Samples of delay added by the decoder, which the LAME delay and padding account for
+pub const DECODER_DELAY: u32 = 528 + 1;
+
+const XING_FRAMES: u32 = 0x1;
+const XING_BYTES: u32 = 0x2;
+const XING_TOC: u32 = 0x4;
+const XING_QUALITY: u32 = 0x8;
+
+/// Offset of the VBRI header from the end of the frame header
+const VBRI_OFFSET: usize = 32;
+
+#[inline]
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+#[inline]
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl Info {
+    /// Reads the Xing/Info or VBRI header from the first frame of a stream, if it has one.
+    pub fn read(frame: &Frame) -> Option<Self> {
+        Self::read_xing(frame).or_else(|| Self::read_vbri(frame))
+    }
+
+    fn read_xing(frame: &Frame) -> Option<Self> {
+        // the tag goes right after the side information
+        let side_info = match (frame.header.version(), frame.header.mono()) {
+            (Version::V1, true) => 17,
+            (Version::V1, false) => 32,
+            (Version::V2 | Version::V2_5, true) => 9,
+            (Version::V2 | Version::V2_5, false) => 17,
+        };
+        let crc = if frame.header.protected() { 2 } else { 0 };
+
+        let data = frame.data.get(crc + side_info..)?;
+
+        if !matches!(data.get(..4)?, b"Xing" | b"Info") {
+            return None;
+        }
+
+        let flags = be_u32(data, 4)?;
+        let mut at = 8;
+
+        // each field is only there if its flag is set
+        let mut frames = None;
+        if flags & XING_FRAMES != 0 {
+            frames = Some(be_u32(data, at)?);
+            at += 4;
+        }
+
+        let mut bytes = None;
+        if flags & XING_BYTES != 0 {
+            bytes = Some(be_u32(data, at)?);
+            at += 4;
+        }
+
+        let mut toc = Toc::None;
+        if flags & XING_TOC != 0 {
+            toc = Toc::Xing(Box::new(data.get(at..at + 100)?.try_into().ok()?));
+            at += 100;
+        }
+
+        if flags & XING_QUALITY != 0 {
+            at += 4;
+        }
+
+        Some(Self {
+            frames,
+            bytes,
+            toc,
+            lame: data.get(at..).and_then(Lame::read),
+        })
+    }
+
+    fn read_vbri(frame: &Frame) -> Option<Self> {
+        let data = frame.data.get(VBRI_OFFSET..)?;
+
+        if data.get(..4)? != b"VBRI" {
+            return None;
+        }
+
+        // 4: version, 6: delay (float), 8: quality
+        let bytes = be_u32(data, 10)?;
+        let frames = be_u32(data, 14)?;
+        let n = be_u16(data, 18)? as usize;
+        let scale = be_u16(data, 20)? as u32;
+        let entry_size = be_u16(data, 22)? as usize;
+        let frames_per_entry = be_u16(data, 24)? as u32;
+        // entries are read as big-endian numbers up to a u32
+        if !(1..=4).contains(&entry_size) {
+            return None;
+        }
+
+        let entries = data
+            .get(26..26 + n * entry_size)?
+            .chunks_exact(entry_size)
+            .map(|entry| {
+                entry
+                    .iter()
+                    .fold(0u32, |acc, &b| (acc << 8) | b as u32)
+                    .saturating_mul(scale)
+            })
+            .collect();
+
+        Some(Self {
+            frames: Some(frames),
+            bytes: Some(bytes),
+            toc: Toc::Vbri {
+                entries,
+                frames_per_entry,
+            },
+            lame: None,
+        })
+    }
+
+    /// Finds the byte offset (from the start of this frame) of the frame `fraction` of the way
+    /// through the song, or `None` if there is no table of contents.
+    pub fn seek(&self, fraction: f64) -> Option<u64> {
+        let fraction = fraction.clamp(0., 1.);
+
+        match &self.toc {
+            Toc::None => None,
+            Toc::Xing(toc) => {
+                let bytes = self.bytes? as f64;
+                let percent = fraction * 100.;
+                let i = (percent as usize).min(99);
+
+                let a = toc[i] as f64;
+                let b = toc.get(i + 1).map_or(256., |&b| b as f64);
+                let position = a + (b - a) * (percent - i as f64);
+
+                Some((position / 256. * bytes) as u64)
+            }
+            Toc::Vbri {
+                entries,
+                frames_per_entry,
+            } => {
+                let frames = self.frames? as f64 * fraction;
+                let entry = frames / *frames_per_entry as f64;
+                let i = (entry as usize).min(entries.len());
+
+                let before: u64 = entries[..i].iter().map(|&e| e as u64).sum();
+                let partial = entries
+                    .get(i)
+                    .map_or(0., |&e| e as f64 * (entry - i as f64));
+
+                Some(before + partial as u64)
+            }
+        }
+    }
+}
+
+impl Lame {
+    /// Number of whole frames of encoder padding at the end, which can be left out so the next
+    /// song follows without the silence.  MP3 frames can't be cut, so any partial frame of it is
+    /// still played.
+    ///
+    /// The delay at the start is kept: the first frames of sound can draw on the bit reservoir in
+    /// the frames before them, so leaving those out would garble the start of the song.
+    pub fn trim(self, samples_per_frame: u32) -> usize {
+        let end = (self.padding as u32).saturating_sub(DECODER_DELAY);
+        (end / samples_per_frame) as usize
+    }
+
+    /// Reads the LAME tag, which goes right after the Xing fields.
+    fn read(data: &[u8]) -> Option<Self> {
+        // other encoders based on LAME (e.g. ffmpeg's) write the tag too
+        if !matches!(data.get(..4)?, b"LAME" | b"Lavf" | b"Lavc") {
+            return None;
+        }
+
+        // 9: encoder version, 10: revision/VBR method, 11: lowpass, 15: peak, 17: radio gain,
+        // 19: audiophile gain, 20: flags, 21: bitrate, then 12 bits each of delay and padding
+        let [a, b, c] = data.get(21..24)?.try_into().ok()?;
+
+        Some(Self {
+            delay: (a as u16) << 4 | (b as u16) >> 4,
+            padding: ((b & 0b1111) as u16) << 8 | c as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::{super::Header, *};
+
+    /// MPEG-1 Layer III, 128 kb/s, 44.1 kHz, stereo: 417 bytes per frame
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    /// An Info frame for `frames` frames of `HEADER`, with a LAME tag
+    pub(in super::super) fn info_frame(frames: u32, delay: u16, padding: u16) -> Vec<u8> {
+        let mut data = vec![0u8; 417 - 4];
+        let mut tag = Vec::new();
+        tag.extend(b"Info");
+        tag.extend((XING_FRAMES | XING_BYTES | XING_TOC).to_be_bytes());
+        tag.extend(frames.to_be_bytes());
+        tag.extend(((frames + 1) * 417).to_be_bytes());
+        tag.extend((0..100).map(|i| (i * 256 / 100) as u8));
+        tag.extend(b"LAME3.100");
+        tag.extend([0; 12]);
+        tag.extend([
+            (delay >> 4) as u8,
+            (delay << 4) as u8 | (padding >> 8) as u8,
+            padding as u8,
+        ]);
+        data[32..32 + tag.len()].copy_from_slice(&tag);
+
+        let mut frame = HEADER.to_vec();
+        frame.extend(data);
+        frame
+    }
+
+    fn frame(bytes: &[u8]) -> Frame {
+        Frame {
+            header: Header::parse(bytes[..4].try_into().unwrap()).unwrap(),
+            data: bytes[4..].to_vec(),
+        }
+    }
+
+    #[test]
+    fn xing() {
+        let info = Info::read(&frame(&info_frame(1000, 576, 1000))).expect("no Xing header");
+
+        assert_eq!(info.frames, Some(1000));
+        assert_eq!(info.bytes, Some(1001 * 417));
+
+        let lame = info.lame.expect("no LAME tag");
+        assert_eq!((lame.delay, lame.padding), (576, 1000));
+        assert_eq!(lame.trim(1152), 0);
+
+        let info = Info::read(&frame(&info_frame(1000, 1200, 2400))).expect("no Xing header");
+        let lame = info.lame.expect("no LAME tag");
+        assert_eq!((lame.delay, lame.padding), (1200, 2400));
+        assert_eq!(lame.trim(1152), 1);
+
+        assert_eq!(info.seek(0.), Some(0));
+        assert_eq!(info.seek(0.5), Some((128. / 256. * 1001. * 417.) as u64));
+        assert_eq!(info.seek(1.), Some(1001 * 417));
+    }
+
+    #[test]
+    fn vbri() {
+        let mut data = vec![0u8; 417 - 4];
+        let mut tag = Vec::new();
+        tag.extend(b"VBRI");
+        tag.extend([0, 1, 0, 0, 0, 75]);
+        tag.extend(4000u32.to_be_bytes());
+        tag.extend(40u32.to_be_bytes());
+        // 4 entries of 2 bytes, scale 10, 10 frames each
+        tag.extend([0, 4, 0, 10, 0, 2, 0, 10]);
+        tag.extend([0, 100, 0, 100, 0, 50, 0, 150]);
+        data[32..32 + tag.len()].copy_from_slice(&tag);
+
+        let mut bytes = HEADER.to_vec();
+        bytes.extend(data);
+
+        let info = Info::read(&frame(&bytes)).expect("no VBRI header");
+        assert_eq!(info.frames, Some(40));
+        assert_eq!(info.seek(0.), Some(0));
+        assert_eq!(info.seek(0.5), Some(2000));
+        assert_eq!(info.seek(0.625), Some(2250));
+        assert_eq!(info.seek(1.), Some(4000));
+
+        // entries of no bytes, or of more than fit in a u32
+        assert_eq!(bytes[4 + 32 + 23], 2);
+        for entry_size in [0, 5] {
+            bytes[4 + 32 + 23] = entry_size;
+            assert!(Info::read(&frame(&bytes)).is_none(), "{entry_size}");
+        }
+    }
+
+    #[test]
+    fn plain() {
+        assert!(Info::read(&frame(&[0xFF, 0xFB, 0x90, 0x00, 1, 2, 3])).is_none());
+        assert!(Info::read(&frame(&super::super::tests::frames(1))).is_none());
+    }
+}
@@ -1,68 +1,144 @@
 use self::data::{Layer, Version};
-use super::{Song, Codec};
-use crate::playlist::SongMetadata;
+use super::reader::{FrameHeader, FrameReader, READ_AHEAD};
+use super::{Codec, Packet, Packets, Song};
+use crate::{getter::Source, playlist::SongMetadata};
 use id3::Id3;
-use std::io::{self, BufRead, BufReader, Cursor, Read, Seek};
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use info::Info;
+pub use info::DECODER_DELAY;
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, Read, Seek},
+};
 
 mod data;
+mod info;
+
+/// Why four bytes aren't a frame header we can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// Doesn't start with the 11-bit sync word
+    Sync,
+    /// The reserved MPEG version
+    Version,
+    /// The reserved layer
+    Layer,
+    /// Bitrate index 0, "free format", which we can't find the frame size of
+    FreeFormat,
+    /// The reserved bitrate index
+    Bitrate,
+    /// The reserved sample rate index
+    SampleRate,
+}
 
-#[derive(Debug, Clone, Copy)]
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sync => write!(f, "no frame sync"),
+            Self::Version => write!(f, "reserved MPEG version"),
+            Self::Layer => write!(f, "reserved MPEG layer"),
+            Self::FreeFormat => write!(f, "free format bitrate is unsupported"),
+            Self::Bitrate => write!(f, "reserved bitrate index"),
+            Self::SampleRate => write!(f, "reserved sample rate index"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// A parsed frame header.  Can only be made with [`Header::parse`], so the fields are always
+/// valid.
+///
 /// [0]: 0b11111111 - first part of sync word
 /// [1]: 0b111vvllc where v is version, l is layer, c is error-protected
 /// [2]: 0bBBBBsspP where B is bitrate idx, s is sample idx, p is padding existence, and P is for private use
-pub struct Header([u8; 4]);
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    bytes: [u8; 4],
+    version: Version,
+    layer: Layer,
+    /// in b/s
+    bitrate: i64,
+    /// in Hz
+    sample_rate: i64,
+}
 
 impl std::ops::Deref for Header {
     type Target = [u8; 4];
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.bytes
     }
 }
 
 impl Header {
+    /// Parses a header, rejecting anything with a reserved or unsupported value.
+    pub const fn parse(bytes: [u8; 4]) -> Result<Self, HeaderError> {
+        if !(bytes[0] == 0xFF && bytes[1] & 0b11100000 == 0b11100000) {
+            return Err(HeaderError::Sync);
+        }
+
+        // no `?` in const fns
+        macro_rules! tri {
+            ($e:expr) => {
+                match $e {
+                    Ok(x) => x,
+                    Err(e) => return Err(e),
+                }
+            };
+        }
+
+        let version = tri!(data::get_version(bytes[1] >> 3));
+        let layer = tri!(data::get_layer(bytes[1] >> 1));
+        let bitrate = tri!(data::get_bitrate(version, layer, bytes[2] >> 4));
+        let sample_rate = tri!(data::get_sample_rate(version, bytes[2] >> 2));
+
+        Ok(Self {
+            bytes,
+            version,
+            layer,
+            bitrate: bitrate * 1000,
+            sample_rate,
+        })
+    }
+
+    /// Whether `other` could be the next frame in the same stream as this one.  The bitrate and
+    /// padding can change between frames, but the version, layer and sample rate can't.
     #[inline]
-    pub const fn sync(self) -> bool {
-        self.0[0] == 0xFF && self.0[1] & 0b11100000 == 0b11100000
+    pub const fn same_stream(self, other: Self) -> bool {
+        self.bytes[1] & 0b11111110 == other.bytes[1] & 0b11111110
+            && self.bytes[2] & 0b00001100 == other.bytes[2] & 0b00001100
     }
 
     #[inline]
     pub const fn version(self) -> Version {
-        match (self.0[1] >> 3) & 0b11 {
-            0b11 => Version::V1,
-            0b10 => Version::V2,
-            0b00 => Version::V2_5,
-			// invalid layer
-            _ =>  {
-				#[allow(unconditional_panic)]
-				[][1]
-			}
-        }
+        self.version
     }
 
     #[inline]
     pub const fn layer(self) -> Layer {
-        match (self.0[1] >> 1) & 0b11 {
-            0b11 => Layer::L1,
-            0b10 => Layer::L2,
-            0b01 => Layer::L3,
-			// invalid layer, can't panic in const fn
-            _ => {
-				#[allow(unconditional_panic)]
-				[][1]
-			}
-        }
+        self.layer
     }
 
     #[inline]
     pub const fn bitrate(self) -> i64 {
-        data::get_bitrate(self.version(), self.layer(), self.0[2] >> 4) * 1000
+        self.bitrate
     }
 
     #[inline]
-    pub fn sample_rate(self) -> i64 {
-        data::get_sample_rate(self.version(), (self[2] >> 2) & 0b11)
+    pub const fn sample_rate(self) -> i64 {
+        self.sample_rate
+    }
+
+    /// Whether the header is followed by a 16-bit CRC
+    #[inline]
+    pub fn protected(self) -> bool {
+        self[1] & 0b1 == 0
+    }
+
+    /// Whether the channel mode is single channel
+    #[inline]
+    pub fn mono(self) -> bool {
+        self[3] >> 6 == 0b11
     }
 
     #[inline]
@@ -75,12 +151,18 @@ impl Header {
         data::get_samples_per_frame(self.version(), self.layer())
     }
 
+    /// Size of the whole frame in bytes, including this header
     #[inline]
     pub fn frame_size(self) -> i64 {
+        let slots = data::get_size_coefficient(self.version(), self.layer()) * self.bitrate()
+            / self.sample_rate()
+            + self.padding() as i64;
+
         if self.layer() == Layer::L1 {
-            4 * (12 * self.bitrate() / self.sample_rate() + self.padding() as i64)
+            // layer I slots are 4 bytes
+            4 * slots
         } else {
-            144 * self.bitrate() / self.sample_rate() + self.padding() as i64
+            slots
         }
     }
 
@@ -90,84 +172,252 @@ impl Header {
     }
 }
 
+impl FrameHeader for Header {
+    const LEN: usize = 4;
+
+    #[inline]
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::parse(bytes.get(..4)?.try_into().ok()?).ok()
+    }
+
+    #[inline]
+    fn same_stream(self, other: Self) -> bool {
+        Header::same_stream(self, other)
+    }
+
+    #[inline]
+    fn frame_len(self) -> usize {
+        self.frame_size() as usize
+    }
+}
+
+/// Whether a frame, header and all, can be decoded without the frames before it.  Layer III frames
+/// can start their audio in the bytes left over at the end of earlier ones (the bit reservoir).
+pub fn standalone(frame: &[u8]) -> bool {
+    let Some(header) = Header::from_bytes(frame) else {
+        return false;
+    };
+    if header.layer() != Layer::L3 {
+        return true;
+    }
+
+    // main_data_begin, how many bytes back the audio starts, opens the side information: 9 bits
+    // of it for MPEG-1, 8 for the others
+    let at = Header::LEN + if header.protected() { 2 } else { 0 };
+    match (header.version(), frame.get(at..at + 2)) {
+        (Version::V1, Some(&[first, second])) => first == 0 && second & 0x80 == 0,
+        (_, Some(&[first, _])) => first == 0,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub header: Header,
     pub data: Vec<u8>,
 }
 
-impl Frame {
-    pub async fn write(&self, mut w: impl AsyncWrite + Unpin) -> io::Result<usize> {
-        Ok(w.write(&self.header[..]).await? + w.write(&self.data).await?)
+impl From<Frame> for Packet {
+    fn from(frame: Frame) -> Self {
+        let mut data = Vec::with_capacity(frame.header.len() + frame.data.len());
+        data.extend(&frame.header[..]);
+        data.extend(frame.data);
+
+        Packet {
+            data,
+            duration: frame.header.duration(),
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct Mp3;
+pub struct Mp3 {
+    /// Offset of the first frame, after any ID3 tags.  This is the Xing/VBRI frame, if there is
+    /// one.
+    first: u64,
+    /// Offset of the first audio frame
+    start: u64,
+    /// Offset of the end of the last frame
+    end: u64,
+    pub info: Option<Info>,
+    /// Frames of encoder padding to leave out at the end, for gapless playback
+    pub trim: usize,
+}
 
 impl Codec for Mp3 {
     const MIME_TYPE: &'static str = "audio/mpeg";
-}
 
-impl Song<Mp3> {
-    pub fn load(metadata: SongMetadata, data: impl Read + Seek) -> io::Result<Self> {
-        let mut reader = BufReader::new(data);
-        let mut data = Vec::new();
-        reader.read_to_end(&mut data)?;
-        reader.rewind()?;
+    fn load(metadata: SongMetadata, mut source: Source) -> io::Result<Song<Self>> {
+        let here = source.stream_position()?;
+        let len = source.seek(io::SeekFrom::End(0))?;
+        source.seek(io::SeekFrom::Start(here))?;
+
+        let mut reader = BufReader::with_capacity(READ_AHEAD, &mut source);
 
         let _id3 = Id3::read(&mut reader)?;
-        let duration = get_duration(&mut reader)?;
-        let mut source = reader.into_inner();
-        source.rewind()?;
+        let id3_end = reader.stream_position()?;
+
+        let mut frames = FrameReader::new(reader);
+
+        let Some(frame) = next_frame(&mut frames)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No MP3 frames found",
+            ));
+        };
+
+        let first = id3_end + frames.offset() - frame.header.frame_size() as u64;
+        let mut info = Info::read(&frame);
+
+        let (start, mut duration) = match &info {
+            // the info frame is silent, so don't count it
+            Some(_) => (id3_end + frames.offset(), 0.),
+            None => (first, frame.header.duration()),
+        };
+
+        let frame_count = info.as_ref().and_then(|info| info.frames);
+        // a corrupt byte count, or a download cut short, would put the end (and every seek)
+        // somewhere that isn't
+        if let Some(info) = &mut info {
+            info.bytes = info
+                .bytes
+                .filter(|&bytes| (start + 1..=len).contains(&(first + bytes as u64)));
+        }
+        let bytes = info.as_ref().and_then(|info| info.bytes);
+
+        let lame = info.as_ref().and_then(|info| info.lame);
+        let trim = lame.map_or(0, |lame| lame.trim(frame.header.samples() as u32));
+        if let Some(lame) = lame {
+            log::debug!(
+                "{} - {} has {} samples of encoder delay and {} of padding, {} frames of it left out",
+                metadata.title,
+                metadata.artist,
+                lame.delay,
+                lame.padding,
+                trim,
+            );
+        }
 
-        Ok(Self {
+        let end = if let (Some(n), Some(bytes)) = (frame_count, bytes) {
+            // the encoder already counted everything, so we don't have to read the whole file
+            duration = n as f64 * frame.header.duration();
+            first + bytes as u64
+        } else {
+            while let Some(header) = frames.skip_frame()? {
+                duration += header.duration();
+            }
+            id3_end + frames.offset()
+        };
+        // the length of what's played, so it matches the time it takes to send
+        duration = (duration - trim as f64 * frame.header.duration()).max(0.);
+
+        if frames.skipped > 0 {
+            log::warn!(
+                "Skipped {} bytes that aren't MP3 frames in {} - {}",
+                frames.skipped,
+                metadata.title,
+                metadata.artist,
+            );
+        }
+
+        Ok(Song {
             metadata,
-            data,
+            source,
             duration,
-            codec: Mp3,
+            gain: None,
+            cues: Default::default(),
+            codec: Mp3 {
+                first,
+                start,
+                end,
+                info,
+                trim,
+            },
         })
     }
 
-    pub fn frames(&self) -> impl Iterator<Item = Frame> + '_ {
-        let mut cursor = Cursor::new(&self.data);
+    fn packets(song: &mut Song<Self>) -> io::Result<Packets<'_>> {
+        Ok(Box::new(song.frames()?.map(Packet::from)))
+    }
+}
+
+impl Song<Mp3> {
+    /// Reads frames from the start of the song, holding at most about [`READ_AHEAD`] bytes of the
+    /// source in memory at a time.  Whole frames of encoder padding at the end are left out, so that
+    /// songs can be played back to back without gaps.
+    pub fn frames(&mut self) -> io::Result<impl Iterator<Item = Frame> + '_> {
+        self.source.seek(io::SeekFrom::Start(self.codec.start))?;
 
-        // skip ID3 tags
-        let _ = Id3::read(&mut cursor);
+        Ok(FrameIterator::new(&mut self.source, self.codec.trim))
+    }
 
-        // move to next 0xFF
-        for i in cursor.position() as usize..self.data.len() {
-            if self.data[i] == 0xFF {
-                cursor.set_position(i as u64);
-                break;
-            }
-        }
-        // if it gets to the end without finding seek word we have bigger problems
+    /// Finds the offset of the frame about `position` seconds into the song, using the Xing/VBRI
+    /// table of contents if there is one, or assuming a constant bitrate if not.
+    pub fn offset_of(&self, position: f64) -> u64 {
+        let Mp3 {
+            first,
+            start,
+            end,
+            ref info,
+            ..
+        } = self.codec;
+
+        let fraction = if self.duration > 0. {
+            (position / self.duration).clamp(0., 1.)
+        } else {
+            0.
+        };
+
+        let offset = match info.as_ref().and_then(|info| info.seek(fraction)) {
+            Some(offset) => first + offset,
+            None => start + (fraction * (end - start) as f64) as u64,
+        };
 
-        FrameIterator { cursor }
+        offset.clamp(start, end)
+    }
+
+    /// Like [`Song::frames`], but starting about `position` seconds into the song, without reading
+    /// the frames before it.  Also returns where the frames start, as near as the table of
+    /// contents or bitrate tells.
+    pub fn frames_from(
+        &mut self,
+        position: f64,
+    ) -> io::Result<(f64, impl Iterator<Item = Frame> + '_)> {
+        let offset = self.offset_of(position);
+        self.source.seek(io::SeekFrom::Start(offset))?;
+
+        let at = if offset > self.codec.start {
+            position.clamp(0., self.duration)
+        } else {
+            0.
+        };
+        Ok((at, FrameIterator::new(&mut self.source, self.codec.trim)))
     }
 }
+
+#[inline]
+fn next_frame<R: Read>(reader: &mut FrameReader<R, Header>) -> io::Result<Option<Frame>> {
+    Ok(reader
+        .next_frame()?
+        .map(|(header, data)| Frame { header, data }))
+}
+
 #[derive(Debug)]
 struct FrameIterator<R> {
-    cursor: R,
+    reader: FrameReader<R, Header>,
+    /// frames read ahead of the one being returned, so the last `trim_end` frames can be left
+    /// out without knowing where the song ends
+    pending: VecDeque<Frame>,
+    trim_end: usize,
 }
 
 impl<R: Read> FrameIterator<R> {
-    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
-        let mut header = [0u8; 4];
-
-        if self.cursor.read(&mut header)? != header.len() {
-            return Ok(None);
+    fn new(source: R, trim_end: usize) -> Self {
+        Self {
+            reader: FrameReader::new(source),
+            pending: VecDeque::with_capacity(trim_end + 1),
+            trim_end,
         }
-
-        let header = Header(header);
-
-        assert!(header.sync(), "Sync word not found");
-
-        let mut data = vec![0u8; header.frame_size() as usize - header.len()];
-        self.cursor.read_exact(&mut data)?;
-
-        Ok(Some(Frame { header, data }))
     }
 }
 
@@ -175,29 +425,291 @@ impl<R: Read> Iterator for FrameIterator<R> {
     type Item = Frame;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_frame().ok().flatten()
+        while self.pending.len() <= self.trim_end {
+            match next_frame(&mut self.reader) {
+                Ok(Some(frame)) => self.pending.push_back(frame),
+                Ok(None) => return None,
+                Err(e) => {
+                    log::error!("Error reading frame: {:?}", e);
+                    return None;
+                }
+            }
+        }
+
+        self.pending.pop_front()
     }
 }
 
-/// Gets the duration of an MP3 (with ID3 tags already read, if they exist)
-fn get_duration(mut source: impl BufRead + Seek) -> io::Result<f64> {
-    let mut duration = 0.;
-    let mut header = [0u8; 4];
-    // TODO - ignore ID3 footer.
+#[cfg(test)]
+pub(super) mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
 
-    let mut data = Vec::new();
-    source.read_until(0xFF, &mut data)?;
-    source.seek(io::SeekFrom::Current(-1))?;
+    /// MPEG-1 Layer III, 128 kb/s, 44.1 kHz, no padding: 417 bytes per frame
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
 
-    while source.read(&mut header)? == header.len() {
-        let header = Header(header);
-        assert!(header.sync(), "Sync word not found!");
+    /// `n` frames, where every data byte of frame `i` is `i`
+    pub(in crate::song) fn frames(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..n {
+            data.extend(HEADER);
+            data.extend(std::iter::repeat_n(i as u8, 417 - 4));
+        }
+        data
+    }
+
+    #[test]
+    fn headers() {
+        use Layer::*;
+        use Version::*;
+
+        // header, version, layer, bitrate, sample rate, frame size, samples per frame
+        let table = [
+            ([0xFF, 0xFB, 0x90, 0x00], V1, L3, 128_000, 44100, 417, 1152),
+            ([0xFF, 0xFB, 0x92, 0x00], V1, L3, 128_000, 44100, 418, 1152),
+            ([0xFF, 0xFD, 0x90, 0x00], V1, L2, 160_000, 44100, 522, 1152),
+            ([0xFF, 0xFF, 0x90, 0x00], V1, L1, 288_000, 44100, 312, 384),
+            ([0xFF, 0xFF, 0x92, 0x00], V1, L1, 288_000, 44100, 316, 384),
+            ([0xFF, 0xF3, 0x90, 0x00], V2, L3, 80_000, 22050, 261, 576),
+            ([0xFF, 0xF5, 0x90, 0x00], V2, L2, 80_000, 22050, 522, 1152),
+            ([0xFF, 0xF7, 0x90, 0x00], V2, L1, 144_000, 22050, 312, 384),
+            ([0xFF, 0xE3, 0x90, 0x00], V2_5, L3, 80_000, 11025, 522, 576),
+            ([0xFF, 0xE3, 0x18, 0xC4], V2_5, L3, 8_000, 8000, 72, 576),
+        ];
+
+        for (bytes, version, layer, bitrate, sample_rate, size, samples) in table {
+            let header = Header::parse(bytes).unwrap_or_else(|e| panic!("{bytes:02X?}: {e}"));
+            assert_eq!(header.version(), version, "{bytes:02X?}");
+            assert_eq!(header.layer(), layer, "{bytes:02X?}");
+            assert_eq!(header.bitrate(), bitrate, "{bytes:02X?}");
+            assert_eq!(header.sample_rate(), sample_rate, "{bytes:02X?}");
+            assert_eq!(header.frame_size(), size, "{bytes:02X?}");
+            assert_eq!(header.samples(), samples, "{bytes:02X?}");
+        }
+    }
 
-        duration += header.duration();
+    #[test]
+    fn standalone_frames() -> io::Result<()> {
+        let data = frames(3);
+        let mut frames = data.chunks(417);
+        // every byte of the first frame is 0, and of the next 1
+        assert!(standalone(frames.next().expect("3 frames")));
+        assert!(!standalone(frames.next().expect("3 frames")));
+        assert!(!standalone(&HEADER));
+
+        // the first frame of audio can't have anything to borrow from
+        let data = include_bytes!("testdata/mpeg1-l3-cbr.mp3");
+        let mut song = Song::<Mp3>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data.to_vec())),
+        )?;
+        let first = Packet::from(song.frames()?.next().expect("a frame"));
+        assert!(standalone(&first.data));
+        Ok(())
+    }
 
-        // skip header size
-        source.seek(io::SeekFrom::Current(header.frame_size() - 4))?;
+    #[test]
+    fn invalid_headers() {
+        let table = [
+            ([0x00, 0xFB, 0x90, 0x00], HeaderError::Sync),
+            ([0xFF, 0xEB, 0x90, 0x00], HeaderError::Version),
+            ([0xFF, 0xF9, 0x90, 0x00], HeaderError::Layer),
+            ([0xFF, 0xFB, 0x00, 0x00], HeaderError::FreeFormat),
+            ([0xFF, 0xFB, 0xF0, 0x00], HeaderError::Bitrate),
+            ([0xFF, 0xFB, 0x9C, 0x00], HeaderError::SampleRate),
+        ];
+
+        for (bytes, error) in table {
+            assert_eq!(Header::parse(bytes).err(), Some(error), "{bytes:02X?}");
+        }
     }
 
-    Ok(duration)
+    /// Real files encoded with LAME 3.100 from one second of audio.  The ones with an Info/Xing
+    /// frame know their length; the others are counted frame by frame.
+    #[test]
+    fn sample_files() -> io::Result<()> {
+        use Version::*;
+
+        // file, version, sample rate, samples per frame, frames (including any Info frame)
+        let table: [(&[u8], _, _, _, _); 5] = [
+            (
+                include_bytes!("testdata/mpeg1-l3-cbr.mp3"),
+                V1,
+                44100,
+                1152,
+                41,
+            ),
+            (
+                include_bytes!("testdata/mpeg1-l3-vbr.mp3"),
+                V1,
+                32000,
+                1152,
+                30,
+            ),
+            (
+                include_bytes!("testdata/mpeg2-l3-cbr.mp3"),
+                V2,
+                22050,
+                576,
+                41,
+            ),
+            (
+                include_bytes!("testdata/mpeg2-l3-vbr.mp3"),
+                V2,
+                24000,
+                576,
+                45,
+            ),
+            (
+                include_bytes!("testdata/mpeg2.5-l3-cbr.mp3"),
+                V2_5,
+                8000,
+                576,
+                16,
+            ),
+        ];
+
+        for (i, (data, version, sample_rate, samples, frames)) in table.into_iter().enumerate() {
+            let mut song = Song::<Mp3>::load(
+                song("title", "artist", None),
+                Source::Buffer(io::Cursor::new(data.to_vec())),
+            )?;
+            assert_eq!(song.codec.end, data.len() as u64, "{i}");
+
+            // nothing between the frames
+            let mut reader = FrameReader::<_, Header>::new(&data[song.codec.first as usize..]);
+            while reader.skip_frame()?.is_some() {}
+            assert_eq!(reader.skipped, 0, "{i}");
+
+            let info = song.codec.info.is_some() as usize;
+            let trim = song.codec.trim;
+            let duration = song.duration;
+
+            let mut played = 0;
+            for frame in song.frames()? {
+                assert_eq!(frame.header.version(), version, "{i}");
+                assert_eq!(frame.header.layer(), Layer::L3, "{i}");
+                assert_eq!(frame.header.sample_rate(), sample_rate, "{i}");
+                assert_eq!(
+                    frame.data.len() as i64 + 4,
+                    frame.header.frame_size(),
+                    "{i}"
+                );
+                played += 1;
+            }
+            assert_eq!(info + played + trim, frames, "{i}");
+
+            // exactly as long as what's played
+            let expected = (played * samples) as f64 / sample_rate as f64;
+            assert!(
+                (duration - expected).abs() < 1e-9,
+                "{i}: {duration} != {expected}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_frames() -> io::Result<()> {
+        // enough frames that headers straddle the read-ahead buffer boundaries
+        let n = 3 * READ_AHEAD / 417;
+        let mut song = Song::<Mp3>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(frames(n))),
+        )?;
+
+        assert!((song.duration - n as f64 * 1152. / 44100.).abs() < 1e-9);
+
+        let mut count = 0;
+        for (i, frame) in song.frames()?.enumerate() {
+            assert_eq!(*frame.header, HEADER);
+            assert!(frame.data.iter().all(|&b| b == i as u8));
+            count += 1;
+        }
+        assert_eq!(count, n);
+
+        // frames() starts over each time
+        assert_eq!(song.frames()?.count(), n);
+
+        Ok(())
+    }
+
+    #[test]
+    fn info_frame() -> io::Result<()> {
+        let n = 1000;
+        let mut data = info::tests::info_frame(n as u32, 576, 1000);
+        data.extend(frames(n));
+
+        // the info frame says there are 1000 frames, so there's no need to read them
+        let mut song = Song::<Mp3>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data)),
+        )?;
+        assert!((song.duration - n as f64 * 1152. / 44100.).abs() < 1e-9);
+        assert_eq!(song.codec.start, 417);
+
+        // the info frame isn't played
+        let first = song.frames()?.next().expect("no frames");
+        assert!(first.data.iter().all(|&b| b == 0));
+        assert_eq!(song.frames()?.count(), n);
+
+        // seeking uses the table of contents, then finds the next frame
+        assert_eq!(song.offset_of(0.), 417);
+        assert_eq!(song.offset_of(song.duration), 1001 * 417);
+        let half = song.duration / 2.;
+        let (at, mut frames) = song.frames_from(half)?;
+        let frame = frames.next().expect("no frames");
+        assert_eq!(frame.data[0], (500u32 % 256) as u8);
+        assert_eq!(at, half);
+        drop(frames);
+        assert_eq!(song.frames_from(0.)?.0, 0.);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_byte_count() -> io::Result<()> {
+        let n = 100;
+        let info = info::tests::info_frame(n as u32, 576, 1000);
+
+        // too few bytes to hold a frame, and more bytes than the file has
+        for bytes in [0u32, 417, 1000 * 417] {
+            let mut data = info.clone();
+            data[48..52].copy_from_slice(&bytes.to_be_bytes());
+            data.extend(frames(n));
+
+            let song = Song::<Mp3>::load(
+                song("title", "artist", None),
+                Source::Buffer(io::Cursor::new(data)),
+            )?;
+            // counted instead
+            assert_eq!(song.codec.end, (n as u64 + 1) * 417, "{bytes}");
+            assert_eq!(song.offset_of(song.duration), song.codec.end, "{bytes}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn gapless() -> io::Result<()> {
+        let n = 100;
+        let mut data = info::tests::info_frame(n as u32, 1200, 2400);
+        data.extend(frames(n));
+
+        let mut song = Song::<Mp3>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data)),
+        )?;
+        assert_eq!(song.codec.trim, 1);
+        // only as long as what's played
+        assert!((song.duration - (n - 1) as f64 * 1152. / 44100.).abs() < 1e-9);
+
+        // the delay is kept, for the bit reservoir
+        let frames: Vec<_> = song.frames()?.map(|frame| frame.data[0]).collect();
+        assert_eq!(frames, (0..n as u8 - 1).collect::<Vec<_>>());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,377 @@
+//! Ogg pages holding a Vorbis or Opus stream.  Pages are sent whole, so chaining songs one after
+//! another makes a valid chained Ogg stream.
+//!
+//! see: https://www.xiph.org/ogg/doc/framing.html
+
+use std::io::{self, BufReader, Read, Seek};
+
+use crate::{getter::Source, playlist::SongMetadata};
+
+use super::{parse_comments, reader::READ_AHEAD, Codec, Packet, Packets, Song};
+
+/// Size of a page header, not counting the segment table
+const HEADER_SIZE: usize = 27;
+
+/// Header type flag for the first page of a logical stream
+const BEGINNING_OF_STREAM: u8 = 0x02;
+
+/// Opus granule positions always count samples at 48 kHz
+const OPUS_RATE: u32 = 48000;
+
+/// How much of a stream to read looking for the end of its comment header, which can hold cover
+/// art
+const MAX_COMMENTS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Vorbis,
+    Opus,
+}
+
+#[derive(Debug)]
+pub struct Ogg {
+    pub kind: Kind,
+    /// Serial number of the audio stream.  Pages of any other stream are passed through, but not
+    /// counted towards the duration.
+    serial: u32,
+    /// Rate of the granule position clock, in Hz
+    rate: u32,
+    /// Samples to throw away at the start (Opus only)
+    pre_skip: u64,
+}
+
+#[derive(Debug)]
+struct Page {
+    header_type: u8,
+    /// Samples decoded by the end of this page, or `None` if no packet ends on it
+    granule: Option<u64>,
+    serial: u32,
+    /// The whole page, including its header
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn body(&self) -> &[u8] {
+        let segments = self.data[HEADER_SIZE - 1] as usize;
+        &self.data[HEADER_SIZE + segments..]
+    }
+}
+
+/// Reads the next page, skipping anything before it that isn't one.  Returns `None` at the end of
+/// the source, or if it ends partway through a page.
+fn read_page(r: &mut impl Read) -> io::Result<Option<Page>> {
+    let mut header = [0u8; HEADER_SIZE];
+    if !read_full(r, &mut header[..4])? {
+        return Ok(None);
+    }
+
+    // lost sync: shift a byte at a time until the capture pattern lines up
+    while &header[..4] != b"OggS" {
+        header.copy_within(1..4, 0);
+        if !read_full(r, &mut header[3..4])? {
+            return Ok(None);
+        }
+    }
+
+    if !read_full(r, &mut header[4..])? {
+        return Ok(None);
+    }
+
+    let segments = header[HEADER_SIZE - 1] as usize;
+    let mut data = Vec::with_capacity(HEADER_SIZE + segments + 255 * segments);
+    data.extend(header);
+    data.resize(HEADER_SIZE + segments, 0);
+    if !read_full(r, &mut data[HEADER_SIZE..])? {
+        return Ok(None);
+    }
+
+    let body: usize = data[HEADER_SIZE..].iter().map(|&len| len as usize).sum();
+    let start = data.len();
+    data.resize(start + body, 0);
+    if !read_full(r, &mut data[start..])? {
+        return Ok(None);
+    }
+
+    let granule = i64::from_le_bytes(header[6..14].try_into().expect("8 bytes"));
+
+    Ok(Some(Page {
+        header_type: header[5],
+        // -1 means no packet finishes on this page
+        granule: u64::try_from(granule).ok(),
+        serial: u32::from_le_bytes(header[14..18].try_into().expect("4 bytes")),
+        data,
+    }))
+}
+
+/// Like `read_exact`, but returns `false` instead of an error at the end of the source.
+fn read_full(r: &mut impl Read, mut buf: &mut [u8]) -> io::Result<bool> {
+    while !buf.is_empty() {
+        match r.read(buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+impl Ogg {
+    /// Reads the identification header at the start of a logical stream.
+    fn identify(page: &Page) -> Option<Self> {
+        let body = page.body();
+
+        if body.starts_with(b"\x01vorbis") {
+            Some(Self {
+                kind: Kind::Vorbis,
+                serial: page.serial,
+                rate: u32::from_le_bytes(body.get(12..16)?.try_into().ok()?),
+                pre_skip: 0,
+            })
+        } else if body.starts_with(b"OpusHead") {
+            Some(Self {
+                kind: Kind::Opus,
+                serial: page.serial,
+                rate: OPUS_RATE,
+                pre_skip: u16::from_le_bytes(body.get(10..12)?.try_into().ok()?) as u64,
+            })
+        } else {
+            None
+        }
+        .filter(|ogg| ogg.rate > 0)
+    }
+}
+
+/// Reads the Vorbis comments from the comment header (the second packet) of the first Vorbis or
+/// Opus stream.
+pub(super) fn comments(r: impl Read) -> io::Result<Vec<(String, String)>> {
+    let mut reader = BufReader::with_capacity(READ_AHEAD, r);
+
+    let mut stream = None;
+    let mut packet = Vec::new();
+    while let Some(page) = read_page(&mut reader)? {
+        let Some(ogg) = &stream else {
+            if page.header_type & BEGINNING_OF_STREAM != 0 {
+                stream = Ogg::identify(&page);
+            }
+            continue;
+        };
+        if page.serial != ogg.serial {
+            continue;
+        }
+
+        // it can go on over several pages
+        packet.extend(page.body());
+        let magic: &[u8] = match ogg.kind {
+            Kind::Vorbis => b"\x03vorbis",
+            Kind::Opus => b"OpusTags",
+        };
+        if packet.len() >= magic.len() && !packet.starts_with(magic) {
+            break;
+        }
+        if let Some(comments) = packet.get(magic.len()..).and_then(parse_comments) {
+            return Ok(comments);
+        }
+        if packet.len() > MAX_COMMENTS {
+            break;
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+impl Codec for Ogg {
+    const MIME_TYPE: &'static str = "audio/ogg";
+
+    fn load(metadata: SongMetadata, mut source: Source) -> io::Result<Song<Self>> {
+        let mut reader = BufReader::with_capacity(READ_AHEAD, &mut source);
+
+        let mut codec = None;
+        while let Some(page) = read_page(&mut reader)? {
+            if page.header_type & BEGINNING_OF_STREAM != 0 {
+                codec = Ogg::identify(&page);
+                if codec.is_some() {
+                    break;
+                }
+            }
+        }
+
+        let Some(codec) = codec else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No Vorbis or Opus stream found",
+            ));
+        };
+
+        // the last granule position is the length of the whole stream
+        let mut end = 0;
+        while let Some(page) = read_page(&mut reader)? {
+            if page.serial == codec.serial {
+                end = page.granule.unwrap_or(end);
+            }
+        }
+
+        drop(reader);
+        log::debug!(
+            "{:?} stream in {} - {}",
+            codec.kind,
+            metadata.title,
+            metadata.artist
+        );
+
+        Ok(Song {
+            metadata,
+            source,
+            duration: end.saturating_sub(codec.pre_skip) as f64 / codec.rate as f64,
+            gain: None,
+            cues: Default::default(),
+            codec,
+        })
+    }
+
+    fn packets(song: &mut Song<Self>) -> io::Result<Packets<'_>> {
+        song.source.rewind()?;
+
+        let Ogg {
+            serial,
+            rate,
+            pre_skip,
+            ..
+        } = song.codec;
+        let mut reader = BufReader::with_capacity(READ_AHEAD, &mut song.source);
+        let mut last = pre_skip;
+
+        Ok(Box::new(std::iter::from_fn(move || {
+            let page = match read_page(&mut reader) {
+                Ok(page) => page?,
+                Err(e) => {
+                    log::error!("Error reading page: {:?}", e);
+                    return None;
+                }
+            };
+
+            let mut duration = 0.;
+            if let Some(granule) = page.granule.filter(|_| page.serial == serial) {
+                duration = granule.saturating_sub(last) as f64 / rate as f64;
+                last = last.max(granule);
+            }
+
+            Some(Packet {
+                data: page.data,
+                duration,
+            })
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    fn page(header_type: u8, granule: i64, serial: u32, body: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend(granule.to_le_bytes());
+        page.extend(serial.to_le_bytes());
+        page.extend([0; 8]);
+
+        let mut lacing: Vec<u8> = std::iter::repeat_n(255, body.len() / 255).collect();
+        lacing.push((body.len() % 255) as u8);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(body);
+        page
+    }
+
+    #[test]
+    fn opus() -> io::Result<()> {
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend(312u16.to_le_bytes());
+        head.extend(48000u32.to_le_bytes());
+
+        let mut data = page(BEGINNING_OF_STREAM, 0, 7, &head);
+        data.extend(page(0, 0, 7, b"OpusTags"));
+        data.extend(page(0, 312 + 48000, 7, &[1; 300]));
+        // junk between pages, and a page that doesn't end a packet
+        data.extend([1, 2, 3]);
+        data.extend(page(0, -1, 7, &[2; 10]));
+        data.extend(page(0, 312 + 72000, 7, &[3; 100]));
+
+        let mut song = Song::<Ogg>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data)),
+        )?;
+        assert_eq!(song.codec.kind, Kind::Opus);
+        assert!((song.duration - 1.5).abs() < 1e-9);
+
+        let packets: Vec<_> = song.packets()?.collect();
+        assert_eq!(packets.len(), 5);
+        assert_eq!(
+            packets.iter().map(|p| p.duration).collect::<Vec<_>>(),
+            [0., 0., 1., 0., 0.5]
+        );
+        assert!(packets.iter().all(|p| p.data.starts_with(b"OggS")));
+
+        // packets() starts over each time
+        assert_eq!(song.packets()?.count(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tags() -> io::Result<()> {
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend(3u32.to_le_bytes());
+        tags.extend(b"lib");
+        tags.extend(2u32.to_le_bytes());
+        for comment in ["R128_TRACK_GAIN=-512", "ARTIST=artist"] {
+            tags.extend((comment.len() as u32).to_le_bytes());
+            tags.extend(comment.as_bytes());
+        }
+
+        let mut data = page(BEGINNING_OF_STREAM, 0, 7, b"OpusHead\x01\x01\0\0");
+        // another stream, and the comments split over two pages
+        data.extend(page(BEGINNING_OF_STREAM, 0, 8, b"junk"));
+        data.extend(page(0, 0, 7, &tags[..20]));
+        data.extend(page(0, 0, 8, b"junk"));
+        data.extend(page(0, 0, 7, &tags[20..]));
+        data.extend(page(0, 48000, 7, &[1; 300]));
+
+        let mut source = io::Cursor::new(data);
+        assert_eq!(
+            crate::song::comments(&mut source)?,
+            [
+                ("R128_TRACK_GAIN".to_owned(), "-512".to_owned()),
+                ("ARTIST".to_owned(), "artist".to_owned())
+            ]
+        );
+        assert_eq!(source.position(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vorbis() -> io::Result<()> {
+        let mut id = b"\x01vorbis".to_vec();
+        id.extend(0u32.to_le_bytes());
+        id.push(2);
+        id.extend(44100u32.to_le_bytes());
+        id.extend([0; 13]);
+
+        let mut data = page(BEGINNING_OF_STREAM, 0, 1, &id);
+        data.extend(page(0, 0, 1, b"\x03vorbis"));
+        data.extend(page(0, 44100 * 3, 1, &[1; 600]));
+
+        let song = Song::<Ogg>::load(
+            song("title", "artist", None),
+            Source::Buffer(io::Cursor::new(data)),
+        )?;
+        assert_eq!(song.codec.kind, Kind::Vorbis);
+        assert!((song.duration - 3.).abs() < 1e-9);
+
+        Ok(())
+    }
+}
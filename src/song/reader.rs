@@ -0,0 +1,227 @@
+//! Finds MP3 or ADTS frames in a stream of bytes, skipping over anything that isn't one
+//! (corrupted bytes, trailing ID3v1 or APE tags, etc.)
+
+use std::io::{self, Read};
+
+/// How much of the source to read at a time
+pub(super) const READ_AHEAD: usize = 1 << 16;
+
+/// The header at the start of each frame of a format where frames know their own size
+pub(super) trait FrameHeader: Copy {
+    /// Bytes in the header
+    const LEN: usize;
+
+    /// Parses a header from the first `LEN` bytes, if they are one.
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+
+    /// Whether `other` could be the next frame in the same stream as this one
+    fn same_stream(self, other: Self) -> bool;
+
+    /// Size of the whole frame in bytes, including the header
+    fn frame_len(self) -> usize;
+}
+
+#[derive(Debug)]
+pub(super) struct FrameReader<R, H> {
+    source: R,
+    buf: Vec<u8>,
+    /// start of the unread part of `buf`
+    pos: usize,
+    /// the last frame read, if the following bytes are expected to be another frame of the same
+    /// stream
+    synced: Option<H>,
+    /// offset of `buf[pos]` from where the reader started
+    offset: u64,
+    /// number of bytes thrown away because they weren't part of a frame
+    pub skipped: u64,
+}
+
+impl<R: Read, H: FrameHeader> FrameReader<R, H> {
+    pub fn new(source: R) -> Self {
+        Self {
+            source,
+            buf: Vec::with_capacity(READ_AHEAD),
+            pos: 0,
+            synced: None,
+            offset: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Offset of the next unread byte from where the reader started
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    fn available(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Tries to buffer at least `n` unread bytes.  Returns `false` if the source ends first.
+    fn fill(&mut self, n: usize) -> io::Result<bool> {
+        if self.available().len() >= n {
+            return Ok(true);
+        }
+
+        // move the unread bytes to the front so the buffer doesn't grow forever
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+
+        while self.buf.len() < n {
+            let len = self.buf.len();
+            self.buf.resize(len + READ_AHEAD.max(n - len), 0);
+
+            let read = match self.source.read(&mut self.buf[len..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            };
+
+            self.buf.truncate(len + read);
+
+            if read == 0 {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+        self.offset += n as u64;
+    }
+
+    /// Throws away bytes until the next possible sync word.
+    fn skip(&mut self) {
+        let n = self.available()[1..]
+            .iter()
+            .position(|&b| b == 0xFF)
+            .map_or(self.available().len(), |i| i + 1);
+
+        self.consume(n);
+        self.skipped += n as u64;
+        self.synced = None;
+    }
+
+    /// Finds the next frame and returns its header, leaving the whole frame at the front of the
+    /// buffer.
+    fn next_header(&mut self) -> io::Result<Option<H>> {
+        loop {
+            if !self.fill(H::LEN)? {
+                // a few bytes of junk at the end
+                let rest = self.available().len();
+                self.consume(rest);
+                self.skipped += rest as u64;
+                return Ok(None);
+            }
+
+            let Some(header) = H::from_bytes(self.available()) else {
+                self.skip();
+                continue;
+            };
+
+            if self.synced.is_some_and(|prev| !prev.same_stream(header)) {
+                self.skip();
+                continue;
+            }
+
+            let size = header.frame_len();
+
+            if !self.fill(size)? {
+                // truncated last frame
+                self.skip();
+                continue;
+            }
+
+            if self.synced.is_none() && self.fill(size + H::LEN)? {
+                // after losing sync, a 0xFF could be anything, so make sure the next frame
+                // lines up too.  At the end of the file, there is nothing to check against.
+                let next = H::from_bytes(&self.available()[size..]);
+
+                if !next.is_some_and(|next| header.same_stream(next)) {
+                    self.skip();
+                    continue;
+                }
+            }
+
+            self.synced = Some(header);
+            return Ok(Some(header));
+        }
+    }
+
+    /// Reads the next frame, returning its header and the data after the header.
+    pub fn next_frame(&mut self) -> io::Result<Option<(H, Vec<u8>)>> {
+        let Some(header) = self.next_header()? else {
+            return Ok(None);
+        };
+
+        let size = header.frame_len();
+        let data = self.available()[H::LEN..size].to_vec();
+        self.consume(size);
+
+        Ok(Some((header, data)))
+    }
+
+    /// Like `next_frame`, but doesn't copy out the frame's data.
+    pub fn skip_frame(&mut self) -> io::Result<Option<H>> {
+        let header = self.next_header()?;
+        if let Some(header) = header {
+            self.consume(header.frame_len());
+        }
+
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::mp3::{tests::frames, Header};
+
+    fn count(data: &[u8]) -> (usize, u64) {
+        let mut reader = FrameReader::<_, Header>::new(data);
+        let mut n = 0;
+        while reader.skip_frame().unwrap().is_some() {
+            n += 1;
+        }
+        (n, reader.skipped)
+    }
+
+    #[test]
+    fn clean() {
+        assert_eq!(count(&frames(10)), (10, 0));
+        assert_eq!(count(&[]), (0, 0));
+    }
+
+    #[test]
+    fn junk() {
+        // junk at the start, including something that looks like a header
+        let mut data = vec![0, 1, 0xFF, 0xFB, 0x90, 0x00, 2, 3];
+        data.extend(frames(5));
+        assert_eq!(count(&data), (5, 8));
+
+        // a corrupted byte in the middle of a header loses that frame only
+        let mut data = frames(5);
+        data[417 * 2 + 1] = 0;
+        assert_eq!(count(&data), (4, 417));
+
+        // ID3v1 tag at the end
+        let mut data = frames(5);
+        let mut tag = [0u8; 128];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[50..54].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        data.extend(tag);
+        assert_eq!(count(&data), (5, 128));
+
+        // truncated last frame
+        let mut data = frames(5);
+        data.truncate(417 * 4 + 100);
+        assert_eq!(count(&data), (4, 100));
+    }
+}
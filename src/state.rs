@@ -0,0 +1,198 @@
+//! Saving what the station is doing, so that after a restart it carries on with the same queue,
+//! from about where it was in the same song, instead of scraping and downloading everything again.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config,
+    playlist::{Entry, Playlist, SongMetadata},
+    runner::{Current, Played},
+};
+
+/// Writes `data` to a file next to `path` first, then moves it over `path`, so a crash while
+/// saving can't leave half of it behind.
+pub async fn replace(path: &Path, data: Vec<u8>) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(partial, path).await
+}
+
+/// A queued song, and the file it was fetched to, if it was, so it isn't fetched again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Saved {
+    #[serde(flatten)]
+    pub metadata: SongMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+impl Saved {
+    fn entry(self) -> Entry {
+        Entry::new(SongMetadata {
+            path: self.path,
+            ..self.metadata
+        })
+    }
+}
+
+/// The song that was playing, and how far into it the station was
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resume {
+    #[serde(flatten)]
+    pub saved: Saved,
+    pub offset: f64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub current: Option<Resume>,
+    pub queue: Vec<Saved>,
+    /// Oldest first
+    pub history: Vec<Played>,
+}
+
+impl Snapshot {
+    pub async fn take(current: &Current, playlist: &Mutex<Playlist>) -> Self {
+        let song = current.song.read().await.clone();
+        let timing = *current.timing.read().await;
+        let history = current.history.read().await.iter().cloned().collect();
+
+        Self {
+            current: song.zip(timing).map(|(metadata, timing)| Resume {
+                saved: Saved {
+                    path: metadata.path.clone(),
+                    metadata,
+                },
+                offset: timing.elapsed(),
+            }),
+            queue: playlist
+                .lock()
+                .expect("Error locking playlist to save")
+                .iter()
+                .map(|entry| Saved {
+                    metadata: entry.metadata.clone(),
+                    path: entry.path(),
+                })
+                .collect(),
+            history,
+        }
+    }
+
+    /// Puts the saved queue in front of anything already queued, and the song that was playing in
+    /// front of that.
+    pub async fn restore(self, current: &Current, playlist: &Mutex<Playlist>) {
+        *current.history.write().await = self.history.into();
+
+        let mut playlist = playlist.lock().expect("Error locking playlist to restore");
+
+        let restored: Vec<_> = self
+            .current
+            .map(|resume| {
+                let mut entry = resume.saved.entry();
+                entry.start = resume.offset;
+                entry
+            })
+            .into_iter()
+            .chain(self.queue.into_iter().map(Saved::entry))
+            .collect();
+
+        // the playlist sources have already queued the songs that are still in them
+        playlist.retain(|queued| {
+            let track = queued.metadata.track_id();
+            !restored
+                .iter()
+                .any(|entry| entry.metadata.track_id() == track)
+        });
+
+        for entry in restored.into_iter().rev() {
+            playlist.push_front(entry);
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(self).expect("Error serializing state");
+        replace(path, json).await
+    }
+
+    /// `None` if nothing was saved yet
+    pub async fn load(path: &Path) -> io::Result<Option<Self>> {
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Saves a snapshot every `interval`, forever.
+pub async fn run_loop(
+    config: config::State,
+    current: Arc<Current>,
+    playlist: Arc<Mutex<Playlist>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+    // the first tick is immediate, and there's nothing to save yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let snapshot = Snapshot::take(&current, &playlist).await;
+        if let Err(e) = snapshot.save(&config.path).await {
+            log::error!("Error saving state to {}: {}", config.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::playlist::tests::song;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn restore() {
+        let sender = lighthouse::Sender::new();
+        let current = Current::new(sender.subscribe());
+        let playlist = Mutex::new(Playlist::from([
+            Entry::new(song("b", "Artist", None)),
+            Entry::new(song("d", "Artist", None)),
+        ]));
+
+        let snapshot: Snapshot = serde_json::from_str(
+            r#"{
+                "current": {"title": "a", "artist": "Artist", "offset": 61.5, "path": "/a.mp3"},
+                "queue": [{"title": "b", "artist": "Artist"}, {"title": "c", "artist": "Artist"}],
+                "history": [{
+                    "id": 1, "track": "00000000000000ff", "title": "z", "artist": "Artist",
+                    "started": 1700000000, "duration": 100, "played": 100, "skipped": false
+                }]
+            }"#,
+        )
+        .unwrap();
+        snapshot.restore(&current, &playlist).await;
+
+        let playlist = playlist.into_inner().unwrap();
+        let titles: Vec<_> = playlist
+            .iter()
+            .map(|entry| &*entry.metadata.title)
+            .collect();
+        assert_eq!(titles, ["a", "b", "c", "d"]);
+        assert_eq!(playlist[0].start, 61.5);
+        assert_eq!(playlist[0].path(), Some("/a.mp3".into()));
+        assert_eq!(playlist[1].start, 0.);
+        assert_eq!(playlist[1].path(), None);
+        assert_eq!(current.history.read().await[0].metadata.title, "z");
+    }
+}
@@ -0,0 +1,435 @@
+//! Converts songs to the station's format, bitrate, sample rate and channels with ffmpeg, so that
+//! players don't see the stream's parameters change between songs.
+//!
+//! Songs are converted when they are fetched rather than while they play: with `lookahead`, that
+//! happens in the background well before the song is needed, and the converted song's length is
+//! known exactly before it starts.
+//!
+//! It also mixes the end of each song into the start of the next for crossfades, which are only
+//! known once the next song is.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    path::PathBuf,
+    process::{ExitStatus, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    process::{ChildStdin, Command},
+    sync::mpsc,
+};
+
+use crate::{
+    config::{self, Curve},
+    getter::Source,
+    song::{mp3, Format},
+};
+
+/// How much of the source to send to ffmpeg at a time
+const CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    pub config: config::Transcode,
+    pub format: Format,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ffmpeg(ExitStatus),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO Error: {e}"),
+            Error::Ffmpeg(status) => write!(f, "ffmpeg failed: {status}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A file in the temporary directory that nothing else is using, with the extension `ext`
+pub fn temp_path(ext: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    std::env::temp_dir().join(format!(
+        "sandy-{}-{}.{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        ext
+    ))
+}
+
+/// Writes the whole source to a subprocess, then closes its stdin.  Stops early, without an
+/// error, if the subprocess stops reading: its exit status says why.  The source is handed back
+/// either way.
+pub async fn feed(mut stdin: ChildStdin, mut source: Source) -> (Source, io::Result<()>) {
+    let (sx, mut rx) = mpsc::channel(2);
+
+    // reading a file blocks, so it's read on a blocking thread, a couple of chunks ahead
+    let reader = tokio::task::spawn_blocking(move || {
+        loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let chunk = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => {
+                    buf.truncate(read);
+                    Ok(buf)
+                }
+                Err(e) => Err(e),
+            };
+
+            let failed = chunk.is_err();
+            // the other end stops receiving if the subprocess stops reading
+            if sx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+        source
+    });
+
+    let mut result = Ok(());
+    while let Some(chunk) = rx.recv().await {
+        let written = match chunk {
+            Ok(chunk) => stdin.write_all(&chunk).await,
+            Err(e) => Err(e),
+        };
+
+        match written {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    drop(rx);
+    drop(stdin);
+
+    let source = reader.await.expect("Error reading source");
+    (source, result)
+}
+
+impl Transcoder {
+    /// ffmpeg arguments to encode in the station's format.  The output path goes at the end.
+    fn output_args(&self) -> Vec<String> {
+        let config::Transcode {
+            bitrate,
+            sample_rate,
+            channels,
+            ..
+        } = self.config;
+
+        let (codec, muxer) = match self.format {
+            Format::Mp3 => ("libmp3lame", "mp3"),
+            Format::Ogg => ("libvorbis", "ogg"),
+            Format::Aac => ("aac", "adts"),
+            Format::Flac => ("flac", "flac"),
+        };
+
+        let mut args: Vec<String> = vec!["-c:a".into(), codec.into()];
+
+        if self.format != Format::Flac {
+            args.extend(["-b:a".into(), format!("{bitrate}k")]);
+        }
+
+        args.extend([
+            "-ar".into(),
+            sample_rate.to_string(),
+            "-ac".into(),
+            channels.to_string(),
+            "-f".into(),
+            muxer.into(),
+            "-y".into(),
+        ]);
+
+        args
+    }
+
+    /// ffmpeg arguments to read a song from stdin, turn it up by `gain` dB, and write it in the
+    /// station's format.  The output path goes at the end.
+    fn args(&self, gain: Option<f64>) -> Vec<String> {
+        let mut args: Vec<String> = [
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-i",
+            "pipe:0",
+            // cover art shows up as a video stream
+            "-vn",
+            "-map_metadata",
+            "-1",
+        ]
+        .map(String::from)
+        .into();
+
+        let mut filters = Vec::new();
+        if let Some(gain) = gain {
+            filters.push(format!("volume={gain:.2}dB"));
+            if gain > 0. {
+                // for songs whose peak isn't known, so the gain couldn't be capped by it
+                filters.push("alimiter=limit=1:level=0".into());
+            }
+        }
+        if !filters.is_empty() {
+            args.extend(["-af".into(), filters.join(",")]);
+        }
+
+        args.extend(self.output_args());
+        args
+    }
+
+    /// Feeds the song through ffmpeg into a temporary file and returns that instead.  The file
+    /// is deleted as soon as it's open, so it goes away with the song.
+    pub async fn transcode(&self, source: Source, gain: Option<f64>) -> Result<Source, Error> {
+        let path = temp_path(self.format.ext());
+
+        let mut child = Command::new(&self.config.ffmpeg)
+            .args(self.args(gain))
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let (_, fed) = feed(child.stdin.take().expect("stdin is piped"), source).await;
+        fed?;
+
+        let status = child.wait().await?;
+        let result = if status.success() {
+            std::fs::File::open(&path)
+                .map(Source::File)
+                .map_err(Error::from)
+        } else {
+            Err(Error::Ffmpeg(status))
+        };
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Error removing {}: {:?}", path.display(), e);
+            }
+        }
+
+        result
+    }
+
+    /// Samples in each of the station's frames
+    fn frame_samples(&self) -> u32 {
+        match self.format {
+            Format::Mp3 if self.config.sample_rate < 32000 => 576,
+            Format::Mp3 => 1152,
+            _ => 1024,
+        }
+    }
+
+    /// Samples that encoding delays the audio by, which players can't know to cut out of a
+    /// stream: the encoder's priming, and for MP3, the decoder's delay too
+    fn delay(&self) -> u32 {
+        match self.format {
+            Format::Mp3 => 576 + mp3::DECODER_DELAY,
+            _ => 1024,
+        }
+    }
+
+    /// How many frames at the start of a mix are only there for the delay.  The mix proper starts
+    /// right on the frame after them, so leaving them out splices it in without a gap.
+    pub fn primed(&self) -> usize {
+        self.delay().div_ceil(self.frame_samples()) as usize
+    }
+
+    /// Fades `tail`, the end of one song, into `head`, the start of the next, over the whole of
+    /// `tail` after its first `preroll` seconds, which are only there for the decoder to warm up
+    /// on.  Both are packets in the station's format, and so is the mix, which starts with
+    /// [`Transcoder::primed`] frames to leave out.
+    pub async fn crossfade(
+        &self,
+        tail: &[u8],
+        preroll: f64,
+        head: &[u8],
+        overlap: f64,
+        curve: Curve,
+    ) -> Result<Source, Error> {
+        let demuxer = match self.format {
+            Format::Mp3 => "mp3",
+            Format::Aac => "aac",
+            format => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{format} can't be spliced"),
+                )))
+            }
+        };
+
+        let inputs = [temp_path(self.format.ext()), temp_path(self.format.ext())];
+        let output = temp_path(self.format.ext());
+
+        let result = async {
+            tokio::fs::write(&inputs[0], tail).await?;
+            tokio::fs::write(&inputs[1], head).await?;
+
+            let curve = curve.ffmpeg();
+            // lines the mix up with the end of the frames that only hold the delay
+            let pad = self.primed() as u32 * self.frame_samples() - self.delay();
+            let status = Command::new(&self.config.ffmpeg)
+                .args(["-hide_banner", "-loglevel", "error"])
+                .args(["-f", demuxer, "-i"])
+                .arg(&inputs[0])
+                .args(["-f", demuxer, "-i"])
+                .arg(&inputs[1])
+                .arg("-filter_complex")
+                .arg(format!(
+                    "[0:a]atrim=start={preroll:.6},asetpts=PTS-STARTPTS[tail];\
+                     [tail][1:a]acrossfade=d={overlap:.6}:c1={curve}:c2={curve},\
+                     adelay=delays={pad}S:all=1"
+                ))
+                .args(self.output_args())
+                // the mix is spliced into the stream, where a header frame would be a gap, and
+                // where the frame before it isn't the mix's own to borrow bits from
+                .args(match self.format {
+                    Format::Mp3 => &["-write_xing", "0", "-reservoir", "0"][..],
+                    _ => &[],
+                })
+                .arg(&output)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .status()
+                .await?;
+
+            if status.success() {
+                Ok(Source::Buffer(io::Cursor::new(
+                    tokio::fs::read(&output).await?,
+                )))
+            } else {
+                Err(Error::Ffmpeg(status))
+            }
+        }
+        .await;
+
+        for path in inputs.iter().chain([&output]) {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!("Error removing {}: {:?}", path.display(), e);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Curve {
+    /// The name of the curve to ffmpeg's `acrossfade`
+    fn ffmpeg(self) -> &'static str {
+        match self {
+            Curve::Linear => "tri",
+            Curve::EqualPower => "qsin",
+            Curve::HalfSine => "hsin",
+            Curve::Logarithmic => "log",
+            Curve::Exponential => "exp",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcoder(ffmpeg: PathBuf, format: Format) -> Transcoder {
+        Transcoder {
+            config: config::Transcode {
+                ffmpeg,
+                bitrate: 96,
+                sample_rate: 48000,
+                channels: 1,
+            },
+            format,
+        }
+    }
+
+    #[test]
+    fn args() {
+        let args = transcoder("ffmpeg".into(), Format::Mp3).args(None);
+        let args = args.join(" ");
+        assert!(args.contains("-i pipe:0"), "{args}");
+        assert!(args.contains("-c:a libmp3lame"), "{args}");
+        assert!(args.contains("-b:a 96k -ar 48000 -ac 1 -f mp3"), "{args}");
+
+        assert!(!args.contains("volume"), "{args}");
+
+        let args = transcoder("ffmpeg".into(), Format::Flac)
+            .args(Some(-3.256))
+            .join(" ");
+        assert!(!args.contains("-b:a"), "{args}");
+        assert!(args.contains("-af volume=-3.26dB"), "{args}");
+        assert!(args.contains("-f flac"), "{args}");
+
+        let args = transcoder("ffmpeg".into(), Format::Aac)
+            .args(Some(1.))
+            .join(" ");
+        assert!(
+            args.ends_with(
+                "-af volume=1.00dB,alimiter=limit=1:level=0 -c:a aac -b:a 96k -ar 48000 -ac 1 -f adts -y"
+            ),
+            "{args}"
+        );
+    }
+
+    #[test]
+    fn priming() {
+        let mp3 = transcoder("ffmpeg".into(), Format::Mp3);
+        assert_eq!(mp3.primed(), 1);
+        assert_eq!(mp3.primed() as u32 * mp3.frame_samples() - mp3.delay(), 47);
+
+        // half as many samples per frame
+        let mut lsf = mp3.clone();
+        lsf.config.sample_rate = 22050;
+        assert_eq!(lsf.primed(), 2);
+        assert_eq!(lsf.primed() as u32 * lsf.frame_samples() - lsf.delay(), 47);
+
+        let aac = transcoder("ffmpeg".into(), Format::Aac);
+        assert_eq!(aac.primed(), 1);
+        assert_eq!(aac.primed() as u32 * aac.frame_samples() - aac.delay(), 0);
+    }
+
+    /// Runs a stand-in for ffmpeg that copies its input to the output path unchanged.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn subprocess() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = temp_path("sh");
+        std::fs::write(&script, "#!/bin/sh\nfor out; do :; done\ncat > \"$out\"\n")?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let mut out = transcoder(script.clone(), Format::Mp3)
+            .transcode(Source::Buffer(io::Cursor::new(data.clone())), None)
+            .await?;
+
+        let mut copied = Vec::new();
+        out.read_to_end(&mut copied)?;
+        assert_eq!(copied, data);
+
+        let failed = transcoder("/bin/false".into(), Format::Mp3)
+            .transcode(Source::Buffer(io::Cursor::new(data)), None)
+            .await;
+        assert!(matches!(failed, Err(Error::Ffmpeg(_))), "{failed:?}");
+
+        std::fs::remove_file(script)?;
+        Ok(())
+    }
+}